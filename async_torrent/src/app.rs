@@ -2,26 +2,68 @@ use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc;
 
 use crate::bencode::MetaInfo;
-use crate::engine::{events::UiEvent, spawn_engine};
+use crate::engine::{
+    EngineHandles,
+    config::EngineConfig,
+    connection_manager::GlobalConnectionLimits,
+    disk_io::PauseControl,
+    events::UiEvent,
+    files::{FilePriorities, FilePriority, file_count},
+    ip_filter::IpFilterControl,
+    piece_picker::StreamControl,
+    rate_limiter::{Bandwidth, Throttle},
+    spawn_engine,
+};
 use crate::tui::app_state::process_event;
 use crate::tui::{app_state::AppState, ui::run as tui_engine};
 
-pub async fn run_tui(info: MetaInfo) -> anyhow::Result<()> {
+pub async fn run_tui(
+    info: MetaInfo,
+    config: EngineConfig,
+    ip_filter: IpFilterControl,
+) -> anyhow::Result<()> {
     // 1. Create shared AppState
     let piece_count = info.info.pieces.len().div_ceil(20); // same as engine
-    let app_state = Arc::new(RwLock::new(AppState::new(piece_count)));
+    let app_state = Arc::new(RwLock::new(AppState::new(
+        piece_count,
+        info.info.total_length(),
+        info.info.piece_length,
+    )));
+
+    // Limiters and priorities are shared with the TUI so they can be changed at runtime
+    let bandwidth = Bandwidth {
+        global: Throttle::new(config.download_limit, config.upload_limit),
+        torrent: Throttle::new(config.torrent_download_limit, config.torrent_upload_limit),
+        counters: Default::default(),
+    };
+    let mut priorities = vec![FilePriority::Normal; file_count(&info.info)];
+    for (index, priority) in &config.file_priorities {
+        if let Some(p) = priorities.get_mut(*index) {
+            *p = *priority;
+        }
+    }
+    let handles = EngineHandles {
+        bandwidth,
+        file_priorities: FilePriorities::new(priorities),
+        stream: StreamControl::new(config.sequential, config.deadline),
+        pause: PauseControl::default(),
+        ip_filter,
+    };
+    let limits = GlobalConnectionLimits::new(config.max_peers, config.max_half_open);
 
     // 2. Create channel for UI events
     let (ui_tx, mut ui_rx) = mpsc::channel::<UiEvent>(256);
 
     let info_arc = Arc::new(info.clone());
-    tokio::spawn({
+    let engine = tokio::spawn({
         // let app_state = app_state.clone();
         let ui_sender = ui_tx.clone();
+        let handles = handles.clone();
         async move {
-            if let Err(e) = spawn_engine(info_arc, ui_sender).await {
-                eprintln!("Engine error: {e}");
-            }
+            spawn_engine(info_arc, config, handles, limits, ui_sender)
+                .await
+                .inspect_err(|e| eprintln!("Engine error: {e}"))
+                .ok()
         }
     });
 
@@ -31,7 +73,14 @@ pub async fn run_tui(info: MetaInfo) -> anyhow::Result<()> {
     });
 
     // 4. Run TUI (blocking in main task)
-    tui_engine(app_state.clone(), info)?;
+    tui_engine(app_state.clone(), info, handles)?;
+
+    // Don't wait on trackers if the engine is still starting up
+    if !engine.is_finished() {
+        engine.abort();
+    } else if let Ok(Some(engine)) = engine.await {
+        engine.shutdown().await;
+    }
 
     Ok(())
 }
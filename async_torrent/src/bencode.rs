@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 use serde_bencoded::from_bytes;
@@ -49,11 +49,29 @@ pub struct Info {
     #[serde(rename = "piece length")]
     pub piece_length: u64,
     pub pieces: ByteBuf,
-    pub name: String,
+    pub name: RawString,
+    /// 1 when peers may only come from the tracker (BEP 27). It's part of
+    /// the info hash, so it has to survive a round trip.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
     #[serde(flatten)]
     pub mode: FileMode,
 }
 
+impl Info {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Total number of bytes across all files in the torrent
+    pub fn total_length(&self) -> u64 {
+        match &self.mode {
+            FileMode::SingleFile { length } => *length,
+            FileMode::MultipleFiles { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum FileMode {
@@ -70,7 +88,40 @@ pub enum FileMode {
 pub struct File {
     pub length: u64,
     // pub md5sum: Option<ByteBuf>,
-    pub path: Vec<String>,
+    pub path: Vec<RawString>,
+}
+
+impl File {
+    /// Path inside the torrent joined with `/`, for display
+    pub fn display_path(&self) -> String {
+        let parts: Vec<String> = self.path.iter().map(|p| p.to_string()).collect();
+        parts.join("/")
+    }
+}
+
+/// Name or path element from the torrent. Kept as the raw bytes, since
+/// nothing makes torrents use valid UTF-8 and changing the bytes would
+/// change the info hash.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct RawString(ByteBuf);
+
+impl RawString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&str> for RawString {
+    fn from(s: &str) -> RawString {
+        RawString(ByteBuf::from(s.as_bytes()))
+    }
+}
+
+impl fmt::Display for RawString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.0))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
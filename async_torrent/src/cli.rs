@@ -0,0 +1,239 @@
+use std::path::PathBuf;
+
+use crate::engine::{config::EngineConfig, piece_picker::Deadline};
+
+pub struct Args {
+    pub torrent_path: PathBuf,
+    pub engine: EngineConfig,
+}
+
+const OPTIONS: &[(&str, &str)] = &[
+    (
+        "--unchoke-slots <n>",
+        "peers unchoked by download/upload rate (default 4)",
+    ),
+    (
+        "--optimistic-slots <n>",
+        "peers unchoked optimistically (default 1)",
+    ),
+    (
+        "--download-limit <KiB/s>",
+        "global download limit (0 = unlimited)",
+    ),
+    (
+        "--upload-limit <KiB/s>",
+        "global upload limit (0 = unlimited)",
+    ),
+    (
+        "--torrent-download-limit <KiB/s>",
+        "download limit for this torrent",
+    ),
+    (
+        "--torrent-upload-limit <KiB/s>",
+        "upload limit for this torrent",
+    ),
+    ("--max-peers <n>", "connected peers overall (default 200)"),
+    (
+        "--max-peers-per-torrent <n>",
+        "connected peers for this torrent (default 50)",
+    ),
+    (
+        "--max-half-open <n>",
+        "connection attempts in flight (default 8)",
+    ),
+    (
+        "--connect-timeout <secs>",
+        "connect and handshake timeout (default 5)",
+    ),
+    (
+        "--idle-timeout <secs>",
+        "drop silent or uninterested peers after this long (default 300)",
+    ),
+    ("--port <port>", "port to accept peers on (default 6881)"),
+    (
+        "--encryption <policy>",
+        "peer encryption: prefer, require or disable (default prefer)",
+    ),
+    (
+        "--transport <pref>",
+        "peer transport: tcp, utp, prefer-tcp or prefer-utp (default prefer-tcp)",
+    ),
+    (
+        "--proxy <url>",
+        "send tracker and peer traffic through socks5://[user:pass@]host:port or http://…",
+    ),
+    (
+        "--interface <name|ip>",
+        "bind tracker and peer connections to this interface or local address",
+    ),
+    (
+        "--ip-filter <file>",
+        "refuse peers in this eMule .dat, PeerGuardian .p2p or CIDR blocklist",
+    ),
+    (
+        "--file-priority <i>=<p>",
+        "priority of file i: skip, low, normal or high (repeatable)",
+    ),
+    (
+        "--sequential",
+        "download pieces in order instead of rarest first",
+    ),
+    (
+        "--stream <i>[:<offset>]",
+        "prioritize file i ahead of a byte offset for playback",
+    ),
+    (
+        "--stream-window <pieces>",
+        "pieces ahead of the stream position to rush (default 16)",
+    ),
+    (
+        "--http-port <port>",
+        "localhost port files are streamed on (default 7881, 0 = off)",
+    ),
+    (
+        "--storage <backend>",
+        "where data is kept: fs, mmap or memory (default fs)",
+    ),
+    (
+        "--allocation <mode>",
+        "disk allocation: sparse, full or on-write (default sparse, mmap is always full)",
+    ),
+    (
+        "--output-dir <dir>",
+        "directory to download into (default .)",
+    ),
+    (
+        "--move-completed <dir>",
+        "move the download here once every wanted file is done",
+    ),
+    (
+        "--disk-threads <n>",
+        "threads doing disk I/O and hashing (default 4)",
+    ),
+    (
+        "--disk-write-queue <n>",
+        "pieces queued for writing before peers wait (default 32)",
+    ),
+    (
+        "--cache-size <MiB>",
+        "memory for cached pieces, 0 to disable (default 64)",
+    ),
+];
+
+pub fn usage(program: &str) -> String {
+    let mut usage = format!("Usage: {program} <path_to_torrent> [options]\n\nOptions:\n");
+    for (flag, help) in OPTIONS {
+        usage += &format!("  {flag:<34}{help}\n");
+    }
+    usage
+}
+
+pub fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut torrent_path = None;
+    let mut engine = EngineConfig::default();
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--unchoke-slots" => engine.unchoke_slots = parse_value(arg, iter.next())?,
+            "--optimistic-slots" => {
+                engine.optimistic_unchoke_slots = parse_value(arg, iter.next())?
+            }
+            "--download-limit" => engine.download_limit = parse_kib(arg, iter.next())?,
+            "--upload-limit" => engine.upload_limit = parse_kib(arg, iter.next())?,
+            "--torrent-download-limit" => {
+                engine.torrent_download_limit = parse_kib(arg, iter.next())?
+            }
+            "--torrent-upload-limit" => engine.torrent_upload_limit = parse_kib(arg, iter.next())?,
+            "--max-peers" => engine.max_peers = parse_value(arg, iter.next())?,
+            "--max-peers-per-torrent" => {
+                engine.max_peers_per_torrent = parse_value(arg, iter.next())?
+            }
+            "--max-half-open" => engine.max_half_open = parse_value(arg, iter.next())?,
+            "--connect-timeout" => engine.connect_timeout = parse_value(arg, iter.next())?,
+            "--idle-timeout" => engine.idle_timeout = parse_value(arg, iter.next())?,
+            "--port" => engine.listen_port = parse_value(arg, iter.next())?,
+            "--encryption" => {
+                let value: String = parse_value(arg, iter.next())?;
+                engine.encryption = value.parse()?;
+            }
+            "--transport" => {
+                let value: String = parse_value(arg, iter.next())?;
+                engine.transport = value.parse()?;
+            }
+            "--proxy" => {
+                let value: String = parse_value(arg, iter.next())?;
+                engine.proxy = Some(value.parse()?);
+            }
+            "--interface" => {
+                let value: String = parse_value(arg, iter.next())?;
+                engine.outgoing = Some(value.parse()?);
+            }
+            "--ip-filter" => engine.ip_filter = Some(parse_value(arg, iter.next())?),
+            "--file-priority" => {
+                let value: String = parse_value(arg, iter.next())?;
+                let (index, priority) = value
+                    .split_once('=')
+                    .ok_or(format!("Expected <index>=<priority> for {arg}"))?;
+                let index = index
+                    .parse()
+                    .map_err(|_| format!("Invalid file index {index}"))?;
+                engine.file_priorities.push((index, priority.parse()?));
+            }
+            "--sequential" => engine.sequential = true,
+            "--stream" => {
+                let value: String = parse_value(arg, iter.next())?;
+                let (index, offset) = value.split_once(':').unwrap_or((&value, "0"));
+                engine.deadline = Some(Deadline {
+                    file_index: index
+                        .parse()
+                        .map_err(|_| format!("Invalid file index {index}"))?,
+                    offset: offset
+                        .parse()
+                        .map_err(|_| format!("Invalid offset {offset}"))?,
+                });
+            }
+            "--stream-window" => engine.stream_window = parse_value(arg, iter.next())?,
+            "--http-port" => engine.http_port = parse_value(arg, iter.next())?,
+            "--storage" => {
+                let value: String = parse_value(arg, iter.next())?;
+                engine.storage = value.parse()?;
+            }
+            "--allocation" => {
+                let value: String = parse_value(arg, iter.next())?;
+                engine.allocation = value.parse()?;
+            }
+            "--disk-threads" => engine.disk_threads = parse_value(arg, iter.next())?,
+            "--disk-write-queue" => engine.disk_write_queue = parse_value(arg, iter.next())?,
+            "--cache-size" => {
+                engine.cache_size = parse_value::<usize>(arg, iter.next())? * 1024 * 1024
+            }
+            "--output-dir" => engine.output_dir = parse_value(arg, iter.next())?,
+            "--move-completed" => engine.move_completed = Some(parse_value(arg, iter.next())?),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {flag}")),
+            path => {
+                if torrent_path.is_some() {
+                    return Err(format!("Unexpected argument {path}"));
+                }
+                torrent_path = Some(PathBuf::from(path));
+            }
+        }
+    }
+
+    Ok(Args {
+        torrent_path: torrent_path.ok_or("Missing torrent path")?,
+        engine,
+    })
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{flag} needs a value"))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value {value} for {flag}"))
+}
+
+/// Rates are given in KiB/s on the command line and kept in bytes/s
+fn parse_kib(flag: &str, value: Option<&String>) -> Result<u64, String> {
+    parse_value::<u64>(flag, value).map(|kib| kib * 1024)
+}
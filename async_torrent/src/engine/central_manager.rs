@@ -1,9 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::interval;
 
+use crate::bencode::MetaInfo;
+use crate::engine::choker::{ChokeCandidate, Choker, RECHOKE_INTERVAL};
+use crate::engine::config::EngineConfig;
+use crate::engine::disk_io::{DiskIo, PauseControl};
 use crate::engine::events::UiEvent;
+use crate::engine::files::{self, TorrentFiles};
+use crate::engine::peers_task::PeerCommand;
+use crate::engine::piece_picker::{PiecePicker, StreamControl};
+use crate::engine::piece_store::{BlockOutcome, BlockRequest, PieceStore};
+
+/// Peers allowed to download pieces in the streaming deadline window
+const STREAM_PEERS: usize = 4;
 
 #[allow(unused)]
 #[derive(Debug)]
@@ -12,159 +30,471 @@ struct PeerState {
     interested: bool,
     bitfield: Vec<bool>,
     outstanding: usize,
+    /// We are choking the peer
+    am_choking: bool,
+    /// Bytes received from and sent to the peer since the last choking round
+    downloaded: u64,
+    uploaded: u64,
+    /// Bytes received from the peer during the last full choking round
+    last_downloaded: u64,
+    control: mpsc::Sender<PeerCommand>,
+    haves: mpsc::UnboundedSender<usize>,
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone)]
 pub enum PieceState {
     Free,
     Done,
-    Reserved(PeerId),
+    Reserved(PeerKey),
 }
 
 #[derive(Debug)]
 pub struct CentralManager {
-    peers: HashMap<PeerId, PeerState>,
+    info: Arc<MetaInfo>,
+    peers: HashMap<PeerKey, PeerState>,
     pieces_status: Vec<PieceState>,
     done_pieces: usize,
+    store: PieceStore,
+    choker: Choker,
+    picker: PiecePicker,
+    /// File changes go through the disk threads, never blocking the manager
+    disk: DiskIo,
+    /// Enabling or removing files, each reporting the pieces to download again
+    file_jobs: JoinSet<Vec<usize>>,
+    /// Where to move the data once every wanted piece is done
+    move_completed: Option<PathBuf>,
+    /// Streaming readers waiting for a piece to be verified
+    waiters: HashMap<usize, Vec<oneshot::Sender<()>>>,
+    /// No new pieces are handed out while paused
+    pause: PauseControl,
+    /// Bytes of wanted pieces still missing, reported to trackers
+    left: watch::Sender<u64>,
     ui_tx: mpsc::Sender<UiEvent>,
 }
 
-pub type PeerId = [u8; 20];
+/// Peers are told apart by address, a peer id isn't unique to a connection
+pub type PeerKey = SocketAddr;
 
 pub enum PieceCommands {
-    RequestPieceIndex(PeerId, oneshot::Sender<Option<usize>>),
-    RequestPieceStatus(usize, oneshot::Sender<Option<PieceState>>),
-    PieceDone(PeerId, usize),
-    PieceFailed(PeerId, usize),
-    UpdateBitfield(PeerId, u32),
-    PeerChoked(PeerId),
-    SetBitfield(PeerId, Vec<bool>),
-    PeerUnchoke(PeerId),
-    PeerDead(PeerId),
-    PeerRegister(PeerId, usize),
+    RequestPieceIndex(PeerKey, oneshot::Sender<Option<usize>>),
+    NextBlock(usize, oneshot::Sender<Option<BlockRequest>>),
+    BlockReceived(PeerKey, usize, u32, Vec<u8>, oneshot::Sender<BlockOutcome>),
+    HasPiece(usize, oneshot::Sender<bool>),
+    /// Which pieces we have, for the bitfield sent after the handshake
+    DonePieces(oneshot::Sender<Vec<bool>>),
+    /// Whether the peer has a wanted piece we don't
+    WantsFrom(PeerKey, oneshot::Sender<bool>),
+    /// Reply once the piece is downloaded and verified
+    WaitPiece(usize, oneshot::Sender<()>),
+    BlockUploaded(PeerKey, usize),
+    PeerInterested(PeerKey, bool),
+    PieceDone(PeerKey, usize),
+    PieceFailed(PeerKey, usize),
+    UpdateBitfield(PeerKey, u32),
+    PeerChoked(PeerKey),
+    SetBitfield(PeerKey, Vec<bool>),
+    PeerUnchoke(PeerKey),
+    PeerDead(PeerKey),
+    PeerRegister(
+        PeerKey,
+        usize,
+        mpsc::Sender<PeerCommand>,
+        mpsc::UnboundedSender<usize>,
+    ),
 }
 
 impl CentralManager {
-    pub fn new(num_piece: usize, ui_tx: mpsc::Sender<UiEvent>) -> CentralManager {
-        CentralManager {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        info: Arc<MetaInfo>,
+        config: &EngineConfig,
+        files: TorrentFiles,
+        disk: DiskIo,
+        stream: StreamControl,
+        pause: PauseControl,
+        ui_tx: mpsc::Sender<UiEvent>,
+    ) -> CentralManager {
+        let num_piece = info.info.pieces.len().div_ceil(20);
+        let manager = CentralManager {
             peers: HashMap::new(),
             pieces_status: vec![PieceState::Free; num_piece],
             done_pieces: 0,
+            store: PieceStore::new(info.info.piece_length, info.info.total_length(), num_piece),
+            choker: Choker::new(config.unchoke_slots, config.optimistic_unchoke_slots),
+            picker: PiecePicker::new(
+                info.clone(),
+                files.priorities.clone(),
+                stream,
+                config.stream_window,
+            ),
+            disk,
+            file_jobs: JoinSet::new(),
+            move_completed: config.move_completed.clone(),
+            waiters: HashMap::new(),
+            pause,
+            left: watch::channel(0).0,
+            info,
             ui_tx,
-        }
+        };
+        manager.update_left();
+        manager
+    }
+
+    /// Follow the bytes still to download
+    pub fn left(&self) -> watch::Receiver<u64> {
+        self.left.subscribe()
     }
     pub async fn run(mut self, mut rx: mpsc::Receiver<PieceCommands>) {
-        while let Some(cmd) = rx.recv().await {
-            match cmd {
-                PieceCommands::RequestPieceIndex(peer_id, sender) => {
-                    // Get a piece index for the peer
-                    if let Some(peer_info) = self.peers.get(&peer_id) {
-                        if self.done_pieces * 100 >= self.pieces_status.len() * 98 {
-                            let index = self
-                                .pieces_status
-                                .iter()
-                                .enumerate()
-                                .find(|(i, state)| {
-                                    **state != PieceState::Done && peer_info.bitfield[*i]
-                                })
-                                .map(|(i, _)| i);
-                            if let Some(i) = index {
-                                let _ = self.ui_tx.send(UiEvent::PieceRequested(i)).await.ok();
-                            }
-                            let _ = sender.send(index);
-                        } else {
-                            let index = self
-                                .pieces_status
-                                .iter()
-                                .enumerate()
-                                .find(|(i, state)| {
-                                    **state == PieceState::Free && peer_info.bitfield[*i]
-                                })
-                                .map(|(i, _)| i);
-                            if let Some(i) = index {
-                                self.pieces_status[i] = PieceState::Reserved(peer_id);
-                                let _ = self.ui_tx.send(UiEvent::PieceRequested(i)).await.ok();
-                            }
-                            let _ = sender.send(index);
+        let mut rechoke = interval(RECHOKE_INTERVAL);
+        loop {
+            tokio::select! {
+                cmd = rx.recv() => match cmd {
+                    Some(cmd) => self.handle_command(cmd).await,
+                    None => break,
+                },
+                _ = rechoke.tick() => self.rechoke(),
+                Some(lost) = self.file_jobs.join_next(), if !self.file_jobs.is_empty() => {
+                    self.pieces_lost(lost.unwrap_or_default());
+                }
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, cmd: PieceCommands) {
+        match cmd {
+            PieceCommands::RequestPieceIndex(peer_id, sender) => {
+                self.refresh_priorities();
+                // Get a piece index for the peer
+                if let Some(peer_info) = self.peers.get(&peer_id)
+                    && !self.pause.is_paused()
+                {
+                    // Deadline pieces go to the fastest peers, unless none of them has it
+                    let window = self.picker.deadline_window();
+                    let fast = self.fast_peers();
+                    let window_ok = |i: usize| {
+                        !window.contains(&i)
+                            || fast.contains(&peer_id)
+                            || !fast.iter().any(|id| self.peers[id].bitfield[i])
+                    };
+                    let wanted_left = self.wanted_left();
+                    let wanted_total = wanted_left + self.done_pieces;
+                    if wanted_left * 100 <= wanted_total * 2 {
+                        // Endgame: hand out pieces even if another peer has them reserved
+                        let index = self.picker.pick(
+                            |i| {
+                                self.pieces_status[i] != PieceState::Done
+                                    && peer_info.bitfield[i]
+                                    && window_ok(i)
+                            },
+                            |_| false,
+                        );
+                        if let Some(i) = index {
+                            let _ = self.ui_tx.send(UiEvent::PieceRequested(i)).await.ok();
                         }
+                        let _ = sender.send(index);
                     } else {
-                        let _ = sender.send(None);
+                        // Finish pieces other peers left half done before starting new ones
+                        let index = self.picker.pick(
+                            |i| {
+                                self.pieces_status[i] == PieceState::Free
+                                    && peer_info.bitfield[i]
+                                    && window_ok(i)
+                            },
+                            |i| self.store.is_partial(i),
+                        );
+                        if let Some(i) = index {
+                            self.pieces_status[i] = PieceState::Reserved(peer_id);
+                            let _ = self.ui_tx.send(UiEvent::PieceRequested(i)).await.ok();
+                        }
+                        let _ = sender.send(index);
                     }
+                } else {
+                    let _ = sender.send(None);
                 }
-                PieceCommands::RequestPieceStatus(piece_index, sender) => {
-                    let _ = sender.send(self.pieces_status.get(piece_index).cloned());
+            }
+            PieceCommands::NextBlock(piece_index, sender) => {
+                let next = match self.pieces_status.get(piece_index) {
+                    Some(PieceState::Done) | None => None,
+                    Some(_) => self.store.next_block(piece_index),
+                };
+                let _ = sender.send(next);
+            }
+            PieceCommands::BlockReceived(peer_id, piece_index, begin, block, sender) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.downloaded += block.len() as u64;
                 }
-                PieceCommands::PieceDone(_peer_id, piece_index) => {
-                    // println!(
-                    //     "\t\t\t\tPiece {}/{} done",
-                    //     piece_index + 1,
-                    //     self.pieces_status.len()
-                    // );
+                let outcome = match self.pieces_status.get(piece_index) {
+                    Some(PieceState::Done) | None => BlockOutcome::Stale,
+                    Some(_) => self.store.add_block(piece_index, begin, &block),
+                };
+                if let BlockOutcome::Next(_) = outcome {
                     let _ = self
                         .ui_tx
-                        .send(UiEvent::PieceCompleted(piece_index))
+                        .send(UiEvent::PieceDownloading(piece_index))
                         .await
                         .ok();
-                    if self.pieces_status[piece_index] != PieceState::Done {
-                        self.pieces_status[piece_index] = PieceState::Done;
-                        self.done_pieces += 1;
-                    }
-                    // println!("{}", self.done_pieces);
-                    if self.done_pieces == self.pieces_status.len() {
-                        return;
-                    }
                 }
-                PieceCommands::PieceFailed(_peer_id, piece_index) => {
-                    self.pieces_status[piece_index] = PieceState::Free;
+                let _ = sender.send(outcome);
+            }
+            PieceCommands::PieceDone(_peer_id, piece_index) => {
+                // println!(
+                //     "\t\t\t\tPiece {}/{} done",
+                //     piece_index + 1,
+                //     self.pieces_status.len()
+                // );
+                let _ = self
+                    .ui_tx
+                    .send(UiEvent::PieceCompleted(piece_index))
+                    .await
+                    .ok();
+                self.store.discard(piece_index);
+                if self.pieces_status[piece_index] != PieceState::Done {
+                    self.pieces_status[piece_index] = PieceState::Done;
+                    self.done_pieces += 1;
+                }
+                for waiter in self.waiters.remove(&piece_index).unwrap_or_default() {
+                    let _ = waiter.send(());
                 }
-                PieceCommands::UpdateBitfield(peer_id, index) => {
-                    if let Some(peer_info) = self.peers.get_mut(&peer_id) {
-                        let index = index as usize;
-                        if index >= peer_info.bitfield.len() {
-                            continue;
-                        }
-                        peer_info.bitfield[index] = true;
-                    }
+                self.update_left();
+                // Queued without bound, a busy peer must never miss a Have
+                for peer in self.peers.values() {
+                    let _ = peer.haves.send(piece_index);
                 }
-                PieceCommands::PeerChoked(peer_id) => {
-                    if let Some(peer) = self.peers.get_mut(&peer_id) {
-                        peer.choked = true;
-                        for piece_stat in self.pieces_status.iter_mut() {
-                            if *piece_stat == PieceState::Reserved(peer_id) {
-                                *piece_stat = PieceState::Free;
+                if self.wanted_left() == 0 {
+                    // Moving the files flushes them first
+                    let disk = self.disk.clone();
+                    match self.move_completed.take() {
+                        Some(dir) => tokio::spawn(async move {
+                            if let Err(e) = disk.move_to(dir.clone()).await {
+                                eprintln!("Failed to move download to {}: {e}", dir.display());
                             }
-                        }
+                        }),
+                        None => tokio::spawn(async move {
+                            if let Err(e) = disk.flush().await {
+                                eprintln!("Failed to flush cached pieces: {e}");
+                            }
+                        }),
+                    };
+                }
+                // println!("{}", self.done_pieces);
+            }
+            PieceCommands::PieceFailed(_peer_id, piece_index) => {
+                // Hash check or write failed, so the buffered blocks can't be used either
+                self.store.discard(piece_index);
+                self.pieces_status[piece_index] = PieceState::Free;
+            }
+            PieceCommands::UpdateBitfield(peer_id, index) => {
+                if let Some(peer_info) = self.peers.get_mut(&peer_id) {
+                    let index = index as usize;
+                    if index < peer_info.bitfield.len() && !peer_info.bitfield[index] {
+                        peer_info.bitfield[index] = true;
+                        self.picker.add_piece(index);
                     }
                 }
-                PieceCommands::PeerDead(peer_id) => {
-                    for index in 0..self.pieces_status.len() {
-                        if self.pieces_status[index] == PieceState::Reserved(peer_id) {
-                            self.pieces_status[index] = PieceState::Free;
+            }
+            PieceCommands::PeerChoked(peer_id) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.choked = true;
+                    for piece_stat in self.pieces_status.iter_mut() {
+                        if *piece_stat == PieceState::Reserved(peer_id) {
+                            *piece_stat = PieceState::Free;
                         }
                     }
                 }
-                PieceCommands::PeerUnchoke(peer_id) => {
-                    if let Some(peer) = self.peers.get_mut(&peer_id) {
-                        peer.choked = false;
+            }
+            PieceCommands::PeerDead(peer_id) => {
+                if let Some(peer) = self.peers.remove(&peer_id) {
+                    self.picker.update_bitfield(&peer.bitfield, false);
+                }
+                for index in 0..self.pieces_status.len() {
+                    if self.pieces_status[index] == PieceState::Reserved(peer_id) {
+                        self.pieces_status[index] = PieceState::Free;
                     }
                 }
-                PieceCommands::PeerRegister(peer_id, num_pieces) => {
-                    self.peers.insert(
-                        peer_id,
-                        PeerState {
-                            choked: true,
-                            interested: false,
-                            bitfield: vec![false; num_pieces],
-                            outstanding: 0,
-                        },
-                    );
-                }
-                PieceCommands::SetBitfield(peer_id, bitfield) => {
-                    if let Some(peer_info) = self.peers.get_mut(&peer_id) {
-                        peer_info.bitfield = bitfield;
+            }
+            PieceCommands::PeerUnchoke(peer_id) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.choked = false;
+                }
+            }
+            PieceCommands::PeerRegister(peer_id, num_pieces, control, haves) => {
+                self.peers.insert(
+                    peer_id,
+                    PeerState {
+                        choked: true,
+                        interested: false,
+                        bitfield: vec![false; num_pieces],
+                        outstanding: 0,
+                        am_choking: true,
+                        downloaded: 0,
+                        uploaded: 0,
+                        last_downloaded: 0,
+                        control,
+                        haves,
+                    },
+                );
+            }
+            PieceCommands::HasPiece(piece_index, sender) => {
+                let _ = sender.send(self.pieces_status.get(piece_index) == Some(&PieceState::Done));
+            }
+            PieceCommands::DonePieces(sender) => {
+                let _ = sender.send(
+                    self.pieces_status
+                        .iter()
+                        .map(|state| *state == PieceState::Done)
+                        .collect(),
+                );
+            }
+            PieceCommands::WantsFrom(peer_id, sender) => {
+                let wants = self.peers.get(&peer_id).is_some_and(|peer| {
+                    peer.bitfield.iter().enumerate().any(|(i, &has)| {
+                        has && self.pieces_status[i] != PieceState::Done && self.picker.is_wanted(i)
+                    })
+                });
+                let _ = sender.send(wants);
+            }
+            PieceCommands::WaitPiece(piece_index, sender) => {
+                match self.pieces_status.get(piece_index) {
+                    Some(PieceState::Done) => {
+                        let _ = sender.send(());
                     }
+                    Some(_) => self.waiters.entry(piece_index).or_default().push(sender),
+                    // Dropping the sender tells the reader the piece doesn't exist
+                    None => {}
+                }
+            }
+            PieceCommands::BlockUploaded(peer_id, len) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.uploaded += len as u64;
+                }
+            }
+            PieceCommands::PeerInterested(peer_id, interested) => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.interested = interested;
+                }
+            }
+            PieceCommands::SetBitfield(peer_id, bitfield) => {
+                if let Some(peer_info) = self.peers.get_mut(&peer_id) {
+                    self.picker.update_bitfield(&peer_info.bitfield, false);
+                    self.picker.update_bitfield(&bitfield, true);
+                    peer_info.bitfield = bitfield;
                 }
             }
         }
     }
+
+    /// Wanted pieces that are not downloaded yet
+    fn wanted_left(&self) -> usize {
+        self.pieces_status
+            .iter()
+            .enumerate()
+            .filter(|(i, state)| **state != PieceState::Done && self.picker.is_wanted(*i))
+            .count()
+    }
+
+    /// Publish the size of the wanted pieces that aren't done yet
+    fn update_left(&self) {
+        let info = &self.info.info;
+        let total = info.total_length();
+        let left = (0..self.pieces_status.len())
+            .filter(|&i| self.pieces_status[i] != PieceState::Done && self.picker.is_wanted(i))
+            .map(|i| {
+                let start = i as u64 * info.piece_length;
+                (total - start).min(info.piece_length)
+            })
+            .sum();
+        self.left
+            .send_if_modified(|l| std::mem::replace(l, left) != left);
+    }
+
+    /// Unchoked peers that sent us the most during the last choking round
+    fn fast_peers(&self) -> HashSet<PeerKey> {
+        let mut peers: Vec<(&PeerKey, &PeerState)> =
+            self.peers.iter().filter(|(_, p)| !p.choked).collect();
+        peers.sort_by_key(|(_, p)| std::cmp::Reverse(p.last_downloaded));
+        peers
+            .into_iter()
+            .take(STREAM_PEERS)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Apply file priority changes made from the TUI. The files are
+    /// created or deleted on the disk threads, pieces that didn't make it
+    /// into place come back through `pieces_lost`.
+    fn refresh_priorities(&mut self) {
+        let info = &self.info.info;
+        let (enabled, skipped) = self.picker.refresh();
+        if !enabled.is_empty() || !skipped.is_empty() {
+            self.update_left();
+        }
+        for file_index in enabled {
+            let done: Vec<usize> = files::file_pieces(info, file_index)
+                .filter(|i| self.pieces_status[*i] == PieceState::Done)
+                .collect();
+            let disk = self.disk.clone();
+            self.file_jobs
+                .spawn(async move { disk.enable_file(file_index, done).await });
+        }
+        for file_index in skipped {
+            // Files we have nothing of yet don't need to stay around
+            let mut pieces = files::file_pieces(info, file_index);
+            if pieces.all(|i| self.pieces_status[i] != PieceState::Done) {
+                let disk = self.disk.clone();
+                self.file_jobs.spawn(async move {
+                    if let Err(e) = disk.remove_file(file_index).await {
+                        eprintln!("Failed to delete file {file_index}: {e}");
+                    }
+                    Vec::new()
+                });
+            }
+        }
+    }
+
+    /// Download again finished pieces that turned out missing or corrupt
+    fn pieces_lost(&mut self, pieces: Vec<usize>) {
+        for piece_index in pieces {
+            if self.pieces_status[piece_index] == PieceState::Done {
+                self.pieces_status[piece_index] = PieceState::Free;
+                self.done_pieces -= 1;
+            }
+        }
+        self.update_left();
+    }
+
+    /// Run a choking round and tell peers whose state changed
+    fn rechoke(&mut self) {
+        let candidates: Vec<ChokeCandidate> = self
+            .peers
+            .iter()
+            .map(|(key, peer)| ChokeCandidate {
+                key: *key,
+                interested: peer.interested,
+                downloaded: peer.downloaded,
+                uploaded: peer.uploaded,
+            })
+            .collect();
+        let seeding = self.wanted_left() == 0;
+        let unchoked = self.choker.rechoke(Instant::now(), &candidates, seeding);
+
+        for (peer_id, peer) in self.peers.iter_mut() {
+            peer.last_downloaded = peer.downloaded;
+            peer.downloaded = 0;
+            peer.uploaded = 0;
+
+            let choke = !unchoked.contains(peer_id);
+            if choke == peer.am_choking {
+                continue;
+            }
+            let cmd = if choke {
+                PeerCommand::Choke
+            } else {
+                PeerCommand::Unchoke
+            };
+            // Never block the manager on a busy peer, it gets another chance next round
+            if peer.control.try_send(cmd).is_ok() {
+                peer.am_choking = choke;
+            }
+        }
+    }
 }
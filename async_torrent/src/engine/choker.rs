@@ -0,0 +1,228 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use crate::engine::central_manager::PeerKey;
+
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What the choker needs to know about a peer for one round
+#[derive(Debug, Clone)]
+pub struct ChokeCandidate {
+    pub key: PeerKey,
+    /// Peer is interested in pieces we have
+    pub interested: bool,
+    /// Bytes the peer sent us since the last round
+    pub downloaded: u64,
+    /// Bytes we sent the peer since the last round
+    pub uploaded: u64,
+}
+
+/// Tit-for-tat choker.
+///
+/// Every round the interested peers with the best rate (download rate while
+/// leeching, upload rate while seeding) get the regular unchoke slots, and one
+/// more interested peer is unchoked optimistically. The optimistic pick only
+/// rotates every `OPTIMISTIC_INTERVAL`, so new peers get a chance to prove
+/// themselves. Time is passed in by the caller, which keeps the choker
+/// independent of the real clock.
+#[derive(Debug)]
+pub struct Choker {
+    unchoke_slots: usize,
+    optimistic_slots: usize,
+    optimistic: Vec<PeerKey>,
+    last_optimistic: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(unchoke_slots: usize, optimistic_slots: usize) -> Choker {
+        Choker {
+            unchoke_slots,
+            optimistic_slots,
+            optimistic: Vec::new(),
+            last_optimistic: None,
+        }
+    }
+
+    /// Run one choking round and return the peers that should be unchoked.
+    /// Everyone else should be choked.
+    pub fn rechoke(
+        &mut self,
+        now: Instant,
+        peers: &[ChokeCandidate],
+        seeding: bool,
+    ) -> HashSet<PeerKey> {
+        let mut interested: Vec<&ChokeCandidate> = peers.iter().filter(|p| p.interested).collect();
+        interested.sort_by(|a, b| {
+            let (a, b) = if seeding {
+                (a.uploaded, b.uploaded)
+            } else {
+                (a.downloaded, b.downloaded)
+            };
+            b.cmp(&a)
+        });
+
+        let mut unchoked: HashSet<PeerKey> = interested
+            .iter()
+            .take(self.unchoke_slots)
+            .map(|p| p.key)
+            .collect();
+
+        // Forget optimistic picks that left, lost interest or earned a regular slot
+        self.optimistic
+            .retain(|id| !unchoked.contains(id) && interested.iter().any(|p| p.key == *id));
+
+        let rotate = match self.last_optimistic {
+            Some(last) => now.duration_since(last) >= OPTIMISTIC_INTERVAL,
+            None => true,
+        };
+        if rotate || self.optimistic.len() < self.optimistic_slots {
+            self.rotate_optimistic(&interested, &unchoked, rotate);
+            if rotate {
+                self.last_optimistic = Some(now);
+            }
+        }
+
+        unchoked.extend(self.optimistic.iter().copied());
+        unchoked
+    }
+
+    /// Replace (or top up) the optimistic picks with the next interested
+    /// peers after the current ones, wrapping around so every peer gets a turn
+    fn rotate_optimistic(
+        &mut self,
+        interested: &[&ChokeCandidate],
+        unchoked: &HashSet<PeerKey>,
+        replace: bool,
+    ) {
+        let mut choked: Vec<PeerKey> = interested
+            .iter()
+            .map(|p| p.key)
+            .filter(|id| !unchoked.contains(id))
+            .collect();
+        if choked.is_empty() {
+            self.optimistic.clear();
+            return;
+        }
+        choked.sort();
+
+        let start = self
+            .optimistic
+            .iter()
+            .filter_map(|id| choked.iter().position(|c| c == id))
+            .max()
+            .map(|i| i + 1)
+            .unwrap_or(0);
+
+        if replace {
+            self.optimistic.clear();
+        }
+        for offset in 0..choked.len() {
+            if self.optimistic.len() >= self.optimistic_slots {
+                break;
+            }
+            let id = choked[(start + offset) % choked.len()];
+            if !self.optimistic.contains(&id) {
+                self.optimistic.push(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(port: u16) -> PeerKey {
+        ([10, 0, 0, 1], port).into()
+    }
+
+    fn peer(port: u16, interested: bool, downloaded: u64, uploaded: u64) -> ChokeCandidate {
+        ChokeCandidate {
+            key: key(port),
+            interested,
+            downloaded,
+            uploaded,
+        }
+    }
+
+    fn keys(ports: &[u16]) -> HashSet<PeerKey> {
+        ports.iter().map(|&p| key(p)).collect()
+    }
+
+    #[test]
+    fn leeching_unchokes_fastest_uploaders_to_us() {
+        let peers = [
+            peer(1, true, 100, 9000),
+            peer(2, true, 500, 0),
+            peer(3, false, 9000, 0),
+            peer(4, true, 300, 0),
+            peer(5, true, 200, 0),
+        ];
+        let mut choker = Choker::new(3, 0);
+        assert_eq!(
+            choker.rechoke(Instant::now(), &peers, false),
+            keys(&[2, 4, 5])
+        );
+    }
+
+    #[test]
+    fn seeding_unchokes_fastest_downloaders_from_us() {
+        let peers = [
+            peer(1, true, 100, 9000),
+            peer(2, true, 500, 0),
+            peer(3, false, 0, 9999),
+            peer(4, true, 0, 300),
+            peer(5, true, 0, 200),
+        ];
+        let mut choker = Choker::new(2, 0);
+        assert_eq!(choker.rechoke(Instant::now(), &peers, true), keys(&[1, 4]));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_interval() {
+        let peers = [
+            peer(1, true, 1000, 0),
+            peer(2, true, 0, 0),
+            peer(3, true, 0, 0),
+            peer(4, true, 0, 0),
+            peer(5, false, 0, 0),
+        ];
+        let mut choker = Choker::new(1, 1);
+        let start = Instant::now();
+        assert_eq!(choker.rechoke(start, &peers, false), keys(&[1, 2]));
+
+        // Regular rounds keep the pick until the interval is up
+        let mut now = start + RECHOKE_INTERVAL;
+        assert_eq!(choker.rechoke(now, &peers, false), keys(&[1, 2]));
+        now = start + OPTIMISTIC_INTERVAL - Duration::from_secs(1);
+        assert_eq!(choker.rechoke(now, &peers, false), keys(&[1, 2]));
+
+        // Then it moves on to the next choked peer, wrapping around
+        now = start + OPTIMISTIC_INTERVAL;
+        assert_eq!(choker.rechoke(now, &peers, false), keys(&[1, 3]));
+        now += OPTIMISTIC_INTERVAL;
+        assert_eq!(choker.rechoke(now, &peers, false), keys(&[1, 4]));
+        now += OPTIMISTIC_INTERVAL;
+        assert_eq!(choker.rechoke(now, &peers, false), keys(&[1, 2]));
+    }
+
+    #[test]
+    fn optimistic_pick_replaced_when_it_leaves() {
+        let mut peers = vec![
+            peer(1, true, 1000, 0),
+            peer(2, true, 0, 0),
+            peer(3, true, 0, 0),
+        ];
+        let mut choker = Choker::new(1, 1);
+        let start = Instant::now();
+        assert_eq!(choker.rechoke(start, &peers, false), keys(&[1, 2]));
+
+        // Losing interest frees the optimistic slot before the interval is up
+        peers[1].interested = false;
+        let now = start + RECHOKE_INTERVAL;
+        assert_eq!(choker.rechoke(now, &peers, false), keys(&[1, 3]));
+    }
+}
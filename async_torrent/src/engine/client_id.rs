@@ -0,0 +1,203 @@
+/// Two letter codes of Azureus style peer ids, `-XXvvvv-`
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("RS", "async_torrent"),
+    ("SD", "Thunder"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Leading letters of Shadow style peer ids, `Xvvv--`
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Client name and version a peer id announces, or the printable part of
+/// it when the convention isn't one we know
+pub fn client_name(peer_id: &[u8; 20]) -> String {
+    azureus(peer_id)
+        .or_else(|| mainline(peer_id))
+        .or_else(|| shadow(peer_id))
+        .unwrap_or_else(|| unknown(peer_id))
+}
+
+/// Client name from the `v` field of an extension handshake, which is
+/// free text so it's only cleaned up for display
+pub fn client_from_version(v: &[u8]) -> Option<String> {
+    let v: String = String::from_utf8_lossy(v)
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(40)
+        .collect();
+    let v = v.trim();
+    (!v.is_empty()).then(|| v.to_string())
+}
+
+/// `-TR2940-`, the four version characters are 0-9 then A-Z
+fn azureus(id: &[u8; 20]) -> Option<String> {
+    if id[0] != b'-' || id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&id[1..3]).ok()?;
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or_else(|| format!("Unknown ({code})"), |(_, name)| name.to_string());
+    let version = &id[3..7];
+    if !version.iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let version = match code {
+        // Major then a two digit minor, the last character marks betas
+        "TR" => {
+            let minor = std::str::from_utf8(&version[1..3]).ok()?;
+            let suffix = match version[3] {
+                b'Z' | b'X' => " beta",
+                _ => "",
+            };
+            format!("{}.{minor}{suffix}", version[0] as char)
+        }
+        _ => {
+            let mut parts: Vec<u32> = version.iter().map(|&c| version_number(c)).collect();
+            // The build number is left out when it's zero
+            if parts.len() > 3 && parts[3] == 0 {
+                parts.pop();
+            }
+            parts
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+    };
+    Some(format!("{name} {version}"))
+}
+
+/// `M4-3-6--` or `M4-20-8-`, version numbers separated by dashes
+fn mainline(id: &[u8; 20]) -> Option<String> {
+    let name = match id[0] {
+        b'M' => "Mainline",
+        b'Q' => "Queen Bee",
+        _ => return None,
+    };
+    let head = std::str::from_utf8(&id[1..8]).ok()?;
+    let parts: Vec<&str> = head.trim_end_matches('-').split('-').collect();
+    if parts.len() != 3
+        || !parts
+            .iter()
+            .all(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return None;
+    }
+    Some(format!("{name} {}", parts.join(".")))
+}
+
+/// `S58B-----`, a letter then up to five version characters ended by dashes
+fn shadow(id: &[u8; 20]) -> Option<String> {
+    let (_, name) = SHADOW_CLIENTS.iter().find(|(c, _)| *c == id[0])?;
+    let end = id[1..6].iter().position(|&c| c == b'-')? + 1;
+    let version = &id[1..end];
+    if version.is_empty()
+        || !version.iter().all(u8::is_ascii_alphanumeric)
+        || id[end..end + 3] != *b"---"
+    {
+        return None;
+    }
+    let version: Vec<String> = version
+        .iter()
+        .map(|&c| version_number(c).to_string())
+        .collect();
+    Some(format!("{name} {}", version.join(".")))
+}
+
+fn unknown(id: &[u8; 20]) -> String {
+    let printable: String = id
+        .iter()
+        .take_while(|c| c.is_ascii_graphic())
+        .map(|&c| c as char)
+        .take(8)
+        .collect();
+    if printable.is_empty() {
+        "Unknown".to_string()
+    } else {
+        format!("Unknown ({printable})")
+    }
+}
+
+fn version_number(c: u8) -> u32 {
+    (c as char).to_digit(36).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pad the start of a peer id with random looking bytes
+    fn id(prefix: &[u8]) -> [u8; 20] {
+        let mut id = *b"x7Gq2LmP9wKz3RtV8nYb";
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    #[test]
+    fn azureus_style() {
+        assert_eq!(client_name(&id(b"-TR2940-")), "Transmission 2.94");
+        assert_eq!(client_name(&id(b"-TR294Z-")), "Transmission 2.94 beta");
+        assert_eq!(client_name(&id(b"-qB4520-")), "qBittorrent 4.5.2");
+        assert_eq!(client_name(&id(b"-UT355W-")), "µTorrent 3.5.5.32");
+        assert_eq!(client_name(&id(b"-ZZ1234-")), "Unknown (ZZ) 1.2.3.4");
+    }
+
+    #[test]
+    fn mainline_style() {
+        assert_eq!(client_name(&id(b"M4-3-6--")), "Mainline 4.3.6");
+        assert_eq!(client_name(&id(b"M4-20-8-")), "Mainline 4.20.8");
+    }
+
+    #[test]
+    fn shadow_style() {
+        assert_eq!(client_name(&id(b"S58B-----")), "Shadow 5.8.11");
+        assert_eq!(client_name(&id(b"T03I---")), "BitTornado 0.3.18");
+    }
+
+    #[test]
+    fn garbage() {
+        assert_eq!(client_name(&[0xff; 20]), "Unknown");
+        assert_eq!(client_name(&id(b"ABCDEFGHIJ\0")), "Unknown (ABCDEFGH)");
+        // Looks Azureus style but the version isn't
+        assert_eq!(client_name(&id(b"-TR\x01\x02\x03\x04-")), "Unknown (-TR)");
+        assert_eq!(client_name(&id(b"M4-x-6--")), "Unknown (M4-x-6--)");
+    }
+
+    #[test]
+    fn version_field() {
+        assert_eq!(
+            client_from_version(b"  qBittorrent/4.5.2\r\n").as_deref(),
+            Some("qBittorrent/4.5.2")
+        );
+        assert_eq!(client_from_version(b"\x00\x01 "), None);
+    }
+}
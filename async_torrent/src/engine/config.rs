@@ -0,0 +1,108 @@
+use std::path::PathBuf;
+
+use crate::engine::{
+    files::FilePriority,
+    mse::EncryptionPolicy,
+    network::{proxy::ProxyConfig, socket::OutgoingInterface},
+    piece_picker::Deadline,
+    storage::{Allocation, StorageKind},
+    transport::TransportPreference,
+};
+
+/// Tunables for the download engine
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// Regular unchoke slots handed out by the choker every round
+    pub unchoke_slots: usize,
+    /// Extra slots given to optimistically unchoked peers
+    pub optimistic_unchoke_slots: usize,
+    /// Rate limits in bytes per second across all torrents, 0 for unlimited
+    pub download_limit: u64,
+    pub upload_limit: u64,
+    /// Rate limits in bytes per second for this torrent, 0 for unlimited
+    pub torrent_download_limit: u64,
+    pub torrent_upload_limit: u64,
+    /// Connected peers across all torrents
+    pub max_peers: usize,
+    /// Connected peers for this torrent
+    pub max_peers_per_torrent: usize,
+    /// Outgoing connections still waiting for TCP connect
+    pub max_half_open: usize,
+    /// Seconds to wait for a connect or handshake to finish
+    pub connect_timeout: u64,
+    /// Seconds a peer may stay silent, or neither side interested, before
+    /// it is dropped
+    pub idle_timeout: u64,
+    /// Whether peer connections use message stream encryption
+    pub encryption: EncryptionPolicy,
+    /// Whether peers are dialed over TCP, uTP or one then the other
+    pub transport: TransportPreference,
+    /// Port we listen on for incoming peers and announce to trackers
+    pub listen_port: u16,
+    /// Proxy tracker announces and outgoing peer connections go through
+    pub proxy: Option<ProxyConfig>,
+    /// Interface or address every tracker and peer socket is bound to
+    pub outgoing: Option<OutgoingInterface>,
+    /// Blocklist of peer addresses in eMule, PeerGuardian or CIDR format
+    pub ip_filter: Option<PathBuf>,
+    /// Priority overrides by file index, every other file is normal
+    pub file_priorities: Vec<(usize, FilePriority)>,
+    /// Download pieces in order instead of rarest first
+    pub sequential: bool,
+    /// Start streaming a file from this position
+    pub deadline: Option<Deadline>,
+    /// Pieces ahead of the streaming position that get top priority
+    pub stream_window: usize,
+    /// Port of the localhost HTTP streaming server, 0 to disable it
+    pub http_port: u16,
+    /// Backend the torrent's data is kept in
+    pub storage: StorageKind,
+    /// How files on disk get their space
+    pub allocation: Allocation,
+    /// Directory the torrent's files are downloaded to
+    pub output_dir: PathBuf,
+    /// Directory the data is moved to once every wanted file is complete
+    pub move_completed: Option<PathBuf>,
+    /// Threads hashing, reading and writing pieces
+    pub disk_threads: usize,
+    /// Pieces waiting to be written before peers have to wait
+    pub disk_write_queue: usize,
+    /// Bytes of pieces kept in memory for writing and serving, 0 to disable
+    pub cache_size: usize,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            unchoke_slots: 4,
+            optimistic_unchoke_slots: 1,
+            download_limit: 0,
+            upload_limit: 0,
+            torrent_download_limit: 0,
+            torrent_upload_limit: 0,
+            max_peers: 200,
+            max_peers_per_torrent: 50,
+            max_half_open: 8,
+            connect_timeout: 5,
+            idle_timeout: 300,
+            encryption: EncryptionPolicy::Prefer,
+            transport: TransportPreference::PreferTcp,
+            listen_port: 6881,
+            proxy: None,
+            outgoing: None,
+            ip_filter: None,
+            file_priorities: Vec::new(),
+            sequential: false,
+            deadline: None,
+            stream_window: 16,
+            http_port: 7881,
+            storage: StorageKind::Filesystem,
+            allocation: Allocation::Sparse,
+            output_dir: PathBuf::from("."),
+            move_completed: None,
+            disk_threads: 4,
+            disk_write_queue: 32,
+            cache_size: 64 * 1024 * 1024,
+        }
+    }
+}
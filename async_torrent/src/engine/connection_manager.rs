@@ -0,0 +1,718 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde_bencoded::to_vec;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc, watch},
+    task::JoinSet,
+    time::{interval, timeout},
+};
+
+use crate::{
+    bencode::MetaInfo,
+    engine::{
+        AsyncError,
+        central_manager::PieceCommands,
+        config::EngineConfig,
+        disk_io::DiskIo,
+        events::UiEvent,
+        extension::{ExtendedHandshake, local_ipv6},
+        ip_filter::IpFilterControl,
+        mse::{self, EncryptionPolicy},
+        network::{
+            proxy::ProxyConfig,
+            socket::{OutgoingInterface, bind_tcp, canonical, connect_tcp, link_down, watch_link},
+        },
+        peers_task::{Idle, Peer},
+        rate_limiter::Bandwidth,
+        transport::{BoxedConnection, PeerStream, Transport, TransportPreference},
+        utp::UtpSocket,
+    },
+    utils::sha1_hash,
+};
+
+const BASE_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+const MAX_CONNECT_FAILURES: u32 = 6;
+const MAX_HANDSHAKE_FAILURES: u32 = 3;
+// Times we may drop a peer for being of no use before forgetting it
+const MAX_IDLE_DROPS: u32 = 3;
+const FILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection slots shared by every torrent in the process
+#[derive(Debug, Clone)]
+pub struct GlobalConnectionLimits {
+    pub peers: Arc<Semaphore>,
+    pub half_open: Arc<Semaphore>,
+}
+
+impl GlobalConnectionLimits {
+    pub fn new(max_peers: usize, max_half_open: usize) -> GlobalConnectionLimits {
+        GlobalConnectionLimits {
+            peers: Arc::new(Semaphore::new(max_peers)),
+            half_open: Arc::new(Semaphore::new(max_half_open)),
+        }
+    }
+}
+
+/// Where a peer address was learned from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Incoming,
+    /// Another address of a connected peer, from its extension handshake
+    Extension,
+}
+
+impl PeerSource {
+    /// Private torrents only take peers from the tracker (BEP 27)
+    fn allowed(self, private: bool) -> bool {
+        !private || self == PeerSource::Tracker
+    }
+}
+
+pub enum ConnEvent {
+    AddPeers(Vec<SocketAddr>, PeerSource),
+    Incoming(BoxedConnection, SocketAddr),
+    ConnectFailed(SocketAddr),
+    HandshakeFailed(SocketAddr),
+    Disconnected(SocketAddr, DisconnectReason),
+}
+
+/// Why an established connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Closed or failed, the peer is worth trying again soon
+    Closed,
+    /// We dropped it since neither side had any use for it
+    Idle,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    source: PeerSource,
+    connect_failures: u32,
+    handshake_failures: u32,
+    idle_drops: u32,
+    retry_at: Instant,
+}
+
+impl Candidate {
+    fn new(source: PeerSource, now: Instant) -> Candidate {
+        Candidate {
+            source,
+            connect_failures: 0,
+            handshake_failures: 0,
+            idle_drops: 0,
+            retry_at: now,
+        }
+    }
+
+    /// Peers that gave us the least trouble are tried first
+    fn rank(&self) -> u32 {
+        self.connect_failures + self.handshake_failures + self.idle_drops
+    }
+}
+
+/// Peer addresses we know of and when each may be tried again
+#[derive(Debug, Default)]
+struct Candidates(HashMap<SocketAddr, Candidate>);
+
+impl Candidates {
+    fn contains(&self, addr: &SocketAddr) -> bool {
+        self.0.contains_key(addr)
+    }
+
+    fn add(&mut self, addr: SocketAddr, source: PeerSource, now: Instant) {
+        self.0
+            .entry(addr)
+            .or_insert_with(|| Candidate::new(source, now));
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) {
+        self.0.remove(addr);
+    }
+
+    fn connect_failed(&mut self, addr: &SocketAddr, now: Instant) {
+        if let Some(candidate) = self.0.get_mut(addr) {
+            candidate.connect_failures += 1;
+            if candidate.connect_failures >= MAX_CONNECT_FAILURES {
+                self.0.remove(addr);
+            } else {
+                candidate.retry_at = now + backoff(candidate.connect_failures);
+            }
+        }
+    }
+
+    fn handshake_failed(&mut self, addr: &SocketAddr, now: Instant) {
+        if let Some(candidate) = self.0.get_mut(addr) {
+            candidate.handshake_failures += 1;
+            if candidate.handshake_failures >= MAX_HANDSHAKE_FAILURES
+                || candidate.source == PeerSource::Incoming
+            {
+                self.0.remove(addr);
+            } else {
+                candidate.retry_at = now + backoff(candidate.handshake_failures);
+            }
+        }
+    }
+
+    fn disconnected(&mut self, addr: &SocketAddr, reason: DisconnectReason, now: Instant) {
+        let Some(candidate) = self.0.get_mut(addr) else {
+            return;
+        };
+        // Incoming peers connect from ephemeral ports we can't dial back
+        if candidate.source == PeerSource::Incoming {
+            self.0.remove(addr);
+            return;
+        }
+        match reason {
+            DisconnectReason::Closed => {
+                candidate.connect_failures = 0;
+                candidate.retry_at = now + BASE_BACKOFF;
+            }
+            // Coming right back would only take the slot we freed again
+            DisconnectReason::Idle => {
+                candidate.idle_drops += 1;
+                if candidate.idle_drops >= MAX_IDLE_DROPS {
+                    self.0.remove(addr);
+                } else {
+                    candidate.retry_at = now + backoff(candidate.idle_drops + 2);
+                }
+            }
+        }
+    }
+
+    /// Outgoing candidates due for a connection attempt, best first
+    fn ready(&self, now: Instant, active: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let mut ready: Vec<(&SocketAddr, &Candidate)> = self
+            .0
+            .iter()
+            .filter(|(addr, c)| {
+                c.source != PeerSource::Incoming && c.retry_at <= now && !active.contains(addr)
+            })
+            .collect();
+        ready.sort_by_key(|(_, c)| c.rank());
+        ready.into_iter().map(|(addr, _)| *addr).collect()
+    }
+}
+
+/// Everything a connection task needs to run a peer
+#[derive(Clone)]
+struct PeerContext {
+    info: Arc<MetaInfo>,
+    info_hash: [u8; 20],
+    encryption: EncryptionPolicy,
+    transport: TransportPreference,
+    /// Shared with the listener, `None` when uTP is off
+    utp: Option<UtpSocket>,
+    /// Outgoing TCP connections go through this when set
+    proxy: Option<ProxyConfig>,
+    /// Interface or address outgoing connections are bound to
+    outgoing: Option<OutgoingInterface>,
+    /// Whether that interface is up, peers are dropped when it goes down
+    link: watch::Receiver<bool>,
+    cmd_tx: mpsc::Sender<PieceCommands>,
+    ui_tx: mpsc::Sender<UiEvent>,
+    bandwidth: Bandwidth,
+    disk: DiskIo,
+    events: mpsc::Sender<ConnEvent>,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+    /// Set while candidates wait for a slot, uninterested peers then make room
+    crowded: watch::Receiver<bool>,
+    listen_port: u16,
+    /// Our public IPv6 address, told to peers in the extension handshake
+    ipv6: Option<Ipv6Addr>,
+}
+
+/// Decides which peers to connect to and when.
+///
+/// Keeps a queue of candidate addresses, opens connections while there are
+/// free peer and half-open slots, and retries failed peers with exponential
+/// backoff. Peers that keep failing are forgotten.
+pub struct ConnectionManager {
+    ctx: PeerContext,
+    ip_filter: IpFilterControl,
+    /// Private torrents only take peers from the tracker (BEP 27)
+    private: bool,
+    global: GlobalConnectionLimits,
+    max_peers: usize,
+    candidates: Candidates,
+    active: HashSet<SocketAddr>,
+    tasks: JoinSet<()>,
+    crowded: watch::Sender<bool>,
+}
+
+impl ConnectionManager {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        info: Arc<MetaInfo>,
+        config: &EngineConfig,
+        global: GlobalConnectionLimits,
+        cmd_tx: mpsc::Sender<PieceCommands>,
+        ui_tx: mpsc::Sender<UiEvent>,
+        bandwidth: Bandwidth,
+        disk: DiskIo,
+        events: mpsc::Sender<ConnEvent>,
+        utp: Option<UtpSocket>,
+        ip_filter: IpFilterControl,
+    ) -> Result<ConnectionManager, AsyncError> {
+        let info_hash = sha1_hash(&to_vec(&info.info)?);
+        let private = info.info.is_private();
+        let (crowded, crowded_rx) = watch::channel(false);
+        Ok(ConnectionManager {
+            ctx: PeerContext {
+                info,
+                info_hash,
+                encryption: config.encryption,
+                transport: config.transport,
+                utp,
+                proxy: config.proxy.clone(),
+                outgoing: config.outgoing.clone(),
+                link: watch_link(config.outgoing.clone()),
+                cmd_tx,
+                ui_tx,
+                bandwidth,
+                disk,
+                events,
+                connect_timeout: Duration::from_secs(config.connect_timeout),
+                idle_timeout: Duration::from_secs(config.idle_timeout),
+                crowded: crowded_rx,
+                listen_port: config.listen_port,
+                ipv6: match (&config.proxy, &config.outgoing) {
+                    // Peers of a private torrent may only learn of us from the tracker
+                    _ if private => None,
+                    // Behind a proxy our own address isn't ours to give out
+                    (Some(_), _) => None,
+                    (None, Some(outgoing)) => match outgoing.local_ip(true) {
+                        Ok(IpAddr::V6(ip)) => Some(ip),
+                        _ => None,
+                    },
+                    (None, None) => local_ipv6(),
+                },
+            },
+            ip_filter,
+            private,
+            global,
+            max_peers: config.max_peers_per_torrent,
+            candidates: Candidates::default(),
+            active: HashSet::new(),
+            tasks: JoinSet::new(),
+            crowded,
+        })
+    }
+
+    pub async fn run(mut self, mut rx: mpsc::Receiver<ConnEvent>) {
+        let mut fill = interval(FILL_INTERVAL);
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => self.handle_event(event),
+                    None => break,
+                },
+                _ = fill.tick() => {}
+                Some(_) = self.tasks.join_next(), if !self.tasks.is_empty() => {}
+            }
+            self.fill_slots();
+        }
+    }
+
+    fn handle_event(&mut self, event: ConnEvent) {
+        let now = Instant::now();
+        match event {
+            ConnEvent::AddPeers(_, source) if !source.allowed(self.private) => {
+                eprintln!("Ignoring {source:?} peers for a private torrent");
+            }
+            ConnEvent::AddPeers(addrs, source) => {
+                // Every discovery channel ends up here, so blocked peers
+                // never become candidates
+                for addr in addrs {
+                    if self.candidates.contains(&addr) || !self.ip_filter.allows_outgoing(&addr) {
+                        continue;
+                    }
+                    self.candidates.add(addr, source, now);
+                }
+            }
+            ConnEvent::Incoming(socket, addr) => {
+                if !self.ip_filter.allows_incoming(&addr)
+                    || self.active.contains(&addr)
+                    || self.active.len() >= self.max_peers
+                    || !*self.ctx.link.borrow()
+                {
+                    return;
+                }
+                let Ok(peer_permit) = self.global.peers.clone().try_acquire_owned() else {
+                    return;
+                };
+                self.candidates.add(addr, PeerSource::Incoming, now);
+                self.active.insert(addr);
+                self.tasks
+                    .spawn(accept_peer(socket, addr, self.ctx.clone(), peer_permit));
+            }
+            ConnEvent::ConnectFailed(addr) => {
+                self.active.remove(&addr);
+                self.candidates.connect_failed(&addr, now);
+            }
+            ConnEvent::HandshakeFailed(addr) => {
+                self.active.remove(&addr);
+                self.candidates.handshake_failed(&addr, now);
+            }
+            ConnEvent::Disconnected(addr, reason) => {
+                self.active.remove(&addr);
+                self.candidates.disconnected(&addr, reason, now);
+            }
+        }
+    }
+
+    /// Start connecting to ready candidates while slots are free
+    fn fill_slots(&mut self) {
+        if !*self.ctx.link.borrow() {
+            return;
+        }
+        let ready = self.candidates.ready(Instant::now(), &self.active);
+        let mut crowded = false;
+        for addr in ready {
+            // The list may have been reloaded since the peer was added
+            if !self.ip_filter.allows_outgoing(&addr) {
+                self.candidates.remove(&addr);
+                continue;
+            }
+            if self.active.len() >= self.max_peers {
+                crowded = true;
+                break;
+            }
+            let Ok(half_open) = self.global.half_open.clone().try_acquire_owned() else {
+                break;
+            };
+            let Ok(peer_permit) = self.global.peers.clone().try_acquire_owned() else {
+                crowded = true;
+                break;
+            };
+            self.active.insert(addr);
+            self.tasks
+                .spawn(connect_peer(addr, self.ctx.clone(), half_open, peer_permit));
+        }
+        self.crowded
+            .send_if_modified(|c| std::mem::replace(c, crowded) != crowded);
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+async fn connect_peer(
+    addr: SocketAddr,
+    ctx: PeerContext,
+    half_open: OwnedSemaphorePermit,
+    peer_permit: OwnedSemaphorePermit,
+) {
+    // The first transport the peer answers on is the one we stay with
+    for &transport in ctx.transport.order() {
+        let Some(socket) = dial(addr, transport, &ctx).await else {
+            continue;
+        };
+        let handshake = mse::connect(socket, &ctx.info_hash, ctx.encryption);
+        let stream = match timeout(ctx.connect_timeout, handshake).await {
+            Ok(Ok(stream)) => Some(stream),
+            // Peers that don't know encryption hang up on the key exchange
+            _ if ctx.encryption == EncryptionPolicy::Prefer => {
+                dial(addr, transport, &ctx).await.map(PeerStream::plain)
+            }
+            _ => None,
+        };
+        drop(half_open);
+        match stream {
+            Some(stream) => run_peer(stream, addr, ctx, peer_permit).await,
+            None => {
+                let _ = ctx.events.send(ConnEvent::HandshakeFailed(addr)).await;
+            }
+        }
+        return;
+    }
+    let _ = ctx.events.send(ConnEvent::ConnectFailed(addr)).await;
+}
+
+async fn dial(
+    addr: SocketAddr,
+    transport: Transport,
+    ctx: &PeerContext,
+) -> Option<BoxedConnection> {
+    let connect = async {
+        let socket: BoxedConnection = match transport {
+            Transport::Tcp => match &ctx.proxy {
+                Some(proxy) => Box::new(proxy.connect(addr.into(), ctx.outgoing.as_ref()).await?),
+                None => Box::new(connect_tcp(addr, ctx.outgoing.as_ref()).await?),
+            },
+            Transport::Utp => {
+                let utp = ctx.utp.as_ref().ok_or(ErrorKind::Unsupported)?;
+                Box::new(utp.connect(addr).await?)
+            }
+        };
+        Ok::<_, std::io::Error>(socket)
+    };
+    match timeout(ctx.connect_timeout, connect).await {
+        Ok(Ok(socket)) => Some(socket),
+        _ => None,
+    }
+}
+
+async fn accept_peer(
+    socket: BoxedConnection,
+    addr: SocketAddr,
+    ctx: PeerContext,
+    peer_permit: OwnedSemaphorePermit,
+) {
+    let handshake = mse::accept(socket, &ctx.info_hash, ctx.encryption);
+    match timeout(ctx.connect_timeout, handshake).await {
+        Ok(Ok(stream)) => run_peer(stream, addr, ctx, peer_permit).await,
+        _ => {
+            let _ = ctx.events.send(ConnEvent::HandshakeFailed(addr)).await;
+        }
+    }
+}
+
+async fn run_peer(
+    stream: PeerStream,
+    addr: SocketAddr,
+    ctx: PeerContext,
+    _peer_permit: OwnedSemaphorePermit,
+) {
+    let new_peer = Peer::new(
+        stream,
+        addr,
+        ctx.info.clone(),
+        ctx.cmd_tx.clone(),
+        ctx.ui_tx.clone(),
+        ctx.bandwidth.clone(),
+        ctx.disk.clone(),
+        ExtendedHandshake::ours(ctx.listen_port, ctx.ipv6, addr),
+        ctx.idle_timeout,
+        ctx.crowded.clone(),
+        ctx.events.clone(),
+    );
+    let mut peer = match timeout(ctx.connect_timeout, new_peer).await {
+        Ok(Ok(peer)) => peer,
+        _ => {
+            let _ = ctx.events.send(ConnEvent::HandshakeFailed(addr)).await;
+            return;
+        }
+    };
+
+    let mut link = ctx.link.clone();
+    let mut reason = DisconnectReason::Closed;
+    tokio::select! {
+        result = peer.start() => if let Err(e) = result {
+            if e.is::<Idle>() {
+                reason = DisconnectReason::Idle;
+            }
+            eprintln!("Error {e}");
+        },
+        // Rather than let the OS reroute it over another interface
+        _ = link_down(&mut link) => {}
+    }
+    let _ = peer.sender.send(PieceCommands::PeerDead(addr)).await;
+    let _ = peer
+        .ui_tx
+        .send(UiEvent::PeerDisconnected(addr.to_string()))
+        .await
+        .ok();
+    let _ = ctx.events.send(ConnEvent::Disconnected(addr, reason)).await;
+}
+
+/// Accept incoming peer connections and hand them to the connection manager
+pub async fn listen(
+    port: u16,
+    outgoing: Option<OutgoingInterface>,
+    events: mpsc::Sender<ConnEvent>,
+) {
+    let listener = match bind_tcp(port, outgoing.as_ref()) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to listen on port {port}: {e}");
+            return;
+        }
+    };
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                if events
+                    .send(ConnEvent::Incoming(Box::new(socket), canonical(addr)))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Err(e) => eprintln!("Failed to accept peer: {e}"),
+        }
+    }
+}
+
+/// Accept incoming uTP connections on the shared socket
+pub async fn listen_utp(utp: UtpSocket, events: mpsc::Sender<ConnEvent>) {
+    loop {
+        match utp.accept().await {
+            Ok((socket, addr)) => {
+                if events
+                    .send(ConnEvent::Incoming(Box::new(socket), addr))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to accept uTP peer: {e}");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([10, 0, 0, 1], port).into()
+    }
+
+    fn tracker_peer(now: Instant) -> Candidates {
+        let mut candidates = Candidates::default();
+        candidates.add(addr(1), PeerSource::Tracker, now);
+        candidates
+    }
+
+    #[test]
+    fn private_torrent_only_takes_tracker_peers() {
+        assert!(PeerSource::Tracker.allowed(true));
+        assert!(!PeerSource::Extension.allowed(true));
+        assert!(PeerSource::Extension.allowed(false));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(2), BASE_BACKOFF * 2);
+        assert_eq!(backoff(4), BASE_BACKOFF * 8);
+        assert_eq!(backoff(8), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failed_peer_waits_for_its_backoff() {
+        let now = Instant::now();
+        let mut candidates = tracker_peer(now);
+        let active = HashSet::new();
+        assert_eq!(candidates.ready(now, &active), [addr(1)]);
+
+        candidates.connect_failed(&addr(1), now);
+        candidates.connect_failed(&addr(1), now);
+        assert_eq!(candidates.0[&addr(1)].retry_at, now + backoff(2));
+        assert!(candidates.ready(now + backoff(1), &active).is_empty());
+        assert_eq!(candidates.ready(now + backoff(2), &active), [addr(1)]);
+    }
+
+    #[test]
+    fn peer_is_forgotten_after_too_many_failures() {
+        let now = Instant::now();
+        let mut candidates = tracker_peer(now);
+        for _ in 1..MAX_CONNECT_FAILURES {
+            candidates.connect_failed(&addr(1), now);
+        }
+        assert!(candidates.contains(&addr(1)));
+        candidates.connect_failed(&addr(1), now);
+        assert!(!candidates.contains(&addr(1)));
+
+        let mut candidates = tracker_peer(now);
+        for _ in 0..MAX_HANDSHAKE_FAILURES {
+            candidates.handshake_failed(&addr(1), now);
+        }
+        assert!(!candidates.contains(&addr(1)));
+    }
+
+    #[test]
+    fn ready_peers_are_ordered_by_failures() {
+        let now = Instant::now();
+        let mut candidates = Candidates::default();
+        for port in 1..=4 {
+            candidates.add(addr(port), PeerSource::Tracker, now);
+        }
+        candidates.add(addr(5), PeerSource::Incoming, now);
+        candidates.connect_failed(&addr(1), now);
+        candidates.connect_failed(&addr(1), now);
+        candidates.handshake_failed(&addr(2), now);
+        candidates.disconnected(&addr(3), DisconnectReason::Idle, now);
+
+        let later = now + MAX_BACKOFF;
+        let active = HashSet::from([addr(4)]);
+        let ready = candidates.ready(later, &active);
+        // Incoming and connected peers are never dialed
+        assert_eq!(ready.len(), 3);
+        assert_eq!(ready[2], addr(1));
+
+        candidates.disconnected(&addr(1), DisconnectReason::Closed, later);
+        let ready = candidates.ready(later + BASE_BACKOFF, &HashSet::new());
+        let first: HashSet<SocketAddr> = ready[..2].iter().copied().collect();
+        assert_eq!(first, HashSet::from([addr(1), addr(4)]));
+    }
+
+    #[test]
+    fn closed_peer_is_retried_soon_with_failures_reset() {
+        let now = Instant::now();
+        let mut candidates = tracker_peer(now);
+        candidates.connect_failed(&addr(1), now);
+        candidates.disconnected(&addr(1), DisconnectReason::Closed, now);
+        let candidate = &candidates.0[&addr(1)];
+        assert_eq!(candidate.connect_failures, 0);
+        assert_eq!(candidate.retry_at, now + BASE_BACKOFF);
+    }
+
+    #[test]
+    fn idle_peer_backs_off_further_each_time_then_is_forgotten() {
+        let now = Instant::now();
+        let mut candidates = tracker_peer(now);
+        candidates.connect_failed(&addr(1), now);
+
+        candidates.disconnected(&addr(1), DisconnectReason::Idle, now);
+        let first = candidates.0[&addr(1)].retry_at;
+        assert!(first > now + BASE_BACKOFF);
+        assert_eq!(candidates.0[&addr(1)].connect_failures, 1);
+
+        candidates.disconnected(&addr(1), DisconnectReason::Idle, now);
+        assert!(candidates.0[&addr(1)].retry_at > first);
+
+        candidates.disconnected(&addr(1), DisconnectReason::Idle, now);
+        assert!(!candidates.contains(&addr(1)));
+    }
+
+    #[test]
+    fn idle_peer_is_not_ready_right_after_the_drop() {
+        let now = Instant::now();
+        let mut candidates = tracker_peer(now);
+        candidates.disconnected(&addr(1), DisconnectReason::Idle, now);
+        let active = HashSet::new();
+        assert!(candidates.ready(now + BASE_BACKOFF, &active).is_empty());
+        assert_eq!(candidates.ready(now + MAX_BACKOFF, &active), [addr(1)]);
+    }
+
+    #[test]
+    fn incoming_peer_is_forgotten_on_disconnect() {
+        let now = Instant::now();
+        let mut candidates = Candidates::default();
+        candidates.add(addr(1), PeerSource::Incoming, now);
+        candidates.add(addr(2), PeerSource::Incoming, now);
+        candidates.disconnected(&addr(1), DisconnectReason::Closed, now);
+        candidates.disconnected(&addr(2), DisconnectReason::Idle, now);
+        assert!(candidates.0.is_empty());
+    }
+}
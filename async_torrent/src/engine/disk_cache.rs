@@ -0,0 +1,300 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use crate::engine::files::FilePriority;
+
+/// Hit and miss counts and memory use shared with the UI
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub used: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    data: Arc<[u8]>,
+    last_used: u64,
+    /// File priorities at the time a piece still waiting to be written
+    /// arrived, so it lands where the part file logic expected it to
+    dirty: Option<Vec<FilePriority>>,
+}
+
+/// A run of adjacent pieces taken out of the cache to be written together
+pub struct DirtyRun {
+    pub pieces: Range<usize>,
+    pub priorities: Vec<FilePriority>,
+    pub data: Vec<u8>,
+}
+
+/// Whole pieces kept in memory in front of the storage backend.
+///
+/// Verified pieces are held back until enough of them have piled up and are
+/// then written in runs of adjacent pieces, turning a mostly sequential
+/// download into a few large writes. Pieces read for peers stay around as
+/// well, since peers ask for the blocks of a piece one after another.
+#[derive(Debug)]
+pub struct PieceCache {
+    /// Bytes the cache may hold, 0 disables it
+    budget: usize,
+    used: usize,
+    dirty: usize,
+    clock: u64,
+    entries: HashMap<usize, Entry>,
+    pub stats: Arc<CacheStats>,
+}
+
+impl PieceCache {
+    pub fn new(budget: usize) -> PieceCache {
+        PieceCache {
+            budget,
+            used: 0,
+            dirty: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            stats: Arc::new(CacheStats::default()),
+        }
+    }
+
+    /// Whether a piece of this size can be cached at all
+    pub fn fits(&self, len: usize) -> bool {
+        len > 0 && len <= self.budget
+    }
+
+    /// Copy a block out of a cached piece, counting the hit or miss
+    pub fn block(&mut self, piece_index: usize, begin: usize, len: usize) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let block = self.entries.get_mut(&piece_index).and_then(|entry| {
+            entry.last_used = self.clock;
+            entry.data.get(begin..begin + len).map(|b| b.to_vec())
+        });
+        let counter = match block {
+            Some(_) => &self.stats.hits,
+            None => &self.stats.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        block
+    }
+
+    /// Keep a piece that is already on disk
+    pub fn insert_clean(&mut self, piece_index: usize, data: Arc<[u8]>) {
+        if self.fits(data.len()) && !self.entries.contains_key(&piece_index) {
+            self.insert(piece_index, data, None);
+        }
+    }
+
+    /// Whether a piece waiting to be written fits next to the ones already
+    /// waiting. Dirty pieces can't be evicted, so they alone must stay
+    /// within the budget.
+    pub fn fits_dirty(&self, len: usize) -> bool {
+        self.fits(len) && self.dirty + len <= self.budget
+    }
+
+    /// Keep a verified piece until it is flushed. The caller checks `fits_dirty`.
+    pub fn insert_dirty(
+        &mut self,
+        piece_index: usize,
+        data: Arc<[u8]>,
+        priorities: Vec<FilePriority>,
+    ) {
+        self.dirty += data.len();
+        self.insert(piece_index, data, Some(priorities));
+    }
+
+    fn insert(&mut self, piece_index: usize, data: Arc<[u8]>, dirty: Option<Vec<FilePriority>>) {
+        self.clock += 1;
+        self.used += data.len();
+        let entry = Entry {
+            data,
+            last_used: self.clock,
+            dirty,
+        };
+        if let Some(old) = self.entries.insert(piece_index, entry) {
+            self.used -= old.data.len();
+            if old.dirty.is_some() {
+                self.dirty -= old.data.len();
+            }
+        }
+        self.evict();
+    }
+
+    /// Drop the least recently used clean pieces until the cache is back
+    /// under budget. Dirty pieces stay until they are flushed.
+    fn evict(&mut self) {
+        while self.used > self.budget {
+            let Some(piece_index) = self
+                .entries
+                .iter()
+                .filter(|(_, e)| e.dirty.is_none())
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(i, _)| *i)
+            else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&piece_index) {
+                self.used -= entry.data.len();
+            }
+        }
+        self.stats.used.store(self.used, Ordering::Relaxed);
+    }
+
+    /// Flush once half the budget is waiting to be written, leaving the
+    /// other half for pieces read by peers
+    pub fn should_flush(&self) -> bool {
+        self.dirty >= self.budget / 2
+    }
+
+    /// Copies of every dirty piece, grouped into runs of adjacent pieces.
+    /// They stay dirty until `mark_clean` says they made it to disk.
+    pub fn dirty_runs(&self) -> Vec<DirtyRun> {
+        let mut dirty: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|(i, e)| Some((*i, e.dirty.as_ref()?, &e.data)))
+            .collect();
+        dirty.sort_by_key(|(i, _, _)| *i);
+
+        let mut runs: Vec<DirtyRun> = Vec::new();
+        for (piece_index, priorities, data) in dirty {
+            match runs.last_mut() {
+                Some(run) if run.pieces.end == piece_index && run.priorities == *priorities => {
+                    run.pieces.end += 1;
+                    run.data.extend_from_slice(data);
+                }
+                _ => runs.push(DirtyRun {
+                    pieces: piece_index..piece_index + 1,
+                    priorities: priorities.clone(),
+                    data: data.to_vec(),
+                }),
+            }
+        }
+        runs
+    }
+
+    /// Drop a piece that never made it to disk, so it isn't written later
+    /// after all. Returns whether it was still waiting to be written.
+    pub fn discard_dirty(&mut self, piece_index: usize) -> bool {
+        if self
+            .entries
+            .get(&piece_index)
+            .is_none_or(|e| e.dirty.is_none())
+        {
+            return false;
+        }
+        let entry = self.entries.remove(&piece_index).unwrap();
+        self.used -= entry.data.len();
+        self.dirty -= entry.data.len();
+        self.stats.used.store(self.used, Ordering::Relaxed);
+        true
+    }
+
+    /// Pieces of a run are on disk now and may be evicted
+    pub fn mark_clean(&mut self, run: &DirtyRun) {
+        for piece_index in run.pieces.clone() {
+            if let Some(entry) = self.entries.get_mut(&piece_index)
+                && entry.dirty.take().is_some()
+            {
+                self.dirty -= entry.data.len();
+            }
+        }
+        self.evict();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn piece(fill: u8) -> Arc<[u8]> {
+        vec![fill; 10].into()
+    }
+
+    fn normal() -> Vec<FilePriority> {
+        vec![FilePriority::Normal; 2]
+    }
+
+    #[test]
+    fn dirty_runs_group_adjacent_pieces_with_equal_priorities() {
+        let mut cache = PieceCache::new(100);
+        let skipped = vec![FilePriority::Normal, FilePriority::Skip];
+        for index in [0, 1, 2, 4] {
+            cache.insert_dirty(index, piece(index as u8), normal());
+        }
+        cache.insert_dirty(5, piece(5), skipped.clone());
+        cache.insert_clean(3, piece(3));
+
+        let runs = cache.dirty_runs();
+        let pieces: Vec<_> = runs.iter().map(|r| r.pieces.clone()).collect();
+        assert_eq!(pieces, [0..3, 4..5, 5..6]);
+        assert_eq!(runs[0].data, [[0; 10], [1; 10], [2; 10]].concat());
+        assert_eq!(runs[2].priorities, skipped);
+    }
+
+    #[test]
+    fn runs_stay_dirty_until_marked_clean() {
+        let mut cache = PieceCache::new(100);
+        cache.insert_dirty(0, piece(0), normal());
+        cache.insert_dirty(1, piece(1), normal());
+        assert_eq!(cache.dirty_runs().len(), 1);
+
+        for run in cache.dirty_runs() {
+            cache.mark_clean(&run);
+        }
+        assert!(cache.dirty_runs().is_empty());
+        // Still cached for readers
+        assert_eq!(cache.block(1, 2, 3), Some(vec![1; 3]));
+    }
+
+    #[test]
+    fn least_recently_used_clean_pieces_go_first() {
+        let mut cache = PieceCache::new(30);
+        cache.insert_clean(0, piece(0));
+        cache.insert_dirty(1, piece(1), normal());
+        cache.insert_clean(2, piece(2));
+        cache.block(0, 0, 1);
+        cache.insert_clean(3, piece(3));
+
+        // Piece 2 was used least recently, the dirty piece is never evicted
+        assert!(cache.block(2, 0, 1).is_none());
+        for index in [0, 1, 3] {
+            assert!(cache.block(index, 0, 1).is_some());
+        }
+        assert_eq!(cache.stats.used.load(Ordering::Relaxed), 30);
+    }
+
+    #[test]
+    fn dirty_pieces_stay_within_budget() {
+        let mut cache = PieceCache::new(25);
+        assert!(cache.fits_dirty(10));
+        cache.insert_dirty(0, piece(0), normal());
+        cache.insert_dirty(1, piece(1), normal());
+        assert!(!cache.fits_dirty(10));
+        assert!(cache.should_flush());
+
+        for run in cache.dirty_runs() {
+            cache.mark_clean(&run);
+        }
+        assert!(cache.fits_dirty(10));
+        // Clean pieces make room for new dirty ones
+        cache.insert_dirty(2, piece(2), normal());
+        assert!(cache.used <= 25);
+    }
+
+    #[test]
+    fn discarded_piece_is_never_written() {
+        let mut cache = PieceCache::new(100);
+        cache.insert_dirty(0, piece(0), normal());
+        cache.insert_clean(1, piece(1));
+        assert!(cache.discard_dirty(0));
+        assert!(!cache.discard_dirty(1));
+        assert!(!cache.discard_dirty(2));
+        assert!(cache.dirty_runs().is_empty());
+        assert_eq!(cache.stats.used.load(Ordering::Relaxed), 10);
+    }
+}
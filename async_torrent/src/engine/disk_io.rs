@@ -0,0 +1,340 @@
+use std::{
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc as std_mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, oneshot};
+
+use crate::{
+    bencode::MetaInfo,
+    engine::{files::TorrentFiles, piece_store::BlockRequest},
+};
+
+// Cached pieces are written at least this often, busy or not
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+enum Job {
+    Hash {
+        piece_index: usize,
+        data: Vec<u8>,
+        reply: oneshot::Sender<(bool, Vec<u8>)>,
+    },
+    Write {
+        piece_index: usize,
+        data: Vec<u8>,
+        reply: oneshot::Sender<std::io::Result<()>>,
+        /// Released once the piece is on disk or in the cache, making room
+        /// for the next one
+        _slot: OwnedSemaphorePermit,
+    },
+    Read {
+        req: BlockRequest,
+        reply: oneshot::Sender<std::io::Result<Vec<u8>>>,
+    },
+    EnableFile {
+        file_index: usize,
+        /// Finished pieces overlapping the file, to be checked once it's in place
+        done: Vec<usize>,
+        reply: oneshot::Sender<Vec<usize>>,
+    },
+    RemoveFile {
+        file_index: usize,
+        reply: oneshot::Sender<std::io::Result<()>>,
+    },
+    MoveTo {
+        root: PathBuf,
+        reply: oneshot::Sender<std::io::Result<()>>,
+    },
+    Flush {
+        reply: oneshot::Sender<std::io::Result<()>>,
+    },
+}
+
+/// Queue depth and average service time shared with the workers
+#[derive(Debug, Default)]
+pub struct DiskStats {
+    pub queued: AtomicUsize,
+    /// Moving average of how long a job takes once a worker picks it up
+    pub latency_micros: AtomicU64,
+}
+
+impl DiskStats {
+    fn record(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros() as u64;
+        let old = self.latency_micros.load(Ordering::Relaxed);
+        let new = if old == 0 {
+            sample
+        } else {
+            (old * 7 + sample) / 8
+        };
+        self.latency_micros.store(new, Ordering::Relaxed);
+    }
+}
+
+/// Stops new downloads while set, keeping the reason for the TUI.
+/// Set when the disk fills up and cleared from the TUI once there is room.
+#[derive(Debug, Clone, Default)]
+pub struct PauseControl {
+    reason: Arc<RwLock<Option<String>>>,
+}
+
+impl PauseControl {
+    pub fn pause(&self, reason: &str) {
+        *self.reason.write().unwrap() = Some(reason.to_string());
+    }
+
+    pub fn resume(&self) {
+        *self.reason.write().unwrap() = None;
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        self.reason.read().unwrap().clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.reason.read().unwrap().is_some()
+    }
+}
+
+/// Whether an error means there's no room left to write
+pub fn is_disk_full(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded)
+}
+
+/// Runs hashing and disk reads and writes on a pool of plain threads so slow
+/// disks never stall the tokio workers.
+///
+/// Writes take a slot in a bounded queue first. A peer whose write has to
+/// wait for a slot stops reading from its socket, which pushes back on the
+/// remote side until the disk catches up.
+#[derive(Debug, Clone)]
+pub struct DiskIo {
+    jobs: std_mpsc::Sender<Job>,
+    write_slots: Arc<Semaphore>,
+    pub stats: Arc<DiskStats>,
+}
+
+impl DiskIo {
+    pub fn new(
+        info: Arc<MetaInfo>,
+        files: TorrentFiles,
+        threads: usize,
+        queued_writes: usize,
+        pause: PauseControl,
+    ) -> DiskIo {
+        let (jobs, rx) = std_mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let stats = Arc::new(DiskStats::default());
+        let last_flush = Arc::new(Mutex::new(Instant::now()));
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            let info = info.clone();
+            let files = files.clone();
+            let stats = stats.clone();
+            let pause = pause.clone();
+            let last_flush = last_flush.clone();
+            thread::Builder::new()
+                .name(format!("disk-io-{i}"))
+                .spawn(move || worker(rx, info, files, stats, pause, last_flush))
+                .expect("failed to spawn disk thread");
+        }
+        DiskIo {
+            jobs,
+            write_slots: Arc::new(Semaphore::new(queued_writes.max(1))),
+            stats,
+        }
+    }
+
+    fn submit(&self, job: Job) {
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        // Workers only go away when every handle is dropped
+        let _ = self.jobs.send(job);
+    }
+
+    /// Check a piece against its hash, handing the data back
+    pub async fn hash(&self, piece_index: usize, data: Vec<u8>) -> (bool, Vec<u8>) {
+        let (reply, rx) = oneshot::channel();
+        self.submit(Job::Hash {
+            piece_index,
+            data,
+            reply,
+        });
+        rx.await.unwrap_or((false, Vec::new()))
+    }
+
+    /// Write a verified piece, waiting for room in the write queue first
+    pub async fn write(&self, piece_index: usize, data: Vec<u8>) -> std::io::Result<()> {
+        let slot = self
+            .write_slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("write queue closed");
+        let (reply, rx) = oneshot::channel();
+        self.submit(Job::Write {
+            piece_index,
+            data,
+            reply,
+            _slot: slot,
+        });
+        rx.await
+            .unwrap_or_else(|_| Err(std::io::Error::other("disk thread stopped")))
+    }
+
+    pub async fn read(&self, req: BlockRequest) -> std::io::Result<Vec<u8>> {
+        let (reply, rx) = oneshot::channel();
+        self.submit(Job::Read { req, reply });
+        rx.await
+            .unwrap_or_else(|_| Err(std::io::Error::other("disk thread stopped")))
+    }
+
+    /// Create a file that was skipped before and check the finished pieces
+    /// overlapping it, returning those that have to be downloaded again
+    pub async fn enable_file(&self, file_index: usize, done: Vec<usize>) -> Vec<usize> {
+        let (reply, rx) = oneshot::channel();
+        self.submit(Job::EnableFile {
+            file_index,
+            done: done.clone(),
+            reply,
+        });
+        rx.await.unwrap_or(done)
+    }
+
+    /// Delete a skipped file nothing was downloaded for
+    pub async fn remove_file(&self, file_index: usize) -> std::io::Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.submit(Job::RemoveFile { file_index, reply });
+        rx.await
+            .unwrap_or_else(|_| Err(std::io::Error::other("disk thread stopped")))
+    }
+
+    /// Move the torrent's files under a new directory
+    pub async fn move_to(&self, root: PathBuf) -> std::io::Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.submit(Job::MoveTo { root, reply });
+        rx.await
+            .unwrap_or_else(|_| Err(std::io::Error::other("disk thread stopped")))
+    }
+
+    /// Write every cached piece out
+    pub async fn flush(&self) -> std::io::Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.submit(Job::Flush { reply });
+        rx.await
+            .unwrap_or_else(|_| Err(std::io::Error::other("disk thread stopped")))
+    }
+}
+
+fn worker(
+    rx: Arc<Mutex<std_mpsc::Receiver<Job>>>,
+    info: Arc<MetaInfo>,
+    files: TorrentFiles,
+    stats: Arc<DiskStats>,
+    pause: PauseControl,
+    last_flush: Arc<Mutex<Instant>>,
+) {
+    // Running out of space stops the download instead of failing every peer
+    let check = |result: std::io::Result<()>| {
+        if let Err(e) = &result
+            && is_disk_full(e)
+            && !pause.is_paused()
+        {
+            eprintln!("Disk full, pausing the torrent: {e}");
+            pause.pause("disk full");
+        }
+        result
+    };
+    // Shared by the workers so only one of them flushes per interval
+    let flush_if_due = || {
+        {
+            let mut last = last_flush.lock().unwrap();
+            if last.elapsed() < FLUSH_INTERVAL {
+                return;
+            }
+            *last = Instant::now();
+        }
+        if let Err(e) = check(files.flush()) {
+            eprintln!("Failed to flush cached pieces: {e}");
+        }
+    };
+    loop {
+        let job = match rx.lock().unwrap().recv_timeout(FLUSH_INTERVAL) {
+            Ok(job) => job,
+            Err(std_mpsc::RecvTimeoutError::Timeout) => {
+                flush_if_due();
+                continue;
+            }
+            Err(std_mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        let start = Instant::now();
+        match job {
+            Job::Hash {
+                piece_index,
+                data,
+                reply,
+            } => {
+                let ok = verify_hash(&data, piece_index, &info.info.pieces);
+                let _ = reply.send((ok, data));
+            }
+            Job::Write {
+                piece_index,
+                data,
+                reply,
+                _slot,
+            } => {
+                let _ = reply.send(check(files.write_piece(piece_index, data)));
+            }
+            Job::Read { req, reply } => {
+                let _ = reply.send(files.read_block(req));
+            }
+            Job::EnableFile {
+                file_index,
+                done,
+                reply,
+            } => {
+                if let Err(e) = check(files.enable_file(&info.info, file_index)) {
+                    eprintln!("Failed to enable file {file_index}: {e}");
+                }
+                // Download again whatever didn't make it into place
+                let lost = done
+                    .into_iter()
+                    .filter(|&i| !matches!(files.verify_piece(&info.info, i), Ok(true)))
+                    .collect();
+                let _ = reply.send(lost);
+            }
+            Job::RemoveFile { file_index, reply } => {
+                let _ = reply.send(files.remove_file(file_index));
+            }
+            Job::MoveTo { root, reply } => {
+                let _ = reply.send(check(files.move_to(&root)));
+            }
+            Job::Flush { reply } => {
+                *last_flush.lock().unwrap() = Instant::now();
+                let _ = reply.send(check(files.flush()));
+            }
+        }
+        stats.record(start.elapsed());
+        stats.queued.fetch_sub(1, Ordering::Relaxed);
+        flush_if_due();
+    }
+}
+
+fn verify_hash(piece_buf: &[u8], piece_index: usize, pieces_field: &[u8]) -> bool {
+    let start = piece_index * 20;
+    let end = start + 20;
+    let expected_hash = &pieces_field[start..end];
+
+    let mut hasher = Sha1::new();
+    hasher.update(piece_buf);
+    let result = hasher.finalize();
+
+    result[..] == expected_hash[..]
+}
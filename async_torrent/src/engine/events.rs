@@ -3,10 +3,39 @@ pub enum UiEvent {
     PieceDownloading(usize),
     PieceCompleted(usize),
 
+    /// Peers are named by address
     PeerUpdate {
-        peer_id: String,
+        peer: String,
         task: String,
         choked: bool,
     },
     PeerDisconnected(String),
+    /// Client and version the peer runs, from its peer id and later from
+    /// its extension handshake
+    PeerClient {
+        peer: String,
+        client: String,
+    },
+    /// Rolling payload rates of one peer, in bytes per second
+    PeerRates {
+        peer: String,
+        download_rate: u64,
+        upload_rate: u64,
+    },
+    /// Rolling payload rates and totals for the whole torrent
+    TransferRates {
+        download_rate: u64,
+        upload_rate: u64,
+        downloaded: u64,
+        uploaded: u64,
+    },
+    /// Disk jobs waiting or running, how long a job takes on average and
+    /// how well the piece cache serves reads
+    DiskStats {
+        queued: usize,
+        latency: std::time::Duration,
+        cache_used: usize,
+        cache_hits: u64,
+        cache_misses: u64,
+    },
 }
@@ -0,0 +1,84 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::bencode::RawString;
+
+/// Message id every BEP 10 extension message is sent under
+pub const EXTENDED_ID: u8 = 20;
+/// Extended message id of the extension handshake
+pub const HANDSHAKE_ID: u8 = 0;
+/// Reserved handshake byte and bit announcing the extension protocol
+pub const RESERVED_BYTE: usize = 5;
+pub const RESERVED_BIT: u8 = 0x10;
+
+const CLIENT: &str = concat!("async_torrent ", env!("CARGO_PKG_VERSION"));
+
+/// BEP 10 extension handshake. We don't speak any extension messages yet,
+/// so ours only tells the other side about us.
+///
+/// Fields are declared in key order since dictionaries are encoded as is.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Compact IPv6 address of the sender, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<ByteBuf>,
+    /// Extension messages supported and the ids to send them with
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Port the sender listens on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// Client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<RawString>,
+    /// The receiver's address as the sender sees it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    pub fn ours(port: u16, ipv6: Option<Ipv6Addr>, remote: SocketAddr) -> ExtendedHandshake {
+        let yourip = match remote.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        ExtendedHandshake {
+            ipv6: ipv6.map(|ip| ByteBuf::from(ip.octets())),
+            m: BTreeMap::new(),
+            p: Some(port),
+            v: Some(RawString::from(CLIENT)),
+            yourip: Some(ByteBuf::from(yourip)),
+        }
+    }
+
+    /// The sender's IPv6 address, which it may be reached on as well
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        let octets: [u8; 16] = self.ipv6.as_deref()?.as_slice().try_into().ok()?;
+        Some(Ipv6Addr::from(octets))
+    }
+}
+
+/// Our public IPv6 address, if we have one. Connecting a UDP socket sends
+/// nothing, it only makes the OS pick the source address it would use.
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind("[::]:0").ok()?;
+    // Any global unicast address does, this one belongs to a public resolver
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip)
+            if !ip.is_loopback()
+                && !ip.is_unspecified()
+                && !ip.is_unicast_link_local()
+                && !ip.is_unique_local()
+                && ip.to_ipv4_mapped().is_none() =>
+        {
+            Some(ip)
+        }
+        _ => None,
+    }
+}
@@ -1,47 +1,572 @@
 use std::{
-    fs::{File, create_dir_all},
-    path::Path,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 use crate::bencode::{FileMode, Info};
+use crate::engine::{
+    disk_cache::{CacheStats, PieceCache},
+    part_file::PartFile,
+    paths::sanitize_path,
+    piece_store::BlockRequest,
+    storage::Storage,
+};
+
+/// How much we want a file. Ordered from least to most wanted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    /// Next priority when cycling through them in the TUI
+    pub fn next(self) -> FilePriority {
+        match self {
+            FilePriority::Normal => FilePriority::High,
+            FilePriority::High => FilePriority::Skip,
+            FilePriority::Skip => FilePriority::Low,
+            FilePriority::Low => FilePriority::Normal,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FilePriority::Skip => "skip",
+            FilePriority::Low => "low",
+            FilePriority::Normal => "normal",
+            FilePriority::High => "high",
+        }
+    }
+}
+
+impl FromStr for FilePriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => Err(format!("Unknown priority {s}")),
+        }
+    }
+}
 
-pub fn initialize_files(info: &Info) -> std::io::Result<()> {
+/// Per-file priorities shared between the TUI and the engine.
+/// The generation is bumped on every change so the engine can cheaply
+/// notice it has to recompute piece priorities.
+#[derive(Debug, Clone)]
+pub struct FilePriorities {
+    priorities: Arc<RwLock<Vec<FilePriority>>>,
+    generation: Arc<AtomicU64>,
+}
+
+impl FilePriorities {
+    pub fn new(priorities: Vec<FilePriority>) -> FilePriorities {
+        FilePriorities {
+            priorities: Arc::new(RwLock::new(priorities)),
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn get(&self, file_index: usize) -> Option<FilePriority> {
+        self.priorities.read().unwrap().get(file_index).copied()
+    }
+
+    pub fn set(&self, file_index: usize, priority: FilePriority) {
+        if let Some(p) = self.priorities.write().unwrap().get_mut(file_index) {
+            *p = priority;
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn snapshot(&self) -> Vec<FilePriority> {
+        self.priorities.read().unwrap().clone()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+/// Number of files in the torrent (1 for single file mode)
+pub fn file_count(info: &Info) -> usize {
+    match &info.mode {
+        FileMode::MultipleFiles { files } => files.len(),
+        FileMode::SingleFile { .. } => 1,
+    }
+}
+
+/// Path of a file on disk, relative to the download directory
+pub fn file_path(info: &Info, file_index: usize) -> std::io::Result<PathBuf> {
+    let name = info.name.as_bytes();
+    match &info.mode {
+        FileMode::MultipleFiles { files } => {
+            let path = files[file_index].path.iter().map(|p| p.as_bytes());
+            sanitize_path(std::iter::once(name).chain(path))
+        }
+        FileMode::SingleFile { .. } => sanitize_path([name]),
+    }
+}
+
+/// Byte range `(offset, length)` of every file inside the torrent's data
+pub fn file_spans(info: &Info) -> Vec<(u64, u64)> {
     match &info.mode {
         FileMode::MultipleFiles { files } => {
-            for file in files {
-                let file_name = format!("{}/{}", info.name, file.path.join("/"));
+            let mut offset = 0;
+            files
+                .iter()
+                .map(|f| {
+                    let span = (offset, f.length);
+                    offset += f.length;
+                    span
+                })
+                .collect()
+        }
+        FileMode::SingleFile { length } => vec![(0, *length)],
+    }
+}
+
+/// Pieces overlapping a file
+pub fn file_pieces(info: &Info, file_index: usize) -> Range<usize> {
+    let (offset, length) = file_spans(info)[file_index];
+    if length == 0 {
+        return 0..0;
+    }
+    let first = (offset / info.piece_length) as usize;
+    let last = ((offset + length - 1) / info.piece_length) as usize;
+    first..last + 1
+}
 
-                if let Some(parent) = Path::new(file_name.as_str()).parent() {
-                    create_dir_all(parent)?;
+/// A piece is as important as the most important file it overlaps
+pub fn piece_priorities(info: &Info, file_priorities: &[FilePriority]) -> Vec<FilePriority> {
+    let num_pieces = info.pieces.len().div_ceil(20);
+    let mut priorities = vec![FilePriority::Skip; num_pieces];
+    for (file_index, priority) in file_priorities.iter().enumerate() {
+        for piece_index in file_pieces(info, file_index) {
+            priorities[piece_index] = priorities[piece_index].max(*priority);
+        }
+    }
+    priorities
+}
+
+/// Which files exist in storage, and the part file holding the pieces that
+/// overlap the ones that don't
+#[derive(Debug)]
+struct Placement {
+    parts: PartFile,
+    allocated: Vec<bool>,
+}
+
+impl Placement {
+    /// Where piece data may go: files that don't exist count as skipped,
+    /// whatever their priority, so nothing is written to them before they
+    /// are allocated
+    fn priorities(&self) -> Vec<FilePriority> {
+        self.allocated
+            .iter()
+            .map(|&a| {
+                if a {
+                    FilePriority::Normal
+                } else {
+                    FilePriority::Skip
                 }
+            })
+            .collect()
+    }
+}
+
+/// Reads and writes piece data through the storage backend, keeping the
+/// parts of pieces that belong to skipped files in a hidden part file instead
+/// and recent pieces in a memory cache in front of both
+#[derive(Debug, Clone)]
+pub struct TorrentFiles {
+    pub priorities: FilePriorities,
+    pub storage: Arc<dyn Storage>,
+    placement: Arc<Mutex<Placement>>,
+    cache: Arc<Mutex<PieceCache>>,
+    /// Held while a flush is writing so others wait for it to land
+    flushing: Arc<Mutex<()>>,
+}
+
+impl TorrentFiles {
+    pub fn new(
+        info: &Info,
+        priorities: FilePriorities,
+        storage: Arc<dyn Storage>,
+        root: &Path,
+        cache_size: usize,
+    ) -> TorrentFiles {
+        TorrentFiles {
+            priorities,
+            storage,
+            placement: Arc::new(Mutex::new(Placement {
+                parts: PartFile::new(info, root),
+                allocated: vec![false; file_count(info)],
+            })),
+            cache: Arc::new(Mutex::new(PieceCache::new(cache_size))),
+            flushing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn cache_stats(&self) -> Arc<CacheStats> {
+        self.cache.lock().unwrap().stats.clone()
+    }
+
+    /// Create every wanted file from scratch. Skipped files are left out.
+    pub fn initialize(&self) -> std::io::Result<()> {
+        let mut placement = self.placement.lock().unwrap();
+        for (file_index, priority) in self.priorities.snapshot().iter().enumerate() {
+            if *priority != FilePriority::Skip {
+                self.storage.allocate(file_index, true)?;
+                placement.allocated[file_index] = true;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_piece(&self, piece_index: usize, data: Vec<u8>) -> std::io::Result<()> {
+        // Pieces only go to files that exist. One that was just enabled gets
+        // its share moved over from the part file once it is allocated.
+        let mut placement = self.placement.lock().unwrap();
+        let priorities = placement.priorities();
+        let overlaps_skipped = self
+            .storage
+            .layout()
+            .piece(piece_index)
+            .iter()
+            .any(|s| priorities.get(s.file_index) == Some(&FilePriority::Skip));
+        if overlaps_skipped {
+            placement.parts.write_piece(piece_index, &data)?;
+        }
 
-                let f = File::options()
-                    .create(true)
-                    .read(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(file_name)?;
-                // Preallocate size to files so that pieces can be written
-                f.set_len(file.length)?;
+        let mut cache = self.cache.lock().unwrap();
+        if !cache.fits_dirty(data.len()) {
+            drop(cache);
+            return self.storage.write_pieces(piece_index, &data, &priorities);
+        }
+        cache.insert_dirty(piece_index, data.into(), priorities);
+        let flush = cache.should_flush();
+        drop(cache);
+        drop(placement);
+        if flush && let Err(e) = self.flush() {
+            // The piece is reported as failed and downloaded again, so it
+            // mustn't reach the disk later on. Unless it already did.
+            if self.cache.lock().unwrap().discard_dirty(piece_index) {
+                return Err(e);
             }
         }
-        FileMode::SingleFile { length } => {
-            let file_name = info.name.as_str();
+        Ok(())
+    }
+
+    /// Write every cached piece that isn't on disk yet
+    pub fn flush(&self) -> std::io::Result<()> {
+        let _flushing = self.flushing.lock().unwrap();
+        // Pieces stay dirty until written, so a failed flush is retried later
+        let runs = self.cache.lock().unwrap().dirty_runs();
+        for run in runs {
+            self.storage
+                .write_pieces(run.pieces.start, &run.data, &run.priorities)?;
+            self.cache.lock().unwrap().mark_clean(&run);
+        }
+        Ok(())
+    }
+
+    pub fn read_block(&self, req: BlockRequest) -> std::io::Result<Vec<u8>> {
+        let piece_index = req.index as usize;
+        let (begin, len) = (req.begin as usize, req.length as usize);
+        if let Some(block) = self.cache.lock().unwrap().block(piece_index, begin, len) {
+            return Ok(block);
+        }
+
+        let placement = self.placement.lock().unwrap();
+        let mut block = vec![0u8; len];
+        if placement
+            .parts
+            .read(piece_index, begin as u64, &mut block)?
+        {
+            return Ok(block);
+        }
+        drop(placement);
+
+        // Peers usually go on to ask for the rest of the piece, so read all
+        // of it while the disk is at it
+        let piece_len = self.storage.layout().piece_len(piece_index);
+        if !self.cache.lock().unwrap().fits(piece_len as usize) {
+            return self.storage.read_block(req);
+        }
+        let piece: Arc<[u8]> = self
+            .storage
+            .read_block(BlockRequest {
+                index: req.index,
+                begin: 0,
+                length: piece_len as u32,
+            })?
+            .into();
+        let block = piece
+            .get(begin..begin + len)
+            .map(|b| b.to_vec())
+            .ok_or_else(|| std::io::Error::other("block exceeds piece boundaries"))?;
+        self.cache.lock().unwrap().insert_clean(piece_index, piece);
+        Ok(block)
+    }
+
+    /// Whether a finished piece is intact where it is kept
+    pub fn verify_piece(&self, info: &Info, piece_index: usize) -> std::io::Result<bool> {
+        self.flush()?;
+        // Pieces in the part file were verified before they were written
+        if self.placement.lock().unwrap().parts.contains(piece_index) {
+            return Ok(true);
+        }
+        self.storage.verify_piece(info, piece_index)
+    }
+
+    /// Create a file that was skipped and has been enabled since, moving in
+    /// the data already downloaded for it. Nothing happens if the file was
+    /// skipped again in the meantime.
+    pub fn enable_file(&self, info: &Info, file_index: usize) -> std::io::Result<()> {
+        let mut placement = self.placement.lock().unwrap();
+        if placement.allocated[file_index]
+            || self.priorities.get(file_index) == Some(FilePriority::Skip)
+        {
+            return Ok(());
+        }
+        self.storage.allocate(file_index, false)?;
+        placement
+            .parts
+            .move_into_place(info, file_index, self.storage.as_ref())?;
+        placement.allocated[file_index] = true;
+        let priorities = placement.priorities();
+        placement.parts.release(info, &priorities);
+        Ok(())
+    }
+
+    /// Delete a file that was skipped before any of it was downloaded.
+    /// Nothing happens if the file was enabled again in the meantime.
+    pub fn remove_file(&self, file_index: usize) -> std::io::Result<()> {
+        let mut placement = self.placement.lock().unwrap();
+        if !placement.allocated[file_index]
+            || self.priorities.get(file_index) != Some(FilePriority::Skip)
+        {
+            return Ok(());
+        }
+        // Cached pieces may still be headed for it
+        self.flush()?;
+        placement.allocated[file_index] = false;
+        self.storage.delete(file_index)
+    }
+
+    /// Move every file under a new directory once pending writes are done
+    pub fn move_to(&self, root: &Path) -> std::io::Result<()> {
+        self.flush()?;
+        self.storage.move_to(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::{bencode::File, engine::storage::MemoryStorage, utils::sha1_hash};
+
+    const PIECE_LENGTH: u64 = 16;
+    // Every piece but the last crosses a file boundary
+    const FILE_LENGTHS: [u64; 4] = [10, 25, 7, 20];
 
-            if let Some(parent) = Path::new(file_name).parent() {
-                create_dir_all(parent)?;
+    fn torrent(data: &[u8]) -> Info {
+        let pieces: Vec<u8> = data
+            .chunks(PIECE_LENGTH as usize)
+            .flat_map(sha1_hash)
+            .collect();
+        Info {
+            piece_length: PIECE_LENGTH,
+            pieces: ByteBuf::from(pieces),
+            name: "torrent".into(),
+            private: None,
+            mode: FileMode::MultipleFiles {
+                files: FILE_LENGTHS
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &length)| File {
+                        length,
+                        path: vec![format!("file{i}").as_str().into()],
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn content() -> Vec<u8> {
+        let total: u64 = FILE_LENGTHS.iter().sum();
+        (0..total).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Directory for the part file, removed when the test is done
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("async_torrent-{name}-{}", std::process::id()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(info: &Info, priorities: Vec<FilePriority>, root: &Path, cache: usize) -> TorrentFiles {
+        let files = TorrentFiles::new(
+            info,
+            FilePriorities::new(priorities),
+            Arc::new(MemoryStorage::new(info)),
+            root,
+            cache,
+        );
+        files.initialize().unwrap();
+        files
+    }
+
+    fn write_all(files: &TorrentFiles, data: &[u8]) {
+        for (i, piece) in data.chunks(PIECE_LENGTH as usize).enumerate() {
+            files.write_piece(i, piece.to_vec()).unwrap();
+        }
+        files.flush().unwrap();
+    }
+
+    fn read(files: &TorrentFiles, index: usize, begin: u32, length: u32) -> Vec<u8> {
+        files
+            .read_block(BlockRequest {
+                index: index as u32,
+                begin,
+                length,
+            })
+            .unwrap()
+    }
+
+    fn stored_file(files: &TorrentFiles, file_index: usize) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0; FILE_LENGTHS[file_index] as usize];
+        files.storage.read_at(file_index, 0, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn file_data(data: &[u8], file_index: usize) -> &[u8] {
+        let start: u64 = FILE_LENGTHS[..file_index].iter().sum();
+        &data[start as usize..(start + FILE_LENGTHS[file_index]) as usize]
+    }
+
+    #[test]
+    fn pieces_across_files_round_trip() {
+        let dir = TempDir::new("round-trip");
+        let data = content();
+        let info = torrent(&data);
+        // Without a cache and with one big enough to hold everything
+        for cache in [0, 1024] {
+            let files = open(&info, vec![FilePriority::Normal; 4], &dir.0, cache);
+            write_all(&files, &data);
+
+            for file_index in 0..FILE_LENGTHS.len() {
+                assert_eq!(
+                    stored_file(&files, file_index).unwrap(),
+                    file_data(&data, file_index)
+                );
             }
+            for piece_index in 0..4 {
+                assert!(files.verify_piece(&info, piece_index).unwrap());
+            }
+            // Piece 2 starts in file 1 and ends in file 3
+            assert_eq!(read(&files, 2, 0, 16), &data[32..48]);
+            assert_eq!(read(&files, 2, 2, 10), &data[34..44]);
+            assert_eq!(read(&files, 3, 0, 14), &data[48..62]);
+        }
+    }
+
+    #[test]
+    fn skipped_file_goes_to_part_file() {
+        let dir = TempDir::new("part-file");
+        let data = content();
+        let info = torrent(&data);
+        let mut priorities = vec![FilePriority::Normal; 4];
+        priorities[1] = FilePriority::Skip;
+        let files = open(&info, priorities, &dir.0, 0);
+        write_all(&files, &data);
 
-            let f = File::options()
-                .create(true)
-                .read(true)
-                .write(true)
-                .truncate(true)
-                .open(file_name)?;
-            // Preallocate size to files so that pieces can be written
-            f.set_len(*length)?;
+        // The skipped file is never created, the wanted ones get their bytes
+        assert!(stored_file(&files, 1).is_err());
+        for file_index in [0, 2, 3] {
+            assert_eq!(
+                stored_file(&files, file_index).unwrap(),
+                file_data(&data, file_index)
+            );
         }
-    };
+        assert!(dir.0.join(".torrent.parts").exists());
+        // Pieces touching it are still whole, served from the part file
+        for piece_index in 0..3 {
+            assert!(files.verify_piece(&info, piece_index).unwrap());
+            let start = piece_index * PIECE_LENGTH as usize;
+            assert_eq!(read(&files, piece_index, 0, 16), &data[start..start + 16]);
+        }
+
+        // Enabling it moves its bytes into place and frees the part file
+        files.priorities.set(1, FilePriority::Normal);
+        files.enable_file(&info, 1).unwrap();
+        assert_eq!(stored_file(&files, 1).unwrap(), file_data(&data, 1));
+        for piece_index in 0..4 {
+            assert!(files.storage.verify_piece(&info, piece_index).unwrap());
+        }
+        assert!(!dir.0.join(".torrent.parts").exists());
+    }
+
+    #[test]
+    fn piece_failing_to_flush_is_not_kept() {
+        let dir = TempDir::new("failed-flush");
+        let data = content();
+        let info = torrent(&data);
+        let files = open(&info, vec![FilePriority::Normal; 4], &dir.0, 32);
+        // Gone behind the engine's back, so writes to it fail
+        files.storage.delete(0).unwrap();
+        assert!(files.write_piece(0, data[..16].to_vec()).is_err());
+        // Nothing is left over for a later flush
+        files.flush().unwrap();
+        assert!(
+            files
+                .read_block(BlockRequest {
+                    index: 0,
+                    begin: 0,
+                    length: 16
+                })
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn skipped_file_without_data_is_removed() {
+        let dir = TempDir::new("remove");
+        let data = content();
+        let info = torrent(&data);
+        let files = open(&info, vec![FilePriority::Normal; 4], &dir.0, 1024);
+        files.write_piece(3, data[48..].to_vec()).unwrap();
 
-    Ok(())
+        files.priorities.set(1, FilePriority::Skip);
+        files.remove_file(1).unwrap();
+        assert!(stored_file(&files, 1).is_err());
+        // Cached pieces were flushed before the file went away
+        assert_eq!(stored_file(&files, 3).unwrap()[6..], data[48..]);
+    }
 }
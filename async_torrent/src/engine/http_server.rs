@@ -0,0 +1,323 @@
+use std::sync::Arc;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    bencode::MetaInfo,
+    engine::{
+        AsyncError,
+        central_manager::PieceCommands,
+        disk_io::DiskIo,
+        files::{FilePriority, TorrentFiles, file_count, file_spans},
+        piece_picker::{Deadline, StreamControl},
+        piece_store::BlockRequest,
+    },
+};
+
+// Largest request head we accept
+const MAX_HEAD_LEN: usize = 8 * 1024;
+// Bytes read from disk and sent per write
+const CHUNK_LEN: u64 = 64 * 1024;
+
+/// What every connection needs to serve files
+#[derive(Clone)]
+struct ServerContext {
+    info: Arc<MetaInfo>,
+    files: TorrentFiles,
+    disk: DiskIo,
+    stream: StreamControl,
+    cmd_tx: mpsc::Sender<PieceCommands>,
+}
+
+/// Serve the torrent's files on localhost so players can stream them while
+/// they download. Reads of missing data wait for the piece to be verified
+/// and move the streaming deadline to the read position.
+pub async fn serve(
+    port: u16,
+    info: Arc<MetaInfo>,
+    files: TorrentFiles,
+    disk: DiskIo,
+    stream: StreamControl,
+    cmd_tx: mpsc::Sender<PieceCommands>,
+) {
+    let listener = match TcpListener::bind(("127.0.0.1", port)).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to start HTTP server on port {port}: {e}");
+            return;
+        }
+    };
+    let ctx = ServerContext {
+        info,
+        files,
+        disk,
+        stream,
+        cmd_tx,
+    };
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(socket, ctx).await {
+                        eprintln!("HTTP error: {e}");
+                    }
+                });
+            }
+            Err(e) => eprintln!("Failed to accept HTTP client: {e}"),
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+async fn read_request(socket: &mut TcpStream) -> Result<Request, AsyncError> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Err("Connection closed before request was complete".into());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_HEAD_LEN {
+            return Err("Request head too large".into());
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().ok_or("Missing request path")?;
+    let path = urlencoding::decode(path)?.into_owned();
+    let range = lines
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, value)| value.trim().to_string());
+    Ok(Request {
+        method,
+        path,
+        range,
+    })
+}
+
+async fn handle_connection(mut socket: TcpStream, ctx: ServerContext) -> Result<(), AsyncError> {
+    let req = read_request(&mut socket).await?;
+    if req.method != "GET" && req.method != "HEAD" {
+        return send_simple(&mut socket, "405 Method Not Allowed", "text/plain", "").await;
+    }
+    let head_only = req.method == "HEAD";
+
+    match req.path.as_str() {
+        "/" => {
+            let body = index_page(&ctx.info);
+            send_simple(&mut socket, "200 OK", "text/html; charset=utf-8", &body).await
+        }
+        "/files.json" => {
+            let body = files_json(&ctx.info);
+            send_simple(&mut socket, "200 OK", "application/json", &body).await
+        }
+        path => {
+            let name = path.trim_start_matches('/');
+            let Some(file_index) =
+                (0..file_count(&ctx.info.info)).find(|i| file_name(&ctx.info, *i) == name)
+            else {
+                return send_simple(&mut socket, "404 Not Found", "text/plain", "Not found").await;
+            };
+            serve_file(
+                &mut socket,
+                &ctx,
+                file_index,
+                req.range.as_deref(),
+                head_only,
+            )
+            .await
+        }
+    }
+}
+
+async fn send_simple(
+    socket: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), AsyncError> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    socket.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Path of a file relative to the torrent, as used in URLs
+fn file_name(info: &MetaInfo, file_index: usize) -> String {
+    match &info.info.mode {
+        crate::bencode::FileMode::MultipleFiles { files } => files[file_index].display_path(),
+        crate::bencode::FileMode::SingleFile { .. } => info.info.name.to_string(),
+    }
+}
+
+fn file_url(info: &MetaInfo, file_index: usize) -> String {
+    let name = file_name(info, file_index);
+    let encoded: Vec<_> = name.split('/').map(urlencoding::encode).collect();
+    format!("/{}", encoded.join("/"))
+}
+
+fn index_page(info: &MetaInfo) -> String {
+    let escape = |s: &str| {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html><head><title>{0}</title></head><body>\n<h1>{0}</h1>\n<ul>\n",
+        escape(&info.info.name.to_string())
+    );
+    for (i, (_, length)) in file_spans(&info.info).iter().enumerate() {
+        page += &format!(
+            "<li><a href=\"{}\">{}</a> ({})</li>\n",
+            escape(&file_url(info, i)),
+            escape(&file_name(info, i)),
+            bytesize::ByteSize(*length)
+        );
+    }
+    page += "</ul>\n</body></html>\n";
+    page
+}
+
+fn files_json(info: &MetaInfo) -> String {
+    let escape = |s: &str| {
+        let mut out = String::new();
+        for c in s.chars() {
+            match c {
+                '"' => out += "\\\"",
+                '\\' => out += "\\\\",
+                c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+                c => out.push(c),
+            }
+        }
+        out
+    };
+    let entries: Vec<String> = file_spans(&info.info)
+        .iter()
+        .enumerate()
+        .map(|(i, (_, length))| {
+            format!(
+                "{{\"index\":{i},\"name\":\"{}\",\"length\":{length},\"url\":\"{}\"}}",
+                escape(&file_name(info, i)),
+                escape(&file_url(info, i))
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// Parse a single `bytes=` range into an inclusive `(start, end)`
+fn parse_range(range: &str, length: u64) -> Option<(u64, u64)> {
+    let spec = range.strip_prefix("bytes=")?;
+    if spec.contains(',') || length == 0 {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (length.saturating_sub(suffix), length - 1)
+        }
+        (start, "") => (start.parse().ok()?, length - 1),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(length - 1),
+        ),
+    };
+    (start <= end && start < length).then_some((start, end))
+}
+
+async fn serve_file(
+    socket: &mut TcpStream,
+    ctx: &ServerContext,
+    file_index: usize,
+    range: Option<&str>,
+    head_only: bool,
+) -> Result<(), AsyncError> {
+    let (file_offset, length) = file_spans(&ctx.info.info)[file_index];
+    let (start, end, status) = match range {
+        Some(range) => match parse_range(range, length) {
+            Some((start, end)) => (start, end, "206 Partial Content"),
+            None => {
+                let response = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{length}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+                socket.write_all(response.as_bytes()).await?;
+                return Ok(());
+            }
+        },
+        None => (0, length.saturating_sub(1), "200 OK"),
+    };
+    let body_len = if length == 0 { 0 } else { end - start + 1 };
+
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/octet-stream\r\nAccept-Ranges: bytes\r\nContent-Length: {body_len}\r\nConnection: close\r\n"
+    );
+    if range.is_some() {
+        response += &format!("Content-Range: bytes {start}-{end}/{length}\r\n");
+    }
+    response += "\r\n";
+    socket.write_all(response.as_bytes()).await?;
+    if head_only || body_len == 0 {
+        return Ok(());
+    }
+
+    // Someone wants to watch this file, so it can't stay skipped. The
+    // central manager creates it, until then its pieces go to the part file.
+    if ctx.files.priorities.get(file_index) == Some(FilePriority::Skip) {
+        ctx.files.priorities.set(file_index, FilePriority::Normal);
+    }
+
+    // Dropped when the response ends or the client goes away
+    let reader = ctx.stream.reader(Deadline {
+        file_index,
+        offset: start,
+    });
+    let piece_length = ctx.info.info.piece_length;
+    let mut pos = start;
+    while pos <= end {
+        let absolute = file_offset + pos;
+        let index = absolute / piece_length;
+        let begin = absolute % piece_length;
+        let len = (end - pos + 1).min(CHUNK_LEN).min(piece_length - begin);
+
+        reader.seek(Deadline {
+            file_index,
+            offset: pos,
+        });
+        let (tx, rx) = oneshot::channel();
+        ctx.cmd_tx
+            .send(PieceCommands::WaitPiece(index as usize, tx))
+            .await?;
+        rx.await?;
+
+        let block = ctx
+            .disk
+            .read(BlockRequest {
+                index: index as u32,
+                begin: begin as u32,
+                length: len as u32,
+            })
+            .await?;
+        socket.write_all(&block).await?;
+        pos += len;
+    }
+    Ok(())
+}
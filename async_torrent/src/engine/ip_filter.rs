@@ -0,0 +1,374 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// eMule access levels below this block the range
+const EMULE_BLOCK_LEVEL: u32 = 128;
+
+/// Blocked address ranges, sorted and merged so a lookup is a binary search
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Read a list in eMule `.dat`, PeerGuardian `.p2p` or CIDR format.
+    /// Formats may be mixed and lines that fit none are skipped.
+    pub fn load(path: &Path) -> std::io::Result<IpFilter> {
+        let bytes = std::fs::read(path)?;
+        let (filter, skipped) = IpFilter::parse(&String::from_utf8_lossy(&bytes));
+        if skipped > 0 {
+            eprintln!(
+                "Skipped {skipped} unreadable lines in IP filter {}",
+                path.display()
+            );
+        }
+        Ok(filter)
+    }
+
+    /// Parse a filter list, returning it with the number of lines skipped
+    pub fn parse(text: &str) -> (IpFilter, usize) {
+        let mut filter = IpFilter::default();
+        let mut skipped = 0;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some(range)) => filter.insert(range),
+                Some(None) => {}
+                None => skipped += 1,
+            }
+        }
+        merge(&mut filter.v4);
+        merge(&mut filter.v6);
+        (filter, skipped)
+    }
+
+    fn insert(&mut self, (start, end): (IpAddr, IpAddr)) {
+        match (start, end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => self.v4.push((start.into(), end.into())),
+            (IpAddr::V6(start), IpAddr::V6(end)) => self.v6.push((start.into(), end.into())),
+            _ => {}
+        }
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => in_ranges(&self.v4, ip.into()),
+            IpAddr::V6(ip) => in_ranges(&self.v6, ip.into()),
+        }
+    }
+
+    /// Ranges left after merging overlapping ones
+    pub fn range_count(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+fn in_ranges<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let after = ranges.partition_point(|(start, _)| *start <= ip);
+    after > 0 && ranges[after - 1].1 >= ip
+}
+
+/// Sort ranges and join the overlapping and touching ones
+fn merge<T: Ord + Copy + From<u8> + std::ops::Add<Output = T>>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if last.1 >= start || last.1 + T::from(1) == start => {
+                last.1 = last.1.max(end)
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+/// The blocked range on a line, `Some(None)` for an eMule range that's
+/// allowed, `None` if the line can't be read
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    // CIDR or a single address
+    if let Some(range) = parse_cidr(line) {
+        return Some(Some(range));
+    }
+    // eMule: `001.002.003.000 - 001.002.003.255 , 000 , description`
+    let mut fields = line.split(',');
+    if let (Some(range), Some(level)) = (fields.next(), fields.next())
+        && let Ok(level) = level.trim().parse::<u32>()
+        && let Some(range) = parse_range(range)
+    {
+        return Some((level < EMULE_BLOCK_LEVEL).then_some(range));
+    }
+    // PeerGuardian: `description:1.2.3.0-1.2.3.255`. Both the description
+    // and IPv6 ranges may contain colons, the range starts after the first
+    // one that leaves a readable range.
+    parse_range(line)
+        .or_else(|| {
+            line.match_indices(':')
+                .find_map(|(i, _)| parse_range(&line[i + 1..]))
+        })
+        .map(Some)
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (parse_ip(start.trim())?, parse_ip(end.trim())?);
+    (start.is_ipv4() == end.is_ipv4() && start <= end).then_some((start, end))
+}
+
+fn parse_cidr(line: &str) -> Option<(IpAddr, IpAddr)> {
+    let (ip, prefix) = match line.split_once('/') {
+        Some((ip, prefix)) => (parse_ip(ip)?, Some(prefix.parse::<u32>().ok()?)),
+        None => (parse_ip(line)?, None),
+    };
+    match ip {
+        IpAddr::V4(ip) => {
+            let prefix = prefix.unwrap_or(32);
+            let mask = u32::MAX.checked_shl(32 - prefix.min(32)).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            (prefix <= 32).then(|| {
+                let end = start | !mask;
+                (Ipv4Addr::from(start).into(), Ipv4Addr::from(end).into())
+            })
+        }
+        IpAddr::V6(ip) => {
+            let prefix = prefix.unwrap_or(128);
+            let mask = u128::MAX.checked_shl(128 - prefix.min(128)).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            (prefix <= 128).then(|| {
+                let end = start | !mask;
+                (Ipv6Addr::from(start).into(), Ipv6Addr::from(end).into())
+            })
+        }
+    }
+}
+
+/// Like `IpAddr::from_str` but taking the zero padded octets eMule lists use
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    if let Ok(ip) = ip.parse() {
+        return Some(ip);
+    }
+    let octets: Vec<u8> = ip
+        .split('.')
+        .map(|o| o.parse().ok())
+        .collect::<Option<_>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(Ipv4Addr::from(octets).into())
+}
+
+/// The filter peers are checked against, shared with the TUI so it can be
+/// reloaded and its counters shown
+#[derive(Debug, Clone, Default)]
+pub struct IpFilterControl {
+    path: Option<PathBuf>,
+    filter: Arc<RwLock<IpFilter>>,
+    /// Error from the last reload, the previous list stays in use
+    error: Arc<RwLock<Option<String>>>,
+    /// Distinct blocked addresses, however often each comes up
+    pub blocked_outgoing: Arc<AtomicU64>,
+    pub blocked_incoming: Arc<AtomicU64>,
+    seen_outgoing: Arc<Mutex<HashSet<IpAddr>>>,
+    seen_incoming: Arc<Mutex<HashSet<IpAddr>>>,
+}
+
+impl IpFilterControl {
+    /// Load the list at `path`, or filter nothing without one
+    pub fn open(path: Option<PathBuf>) -> std::io::Result<IpFilterControl> {
+        let filter = match &path {
+            Some(path) => IpFilter::load(path).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to load IP filter {}: {e}", path.display()),
+                )
+            })?,
+            None => IpFilter::default(),
+        };
+        Ok(IpFilterControl {
+            path,
+            filter: Arc::new(RwLock::new(filter)),
+            ..Default::default()
+        })
+    }
+
+    /// Read the list again, keeping the current one if that fails
+    pub fn reload(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        match IpFilter::load(path) {
+            Ok(filter) => {
+                eprintln!(
+                    "Reloaded IP filter {} with {} ranges",
+                    path.display(),
+                    filter.range_count()
+                );
+                *self.filter.write().unwrap() = filter;
+                *self.error.write().unwrap() = None;
+            }
+            Err(e) => {
+                eprintln!("Failed to reload IP filter {}: {e}", path.display());
+                *self.error.write().unwrap() = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Whether we may connect to the peer, counting it if not
+    pub fn allows_outgoing(&self, addr: &SocketAddr) -> bool {
+        self.allows(addr, &self.blocked_outgoing, &self.seen_outgoing)
+    }
+
+    /// Whether the peer may connect to us, counting it if not
+    pub fn allows_incoming(&self, addr: &SocketAddr) -> bool {
+        self.allows(addr, &self.blocked_incoming, &self.seen_incoming)
+    }
+
+    fn allows(
+        &self,
+        addr: &SocketAddr,
+        counter: &AtomicU64,
+        seen: &Mutex<HashSet<IpAddr>>,
+    ) -> bool {
+        let blocked = self.filter.read().unwrap().is_blocked(addr.ip());
+        // Trackers hand out the same peers on every announce
+        if blocked && seen.lock().unwrap().insert(addr.ip()) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        !blocked
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn ranges(&self) -> usize {
+        self.filter.read().unwrap().range_count()
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.error.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn peerguardian_ipv6_range() {
+        let (filter, skipped) = IpFilter::parse(
+            "Some net:2001:db8::-2001:db8::ffff
+             Label: with colons:2001:db9::1-2001:db9::2
+             2001:dba::-2001:dba::1
+",
+        );
+        assert_eq!(skipped, 0);
+        assert!(filter.is_blocked(ip("2001:db8::1234")));
+        assert!(!filter.is_blocked(ip("2001:db8::1:0")));
+        assert!(filter.is_blocked(ip("2001:db9::2")));
+        assert!(!filter.is_blocked(ip("2001:db9::3")));
+        assert!(filter.is_blocked(ip("2001:dba::1")));
+    }
+
+    #[test]
+    fn blocked_address_counted_once() {
+        let control = IpFilterControl {
+            filter: Arc::new(RwLock::new(
+                IpFilter::parse(
+                    "1.2.3.0/24
+",
+                )
+                .0,
+            )),
+            ..Default::default()
+        };
+        let blocked: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        for port in [6881, 6881, 6882] {
+            assert!(!control.allows_outgoing(&SocketAddr::new(blocked.ip(), port)));
+        }
+        assert!(!control.allows_outgoing(&"1.2.3.5:6881".parse().unwrap()));
+        assert!(control.allows_outgoing(&"1.2.4.4:6881".parse().unwrap()));
+        assert!(!control.allows_incoming(&blocked));
+        assert_eq!(control.blocked_outgoing.load(Ordering::Relaxed), 2);
+        assert_eq!(control.blocked_incoming.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn emule_levels() {
+        let (filter, skipped) = IpFilter::parse(
+            "001.002.003.000 - 001.002.003.255 , 000 , Blocked\n\
+             005.006.007.000 - 005.006.007.255 , 200 , Allowed\n",
+        );
+        assert_eq!(skipped, 0);
+        assert_eq!(filter.range_count(), 1);
+        assert!(filter.is_blocked(ip("1.2.3.4")));
+        assert!(!filter.is_blocked(ip("5.6.7.8")));
+    }
+
+    #[test]
+    fn peerguardian_description_with_separators() {
+        let (filter, skipped) = IpFilter::parse(
+            "Bad Corp, Inc.: range 2, part 1:10.0.0.0-10.0.0.255\n\
+             Lists, 2, 3:10.0.2.0-10.0.2.255\n",
+        );
+        assert_eq!(skipped, 0);
+        assert!(filter.is_blocked(ip("10.0.0.128")));
+        assert!(!filter.is_blocked(ip("10.0.1.0")));
+        // Looks like an eMule level at first
+        assert!(filter.is_blocked(ip("10.0.2.1")));
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let (filter, _) = IpFilter::parse("192.168.0.0/16\n8.8.8.8/32\n2001:db8::/32\n");
+        assert!(filter.is_blocked(ip("192.168.255.1")));
+        assert!(!filter.is_blocked(ip("192.169.0.0")));
+        assert!(filter.is_blocked(ip("8.8.8.8")));
+        assert!(!filter.is_blocked(ip("8.8.8.9")));
+        assert!(filter.is_blocked(ip("2001:db8:ffff::1")));
+        assert!(!filter.is_blocked(ip("2001:db9::")));
+
+        let (filter, _) = IpFilter::parse("0.0.0.0/0\n::/0\n");
+        assert!(filter.is_blocked(ip("0.0.0.0")));
+        assert!(filter.is_blocked(ip("255.255.255.255")));
+        assert!(filter.is_blocked(ip("ffff::1")));
+
+        let (filter, skipped) = IpFilter::parse("1.2.3.4/33\n");
+        assert_eq!((filter.range_count(), skipped), (0, 1));
+    }
+
+    #[test]
+    fn adjacent_and_overlapping_ranges_merge() {
+        let (filter, _) = IpFilter::parse(
+            "1.0.0.0 - 1.0.0.10 , 0 , a\n\
+             1.0.0.11 - 1.0.0.20 , 0 , touching\n\
+             1.0.0.15 - 1.0.0.40 , 0 , overlapping\n\
+             1.0.0.5 - 1.0.0.6 , 0 , inside\n\
+             1.0.0.42 - 1.0.0.50 , 0 , apart\n",
+        );
+        assert_eq!(filter.range_count(), 2);
+        assert!(filter.is_blocked(ip("1.0.0.30")));
+        assert!(!filter.is_blocked(ip("1.0.0.41")));
+        assert!(filter.is_blocked(ip("1.0.0.42")));
+    }
+
+    #[test]
+    fn mapped_ipv6_checked_as_ipv4() {
+        let (filter, _) = IpFilter::parse("1.2.3.0/24\n");
+        assert!(filter.is_blocked(ip("::ffff:1.2.3.4")));
+        assert!(!filter.is_blocked(ip("::ffff:1.2.4.4")));
+    }
+}
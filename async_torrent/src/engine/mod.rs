@@ -1,75 +1,230 @@
 pub mod central_manager;
+pub mod choker;
+pub mod client_id;
+pub mod config;
+pub mod connection_manager;
+pub mod disk_cache;
+pub mod disk_io;
 pub mod events;
+pub mod extension;
 pub mod files;
+pub mod http_server;
+pub mod ip_filter;
+pub mod mse;
 pub mod network;
+pub mod part_file;
+pub mod paths;
 pub mod peers;
 pub mod peers_task;
+pub mod piece_picker;
+pub mod piece_store;
+pub mod rate_limiter;
+pub mod stats;
+pub mod storage;
 pub mod tracker;
+pub mod transport;
+pub mod utp;
 
 use std::error::Error;
 use std::sync::Arc;
-use tokio::{sync::mpsc, task::JoinSet};
+use std::time::Duration;
+use tokio::{sync::mpsc, task::JoinSet, time::timeout};
 
-use crate::engine::central_manager::PieceCommands;
+use crate::engine::connection_manager::{
+    ConnEvent, ConnectionManager, GlobalConnectionLimits, PeerSource, listen, listen_utp,
+};
 use crate::engine::{
-    central_manager::CentralManager, events::UiEvent, peers_task::Peer, tracker::fetch_peers,
+    central_manager::CentralManager,
+    disk_io::{DiskIo, PauseControl},
+    events::UiEvent,
+    ip_filter::IpFilterControl,
+    network::socket::bind_udp,
+    stats::{report_disk_stats, report_transfer_rates},
+    tracker::{AnnounceEvent, AnnounceParams, announce_loop, fetch_peers},
+    transport::TransportPreference,
+    utp::UtpSocket,
 };
 
 use crate::bencode::MetaInfo;
+use crate::engine::config::EngineConfig;
+use crate::engine::files::{FilePriorities, TorrentFiles};
+use crate::engine::piece_picker::StreamControl;
+use crate::engine::rate_limiter::Bandwidth;
 type AsyncError = Box<dyn Error + Send + Sync>;
 
+// How long quitting may wait on trackers to hear we stopped
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Engine state the TUI can change while the torrent is running
+#[derive(Debug, Clone)]
+pub struct EngineHandles {
+    pub bandwidth: Bandwidth,
+    pub file_priorities: FilePriorities,
+    pub stream: StreamControl,
+    pub pause: PauseControl,
+    pub ip_filter: IpFilterControl,
+}
+
+/// A running torrent, stopped with `shutdown` once the TUI quits
+pub struct Engine {
+    tasks: JoinSet<()>,
+    disk: DiskIo,
+    info: Arc<MetaInfo>,
+    announce: AnnounceParams,
+}
+
+impl Engine {
+    /// Stop every task, write out what's still cached and tell the tracker
+    /// we're gone
+    pub async fn shutdown(mut self) {
+        self.tasks.shutdown().await;
+        if let Err(e) = self.disk.flush().await {
+            eprintln!("Failed to flush cached pieces: {e}");
+        }
+        let stopped = fetch_peers(&self.info, &self.announce, AnnounceEvent::Stopped);
+        match timeout(STOPPED_TIMEOUT, stopped).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Stopped announce failed: {e}"),
+            Err(_) => eprintln!("Stopped announce timed out"),
+        }
+    }
+}
+
 pub async fn spawn_engine(
     info: Arc<MetaInfo>,
+    config: EngineConfig,
+    handles: EngineHandles,
+    limits: GlobalConnectionLimits,
     ui_tx: mpsc::Sender<UiEvent>,
-) -> Result<(), AsyncError> {
-    files::initialize_files(&info.info)?;
-
-    let peers = fetch_peers(&info).await?;
-    let piece_count = info.info.pieces.len().div_ceil(20);
+) -> Result<Engine, AsyncError> {
+    let storage = storage::open_storage(
+        config.storage,
+        config.allocation,
+        &config.output_dir,
+        &info.info,
+    )?;
+    let torrent_files = TorrentFiles::new(
+        &info.info,
+        handles.file_priorities.clone(),
+        storage,
+        &config.output_dir,
+        config.cache_size,
+    );
+    torrent_files.initialize()?;
+    let disk = DiskIo::new(
+        info.clone(),
+        torrent_files.clone(),
+        config.disk_threads,
+        config.disk_write_queue,
+        handles.pause.clone(),
+    );
 
     let (cmd_tx, cmd_rx) = mpsc::channel(256);
+    let (conn_tx, conn_rx) = mpsc::channel(256);
 
-    let central = CentralManager::new(piece_count, ui_tx.clone());
+    let server = http_server::serve(
+        config.http_port,
+        info.clone(),
+        torrent_files.clone(),
+        disk.clone(),
+        handles.stream.clone(),
+        cmd_tx.clone(),
+    );
+    let central = CentralManager::new(
+        info.clone(),
+        &config,
+        torrent_files.clone(),
+        disk.clone(),
+        handles.stream.clone(),
+        handles.pause.clone(),
+        ui_tx.clone(),
+    );
+    let counters = handles.bandwidth.counters.clone();
+
+    let announce = AnnounceParams {
+        port: config.listen_port,
+        key: rand::random(),
+        proxy: config.proxy.clone(),
+        outgoing: config.outgoing.clone(),
+        counters: counters.clone(),
+        left: central.left(),
+    };
+    let peers = fetch_peers(&info, &announce, AnnounceEvent::Started).await?;
+
+    // uTP can't be relayed by the proxy, and sending it around would leak
+    // our address
+    let utp = if config.proxy.is_some() {
+        if config.transport == TransportPreference::Utp {
+            eprintln!("uTP is disabled behind a proxy, no peers can be dialed");
+        }
+        None
+    } else if config.transport.uses_utp() {
+        match bind_udp(config.listen_port, config.outgoing.as_ref()).and_then(UtpSocket::new) {
+            Ok(utp) => Some(utp),
+            Err(e) => {
+                eprintln!(
+                    "Failed to open uTP socket on port {}: {e}",
+                    config.listen_port
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let connections = ConnectionManager::new(
+        info.clone(),
+        &config,
+        limits,
+        cmd_tx,
+        ui_tx.clone(),
+        handles.bandwidth,
+        disk.clone(),
+        conn_tx.clone(),
+        utp.clone(),
+        handles.ip_filter.clone(),
+    )?;
 
     let mut join_set = JoinSet::new();
 
     join_set.spawn(async move {
         central.run(cmd_rx).await;
     });
-
-    for peer_addr in peers.peers.0 {
-        let torrent = info.clone();
-        let ui_tx = ui_tx.clone();
-        let cmd_tx = cmd_tx.clone();
-
-        join_set.spawn(async move {
-            if let Ok(mut peer) = Peer::new(peer_addr, torrent, cmd_tx, ui_tx).await {
-                match peer.start().await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("Error {e}");
-                    }
-                }
-                let _ = peer
-                    .sender
-                    .send(PieceCommands::PeerDead(peer.peer_id))
-                    .await;
-                let _ = peer
-                    .ui_tx
-                    .send(UiEvent::PeerDisconnected(
-                        String::from_utf8_lossy(&peer.peer_id).to_string(),
-                    ))
-                    .await.ok();
-            }
-        });
+    join_set.spawn(async move {
+        connections.run(conn_rx).await;
+    });
+    if config.http_port != 0 {
+        join_set.spawn(server);
     }
+    join_set.spawn(report_transfer_rates(counters, ui_tx.clone()));
+    join_set.spawn(report_disk_stats(
+        disk.stats.clone(),
+        torrent_files.cache_stats(),
+        ui_tx.clone(),
+    ));
+    join_set.spawn(listen(
+        config.listen_port,
+        config.outgoing.clone(),
+        conn_tx.clone(),
+    ));
+    if let Some(utp) = utp {
+        join_set.spawn(listen_utp(utp, conn_tx.clone()));
+    }
+    join_set.spawn(announce_loop(
+        info.clone(),
+        announce.clone(),
+        peers.interval,
+        conn_tx.clone(),
+    ));
 
-    // Detach engine tasks (UI controls lifetime)
-    tokio::spawn(async move {
-        while let Some(res) = join_set.join_next().await {
-            let _ = res;
-        }
-    });
+    conn_tx
+        .send(ConnEvent::AddPeers(peers.all_peers(), PeerSource::Tracker))
+        .await?;
 
-    Ok(())
+    Ok(Engine {
+        tasks: join_set,
+        disk,
+        info,
+        announce,
+    })
 }
@@ -0,0 +1,536 @@
+use std::{
+    io::{Error, ErrorKind},
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll, ready},
+};
+
+use num_bigint::BigUint;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    engine::transport::{BoxedConnection, PeerStream, Prefixed},
+    utils::sha1_hash,
+};
+
+// 768 bit safe prime and generator of the key exchange
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LEN: usize = 96;
+// Longest random padding either side may send
+const MAX_PAD: usize = 512;
+// Verification constant, sent encrypted so each side can find the other's stream
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+// Start of a plaintext BitTorrent handshake
+const PROTOCOL_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+// Keystream bytes thrown away before use, as RC4's first ones are weak
+const RC4_DISCARD: usize = 1024;
+
+/// Whether connections get wrapped in message stream encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Plaintext only, encrypted peers are turned away
+    Disable,
+    /// Try encryption first and fall back to plaintext
+    Prefer,
+    /// Only RC4 encrypted connections
+    Require,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(EncryptionPolicy::Disable),
+            "prefer" => Ok(EncryptionPolicy::Prefer),
+            "require" => Ok(EncryptionPolicy::Require),
+            _ => Err(format!("Unknown encryption policy {s}")),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Rc4 {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+/// Stream cipher for one direction, keyed from the shared secret
+fn cipher(name: &[u8], secret: &[u8], info_hash: &[u8; 20]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[name, secret, info_hash]));
+    rc4.apply(&mut [0u8; RC4_DISCARD]);
+    rc4
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    sha1_hash(&parts.concat())
+}
+
+/// Diffie-Hellman key pair
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> KeyPair {
+        let prime = prime();
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime);
+        KeyPair {
+            private,
+            public: to_key(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote: &[u8]) -> [u8; KEY_LEN] {
+        let remote = BigUint::from_bytes_be(remote);
+        to_key(&remote.modpow(&self.private, &prime()))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).expect("valid prime")
+}
+
+/// Big endian, left padded to the key length
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::rng();
+    let len = rng.random_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.random()).collect()
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Read byte by byte until the stream ends with `pattern`, giving up after
+/// the largest padding that may come before it
+async fn sync_on(socket: &mut BoxedConnection, pattern: &[u8]) -> std::io::Result<()> {
+    let mut window = Vec::with_capacity(MAX_PAD + pattern.len());
+    while !window.ends_with(pattern) {
+        if window.len() == MAX_PAD + pattern.len() {
+            return Err(protocol_error("encryption handshake never synchronized"));
+        }
+        window.push(socket.read_u8().await?);
+    }
+    Ok(())
+}
+
+async fn read_decrypted(
+    socket: &mut BoxedConnection,
+    rc4: &mut Rc4,
+    len: usize,
+) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    socket.read_exact(&mut buf).await?;
+    rc4.apply(&mut buf);
+    Ok(buf)
+}
+
+/// Set up the connection we opened to a peer. The caller retries in
+/// plaintext if this fails and encryption isn't required.
+pub async fn connect(
+    mut socket: BoxedConnection,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> std::io::Result<PeerStream> {
+    if policy == EncryptionPolicy::Disable {
+        return Ok(PeerStream::plain(socket));
+    }
+    let keys = KeyPair::generate();
+    socket
+        .write_all(&[&keys.public[..], &random_pad()].concat())
+        .await?;
+    let mut remote = [0u8; KEY_LEN];
+    socket.read_exact(&mut remote).await?;
+    let secret = keys.shared_secret(&remote);
+
+    let mut encrypt = cipher(b"keyA", &secret, info_hash);
+    let mut decrypt = cipher(b"keyB", &secret, info_hash);
+    let provide = match policy {
+        EncryptionPolicy::Require => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+    let pad = random_pad();
+    let mut offer = [
+        &VC[..],
+        &provide.to_be_bytes(),
+        &(pad.len() as u16).to_be_bytes(),
+        &pad,
+        // No initial payload, the BitTorrent handshake follows on its own
+        &0u16.to_be_bytes(),
+    ]
+    .concat();
+    encrypt.apply(&mut offer);
+    let req1 = hash(&[b"req1", &secret]);
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    let skey: Vec<u8> = req2.iter().zip(req3).map(|(a, b)| a ^ b).collect();
+    socket
+        .write_all(&[&req1[..], &skey, &offer].concat())
+        .await?;
+
+    // The peer's padding comes first, its encrypted VC marks where it ends
+    let mut vc = VC;
+    decrypt.clone().apply(&mut vc);
+    sync_on(&mut socket, &vc).await?;
+    decrypt.apply(&mut [0u8; VC.len()]);
+    let answer = read_decrypted(&mut socket, &mut decrypt, 6).await?;
+    let select = u32::from_be_bytes(answer[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(answer[4..].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return Err(protocol_error("encryption padding too long"));
+    }
+    read_decrypted(&mut socket, &mut decrypt, pad_len).await?;
+
+    let (reader, writer) = tokio::io::split(socket);
+    match select {
+        CRYPTO_RC4 => Ok(PeerStream::new(
+            Rc4Reader::new(reader, decrypt),
+            Rc4Writer::new(writer, encrypt),
+        )),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => Ok(PeerStream::new(reader, writer)),
+        _ => Err(protocol_error("peer selected a method we didn't offer")),
+    }
+}
+
+/// Set up a connection a peer opened to us, which may start with either a
+/// plaintext handshake or a key exchange
+pub async fn accept(
+    mut socket: BoxedConnection,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> std::io::Result<PeerStream> {
+    let mut head = [0u8; PROTOCOL_HEADER.len()];
+    socket.read_exact(&mut head).await?;
+    if head == *PROTOCOL_HEADER {
+        if policy == EncryptionPolicy::Require {
+            return Err(protocol_error(
+                "plaintext peer refused, encryption is required",
+            ));
+        }
+        let (reader, writer) = tokio::io::split(socket);
+        return Ok(PeerStream::new(
+            Prefixed::new(head.to_vec(), reader),
+            writer,
+        ));
+    }
+    if policy == EncryptionPolicy::Disable {
+        return Err(protocol_error(
+            "encrypted peer refused, encryption is disabled",
+        ));
+    }
+
+    let mut remote = [0u8; KEY_LEN];
+    remote[..head.len()].copy_from_slice(&head);
+    socket.read_exact(&mut remote[head.len()..]).await?;
+    let keys = KeyPair::generate();
+    socket
+        .write_all(&[&keys.public[..], &random_pad()].concat())
+        .await?;
+    let secret = keys.shared_secret(&remote);
+
+    sync_on(&mut socket, &hash(&[b"req1", &secret])).await?;
+    let mut skey = [0u8; 20];
+    socket.read_exact(&mut skey).await?;
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    if skey.iter().zip(req3).map(|(a, b)| a ^ b).ne(req2) {
+        return Err(protocol_error("peer asked for a torrent we don't have"));
+    }
+
+    let mut decrypt = cipher(b"keyA", &secret, info_hash);
+    let mut encrypt = cipher(b"keyB", &secret, info_hash);
+    let offer = read_decrypted(&mut socket, &mut decrypt, 14).await?;
+    if offer[..8] != VC {
+        return Err(protocol_error("bad verification constant"));
+    }
+    let provide = u32::from_be_bytes(offer[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(offer[12..].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return Err(protocol_error("encryption padding too long"));
+    }
+    read_decrypted(&mut socket, &mut decrypt, pad_len).await?;
+    let ia_len = u16::from_be_bytes(
+        read_decrypted(&mut socket, &mut decrypt, 2)
+            .await?
+            .try_into()
+            .unwrap(),
+    );
+    // Start of the peer's BitTorrent handshake, if it sent one along
+    let initial = read_decrypted(&mut socket, &mut decrypt, ia_len as usize).await?;
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy == EncryptionPolicy::Prefer {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(protocol_error("no encryption method in common"));
+    };
+    let mut answer = [&VC[..], &select.to_be_bytes(), &0u16.to_be_bytes()].concat();
+    encrypt.apply(&mut answer);
+    socket.write_all(&answer).await?;
+
+    let (reader, writer) = tokio::io::split(socket);
+    if select == CRYPTO_RC4 {
+        Ok(PeerStream::new(
+            Prefixed::new(initial, Rc4Reader::new(reader, decrypt)),
+            Rc4Writer::new(writer, encrypt),
+        ))
+    } else {
+        Ok(PeerStream::new(Prefixed::new(initial, reader), writer))
+    }
+}
+
+/// Decrypts everything read through it
+struct Rc4Reader<R> {
+    inner: R,
+    rc4: Rc4,
+}
+
+impl<R> Rc4Reader<R> {
+    fn new(inner: R, rc4: Rc4) -> Rc4Reader<R> {
+        Rc4Reader { inner, rc4 }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Rc4Reader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.rc4.apply(&mut buf.filled_mut()[start..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Encrypts everything written through it. Data is encrypted as soon as it
+/// is accepted, so bytes the socket didn't take yet wait in `pending`.
+struct Rc4Writer<W> {
+    inner: W,
+    rc4: Rc4,
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<W> Rc4Writer<W> {
+    fn new(inner: W, rc4: Rc4) -> Rc4Writer<W> {
+        Rc4Writer {
+            inner,
+            rc4,
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Rc4Writer<W> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Rc4Writer<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+        this.pending.extend_from_slice(buf);
+        this.rc4.apply(&mut this.pending);
+        // Whatever doesn't go out now goes out on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use EncryptionPolicy::{Disable, Prefer, Require};
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    fn pipe() -> (BoxedConnection, BoxedConnection) {
+        let (a, b) = duplex(64 * 1024);
+        (Box::new(a), Box::new(b))
+    }
+
+    /// Run both sides of the handshake, then send a BitTorrent header one
+    /// way and a reply the other
+    async fn exchange(
+        ours: EncryptionPolicy,
+        theirs: EncryptionPolicy,
+    ) -> (std::io::Result<()>, std::io::Result<()>) {
+        let (a, b) = pipe();
+        let hello = [&PROTOCOL_HEADER[..], b"hello"].concat();
+        let client = async {
+            let mut stream = connect(a, &INFO_HASH, ours).await?;
+            stream.writer.write_all(&hello).await?;
+            stream.writer.flush().await?;
+            let mut reply = [0u8; 5];
+            stream.reader.read_exact(&mut reply).await?;
+            assert_eq!(&reply, b"world");
+            Ok(())
+        };
+        let server = async {
+            let mut stream = accept(b, &INFO_HASH, theirs).await?;
+            let mut buf = vec![0u8; hello.len()];
+            stream.reader.read_exact(&mut buf).await?;
+            assert_eq!(buf, hello);
+            stream.writer.write_all(b"world").await?;
+            stream.writer.flush().await?;
+            Ok(())
+        };
+        tokio::join!(client, server)
+    }
+
+    #[tokio::test]
+    async fn compatible_policies_connect() {
+        for (ours, theirs) in [
+            (Disable, Disable),
+            (Disable, Prefer),
+            (Prefer, Prefer),
+            (Prefer, Require),
+            (Require, Prefer),
+            (Require, Require),
+        ] {
+            let (client, server) = exchange(ours, theirs).await;
+            assert!(client.is_ok(), "{ours:?} to {theirs:?}: {client:?}");
+            assert!(server.is_ok(), "{ours:?} to {theirs:?}: {server:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn require_refuses_plaintext() {
+        let (_, server) = exchange(Disable, Require).await;
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn disable_refuses_encryption() {
+        for ours in [Prefer, Require] {
+            let (client, server) = exchange(ours, Disable).await;
+            assert!(client.is_err());
+            assert!(server.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn prefer_falls_back_to_plaintext() {
+        let (client, server) = exchange(Prefer, Disable).await;
+        assert!(client.is_err() && server.is_err());
+        // Like the connection manager, dial again without the key exchange
+        let (a, b) = pipe();
+        let mut stream = PeerStream::plain(a);
+        let (sent, accepted) = tokio::join!(
+            stream.writer.write_all(PROTOCOL_HEADER),
+            accept(b, &INFO_HASH, Disable)
+        );
+        sent.unwrap();
+        let mut head = [0u8; PROTOCOL_HEADER.len()];
+        accepted
+            .unwrap()
+            .reader
+            .read_exact(&mut head)
+            .await
+            .unwrap();
+        assert_eq!(head, *PROTOCOL_HEADER);
+    }
+
+    #[test]
+    fn rc4_test_vector() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_streams() {
+        let ours = KeyPair::generate();
+        let theirs = KeyPair::generate();
+        let secret = ours.shared_secret(&theirs.public);
+        assert_eq!(secret, theirs.shared_secret(&ours.public));
+
+        let keystream = |mut rc4: Rc4| {
+            let mut buf = [0u8; 64];
+            rc4.apply(&mut buf);
+            buf
+        };
+        // The 1024 discarded bytes are the same on both ends
+        let mut manual = Rc4::new(&hash(&[b"keyA", &secret, &INFO_HASH]));
+        manual.apply(&mut [0u8; RC4_DISCARD]);
+        let a = keystream(cipher(b"keyA", &secret, &INFO_HASH));
+        assert_eq!(a, keystream(manual));
+        assert_eq!(a, keystream(cipher(b"keyA", &secret, &INFO_HASH)));
+        assert_ne!(a, keystream(cipher(b"keyB", &secret, &INFO_HASH)));
+    }
+}
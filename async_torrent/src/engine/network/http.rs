@@ -1,8 +1,27 @@
-use std::error::Error;
 use reqwest;
+use std::error::Error;
+
+use crate::engine::network::{proxy::ProxyConfig, socket::OutgoingInterface};
 
-pub async fn get_peers(url:String) -> Result<Vec<u8>, Box<dyn Error + Send+Sync>> {
-    let data = reqwest::get(url).await?;
+pub async fn get_peers(
+    url: String,
+    proxy: Option<&ProxyConfig>,
+    outgoing: Option<&OutgoingInterface>,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut client = reqwest::Client::builder();
+    match outgoing {
+        #[cfg(target_os = "linux")]
+        Some(OutgoingInterface::Name(name)) => client = client.interface(name),
+        Some(OutgoingInterface::Address(ip)) => client = client.local_address(*ip),
+        // Binding the interface's address is the best we can do elsewhere
+        #[cfg(not(target_os = "linux"))]
+        Some(outgoing) => client = client.local_address(outgoing.local_ip(false)?),
+        None => {}
+    }
+    if let Some(proxy) = proxy {
+        client = client.proxy(proxy.reqwest_proxy()?);
+    }
+    let data = client.build()?.get(url).send().await?;
     let str_data = data.bytes().await?;
 
     Ok(str_data.to_vec())
@@ -1,2 +1,4 @@
 pub mod http;
+pub mod proxy;
+pub mod socket;
 pub mod udp;
@@ -0,0 +1,316 @@
+use std::{
+    ffi::CStr,
+    fmt,
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
+    sync::watch,
+    time::interval,
+};
+
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interface or local address every torrent socket is pinned to, so
+/// traffic can be kept on a VPN tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutgoingInterface {
+    Name(String),
+    Address(IpAddr),
+}
+
+impl FromStr for OutgoingInterface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = s.parse() {
+            return Ok(OutgoingInterface::Address(ip));
+        }
+        // IFNAMSIZ including the NUL
+        if s.is_empty() || s.len() > 15 || s.contains(['/', ' ']) {
+            return Err(format!("Invalid interface name {s}"));
+        }
+        Ok(OutgoingInterface::Name(s.to_string()))
+    }
+}
+
+impl fmt::Display for OutgoingInterface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutgoingInterface::Name(name) => write!(f, "interface {name}"),
+            OutgoingInterface::Address(ip) => write!(f, "address {ip}"),
+        }
+    }
+}
+
+impl OutgoingInterface {
+    /// Whether the interface exists and is up, or the address is still
+    /// assigned to an interface that is
+    pub fn is_up(&self) -> bool {
+        let Ok(addrs) = interface_addrs() else {
+            return false;
+        };
+        addrs.iter().filter(|a| a.up).any(|a| match self {
+            OutgoingInterface::Name(name) => &a.name == name,
+            OutgoingInterface::Address(ip) => a.ip == Some(*ip),
+        })
+    }
+
+    /// Local address of the given family traffic leaves from. Global
+    /// addresses are preferred for an interface.
+    pub fn local_ip(&self, ipv6: bool) -> std::io::Result<IpAddr> {
+        let not_available = || {
+            Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("{self} has no IPv{} address", if ipv6 { 6 } else { 4 }),
+            )
+        };
+        match self {
+            OutgoingInterface::Address(ip) if ip.is_ipv6() == ipv6 => Ok(*ip),
+            OutgoingInterface::Address(_) => Err(not_available()),
+            OutgoingInterface::Name(name) => {
+                let mut ips: Vec<IpAddr> = interface_addrs()?
+                    .into_iter()
+                    .filter(|a| a.up && &a.name == name)
+                    .filter_map(|a| a.ip)
+                    .filter(|ip| ip.is_ipv6() == ipv6)
+                    .collect();
+                ips.sort_by_key(|ip| match ip {
+                    IpAddr::V6(ip) => ip.is_unicast_link_local() || ip.is_unique_local(),
+                    IpAddr::V4(_) => false,
+                });
+                ips.first().copied().ok_or_else(not_available)
+            }
+        }
+    }
+
+    /// Restrict the socket to the interface and bind it to `port`. Fails
+    /// when the interface or address is gone instead of falling back to the
+    /// default route.
+    fn bind(&self, socket: &Socket, domain: Domain, port: u16) -> std::io::Result<()> {
+        let ip = match self {
+            OutgoingInterface::Address(IpAddr::V4(ip)) if domain == Domain::IPV6 => {
+                IpAddr::V6(ip.to_ipv6_mapped())
+            }
+            OutgoingInterface::Address(ip) if ip.is_ipv6() == (domain == Domain::IPV6) => *ip,
+            OutgoingInterface::Address(_) => {
+                return Err(Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    format!("{self} is of the wrong address family"),
+                ));
+            }
+            OutgoingInterface::Name(name) => bind_device(socket, name, domain)?,
+        };
+        socket.bind(&SockAddr::from(SocketAddr::new(ip, port)))
+    }
+}
+
+/// Tie the socket to the interface, returning the address to bind to
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, name: &str, domain: Domain) -> std::io::Result<IpAddr> {
+    socket.bind_device(Some(name.as_bytes()))?;
+    Ok(match domain {
+        Domain::IPV6 => Ipv6Addr::UNSPECIFIED.into(),
+        _ => Ipv4Addr::UNSPECIFIED.into(),
+    })
+}
+
+/// Tie the socket to the interface, returning the address to bind to.
+/// Without SO_BINDTODEVICE, binding the interface's address is the best we
+/// can do.
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, name: &str, domain: Domain) -> std::io::Result<IpAddr> {
+    OutgoingInterface::Name(name.to_string()).local_ip(domain == Domain::IPV6)
+}
+
+struct InterfaceAddr {
+    name: String,
+    up: bool,
+    ip: Option<IpAddr>,
+}
+
+/// Every address of every interface on the host
+fn interface_addrs() -> std::io::Result<Vec<InterfaceAddr>> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // Safety: on success `list` points to a list we free below
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut addrs = Vec::new();
+    let mut node = list;
+    while !node.is_null() {
+        // Safety: every node and what it points to lives until freeifaddrs
+        let ifa = unsafe { &*node };
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        addrs.push(InterfaceAddr {
+            name: name.to_string_lossy().into_owned(),
+            up: ifa.ifa_flags & libc::IFF_UP as libc::c_uint != 0,
+            ip: unsafe { sockaddr_ip(ifa.ifa_addr) },
+        });
+        node = ifa.ifa_next;
+    }
+    // Safety: `list` came from getifaddrs and nothing borrows from it anymore
+    unsafe { libc::freeifaddrs(list) };
+    Ok(addrs)
+}
+
+/// Safety: `addr` must be null or point to a sockaddr of its family's size
+unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    // Safety: guaranteed by the caller
+    unsafe {
+        match (*addr).sa_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = &*(addr as *const libc::sockaddr_in);
+                Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into())
+            }
+            libc::AF_INET6 => {
+                let addr = &*(addr as *const libc::sockaddr_in6);
+                Some(Ipv6Addr::from(addr.sin6_addr.s6_addr).into())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Track whether the outgoing interface is up. Without one the link is
+/// always up.
+pub fn watch_link(outgoing: Option<OutgoingInterface>) -> watch::Receiver<bool> {
+    let Some(outgoing) = outgoing else {
+        return watch::channel(true).1;
+    };
+    let (tx, rx) = watch::channel(outgoing.is_up());
+    if !*rx.borrow() {
+        eprintln!("{outgoing} is down, not connecting to peers until it is back");
+    }
+    tokio::spawn(async move {
+        let mut check = interval(LINK_CHECK_INTERVAL);
+        while !tx.is_closed() {
+            check.tick().await;
+            let up = outgoing.is_up();
+            if tx.send_if_modified(|was_up| std::mem::replace(was_up, up) != up) {
+                if up {
+                    eprintln!("{outgoing} is back, resuming connections");
+                } else {
+                    eprintln!("{outgoing} went down, stopping connections");
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Resolve once the link goes down. Never resolves for a link that can't.
+pub async fn link_down(link: &mut watch::Receiver<bool>) {
+    if link.wait_for(|up| !*up).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Connect to a peer or tracker through the outgoing interface, if any
+pub async fn connect_tcp(
+    addr: SocketAddr,
+    outgoing: Option<&OutgoingInterface>,
+) -> std::io::Result<TcpStream> {
+    let Some(outgoing) = outgoing else {
+        return TcpStream::connect(addr).await;
+    };
+    let domain = Domain::for_address(addr);
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    outgoing.bind(&socket, domain, 0)?;
+    socket.set_nonblocking(true)?;
+    TcpSocket::from_std_stream(socket.into())
+        .connect(addr)
+        .await
+}
+
+/// UDP socket on an ephemeral port for talking to `remote`, bound to the
+/// outgoing interface if any
+pub fn bind_udp_for(
+    remote: SocketAddr,
+    outgoing: Option<&OutgoingInterface>,
+) -> std::io::Result<UdpSocket> {
+    let domain = Domain::for_address(remote);
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    match outgoing {
+        Some(outgoing) => outgoing.bind(&socket, domain, 0)?,
+        None => {
+            let any: SocketAddr = match domain {
+                Domain::IPV6 => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                _ => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            };
+            socket.bind(&SockAddr::from(any))?;
+        }
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Bind a listening socket taking both IPv6 and IPv4 connections, or only
+/// IPv4 on hosts without IPv6
+pub fn bind_tcp(port: u16, outgoing: Option<&OutgoingInterface>) -> std::io::Result<TcpListener> {
+    let socket = dual_stack(Type::STREAM, Protocol::TCP, port, outgoing)?;
+    socket.listen(128)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind a UDP socket reachable over both IPv6 and IPv4, or only IPv4 on
+/// hosts without IPv6
+pub fn bind_udp(port: u16, outgoing: Option<&OutgoingInterface>) -> std::io::Result<UdpSocket> {
+    let socket = dual_stack(Type::DGRAM, Protocol::UDP, port, outgoing)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn dual_stack(
+    kind: Type,
+    protocol: Protocol,
+    port: u16,
+    outgoing: Option<&OutgoingInterface>,
+) -> std::io::Result<Socket> {
+    let bind = |domain, addr: SocketAddr| -> std::io::Result<Socket> {
+        let socket = Socket::new(domain, kind, Some(protocol))?;
+        if domain == Domain::IPV6 {
+            socket.set_only_v6(false)?;
+        }
+        // Lets a restarted client listen again right away, for UDP it would
+        // let two sockets share the port instead
+        if kind == Type::STREAM {
+            socket.set_reuse_address(true)?;
+        }
+        match outgoing {
+            Some(outgoing) => outgoing.bind(&socket, domain, port)?,
+            None => socket.bind(&SockAddr::from(addr))?,
+        }
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    };
+    bind(
+        Domain::IPV6,
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+    )
+    .or_else(|_| bind(Domain::IPV4, SocketAddr::from(([0, 0, 0, 0], port))))
+}
+
+/// IPv4 peers reach dual-stack sockets as IPv4-mapped IPv6 addresses. Turn
+/// those back into plain IPv4 so the same peer has one address everywhere.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Address to send to from a socket of the given family, mapping IPv4
+/// addresses into IPv6 for dual-stack sockets
+pub fn for_socket(addr: SocketAddr, local: SocketAddr) -> SocketAddr {
+    match (addr, local) {
+        (SocketAddr::V4(v4), SocketAddr::V6(_)) => {
+            SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port()))
+        }
+        _ => addr,
+    }
+}
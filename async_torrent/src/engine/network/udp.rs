@@ -1,29 +1,148 @@
-use std::{
-    error::Error,
-    net::{Ipv4Addr, SocketAddrV4},
+use std::{error::Error, net::SocketAddr};
+
+use tokio::net::{UdpSocket, lookup_host};
+use url::Url;
+
+use crate::engine::{
+    network::{
+        proxy::{ProxyConfig, SocksUdp, TargetAddr},
+        socket::{OutgoingInterface, bind_udp_for},
+    },
+    peers::{Peers, parse_compact},
 };
 
-use tokio::net::UdpSocket;
+const URL_DATA_OPTION: u8 = 2;
+
+/// Socket a UDP tracker is reached over, directly or relayed by a SOCKS5 proxy
+enum TrackerSocket {
+    Direct(UdpSocket),
+    Proxied(SocksUdp, TargetAddr),
+}
+
+impl TrackerSocket {
+    async fn send(&self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            TrackerSocket::Direct(socket) => socket.send(data).await.map(|_| ()),
+            TrackerSocket::Proxied(socket, target) => socket.send_to(data, target).await,
+        }
+    }
 
-use crate::engine::peers::Peers;
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            TrackerSocket::Direct(socket) => socket.recv(buf).await,
+            TrackerSocket::Proxied(socket, _) => socket.recv(buf).await,
+        }
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
 pub async fn get_peers(
     tracker_url: String,
+    proxy: Option<&ProxyConfig>,
+    outgoing: Option<&OutgoingInterface>,
     info_hash: &[u8; 20],
     peer_id: &[u8; 20],
     left: u64,
     port: u16,
     downloaded: u64,
     uploaded: u64,
+    event: u32,
     num_want: u32,
-) -> Result<Peers, Box<dyn Error + Send+Sync>> {
-    let tracker_addr = tracker_url
-        .trim_start_matches("udp://")
-        .trim_end_matches("/announce");
+    key: u32,
+) -> Result<Peers, Box<dyn Error + Send + Sync>> {
+    let url = Url::parse(&tracker_url)?;
+    let host = url.host_str().ok_or("UDP tracker URL has no host")?;
+    let tracker_addr = format!(
+        "{host}:{}",
+        url.port().ok_or("UDP tracker URL has no port")?
+    );
+    // Private trackers put the passkey in the path, it goes along as is
+    let url_data = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+
+    if let Some(proxy) = proxy {
+        // The proxy resolves the tracker, so we can't tell which family it
+        // answers over. Assume IPv4 unless given an IPv6 address.
+        let target: TargetAddr = tracker_addr.parse()?;
+        let ip_len = match target {
+            TargetAddr::Ip(SocketAddr::V6(_)) => 16,
+            _ => 4,
+        };
+        let socket = TrackerSocket::Proxied(proxy.udp_associate(outgoing).await?, target);
+        let peers = announce(
+            &socket, ip_len, &url_data, info_hash, peer_id, left, port, downloaded, uploaded,
+            event, num_want, key,
+        )
+        .await?;
+        return Ok(Peers(peers));
+    }
 
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect(tracker_addr).await?;
+    // Trackers only hand out peers of the family they were reached over,
+    // so a dual-stack tracker is asked once over each
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in lookup_host(&tracker_addr).await? {
+        if !addrs.iter().any(|a| a.is_ipv4() == addr.is_ipv4()) {
+            addrs.push(addr);
+        }
+    }
+    let mut peers = Vec::new();
+    let mut last_err = None;
+    for addr in addrs {
+        let socket = match bind_udp_for(addr, outgoing) {
+            Ok(socket) => socket,
+            Err(e) => {
+                last_err = Some(e.into());
+                continue;
+            }
+        };
+        if let Err(e) = socket.connect(addr).await {
+            last_err = Some(e.into());
+            continue;
+        }
+        let ip_len = if addr.is_ipv4() { 4 } else { 16 };
+        match announce(
+            &TrackerSocket::Direct(socket),
+            ip_len,
+            &url_data,
+            info_hash,
+            peer_id,
+            left,
+            port,
+            downloaded,
+            uploaded,
+            event,
+            num_want,
+            key,
+        )
+        .await
+        {
+            Ok(found) => peers.extend(found),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    match last_err {
+        Some(e) if peers.is_empty() => Err(e),
+        _ => Ok(Peers(peers)),
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
+async fn announce(
+    socket: &TrackerSocket,
+    ip_len: usize,
+    url_data: &str,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    left: u64,
+    port: u16,
+    downloaded: u64,
+    uploaded: u64,
+    event: u32,
+    num_want: u32,
+    key: u32,
+) -> Result<Vec<SocketAddr>, Box<dyn Error + Send + Sync>> {
     let protocol_id: i64 = 0x41727101980;
     let connect_action: i32 = 0;
     let connect_tid: i32 = rand::random();
@@ -49,11 +168,9 @@ pub async fn get_peers(
         return Err("Wrong action".into());
     }
 
-    let event: u32 = 2;
     let announce_action: u32 = 1;
     let announce_tid: u32 = rand::random();
     let ip: u32 = 0;
-    let key: u32 = rand::random();
 
     let mut announce = Vec::new();
     announce.extend_from_slice(&conn_id.to_be_bytes());
@@ -69,6 +186,12 @@ pub async fn get_peers(
     announce.extend_from_slice(&key.to_be_bytes());
     announce.extend_from_slice(&num_want.to_be_bytes());
     announce.extend_from_slice(&port.to_be_bytes());
+    // BEP 41 URL data option, in pieces of at most 255 bytes
+    for chunk in url_data.as_bytes().chunks(255) {
+        announce.push(URL_DATA_OPTION);
+        announce.push(chunk.len() as u8);
+        announce.extend_from_slice(chunk);
+    }
 
     socket.send(&announce).await?;
 
@@ -90,7 +213,7 @@ pub async fn get_peers(
 
     // println!("Seeders = {ann_seeders}, Leechers = {_ann_leechers}");
 
-    let peers = parse_announce_response_peers(&resp[..len])?;
+    let peers = parse_announce_response_peers(&resp[..len], ip_len)?;
 
     Ok(peers)
 }
@@ -114,18 +237,9 @@ fn parse_announce_response(resp: &[u8]) -> Result<(u32, u32, u32, u32, u32), Str
     Ok((action, transaction_id, interval, leecher, seeder))
 }
 
-fn parse_announce_response_peers(resp: &[u8]) -> Result<Peers, String> {
+fn parse_announce_response_peers(resp: &[u8], ip_len: usize) -> Result<Vec<SocketAddr>, String> {
     let peers_bytes = &resp[20..]; // skip header (20 bytes)
-    let peers = Peers(
-        peers_bytes
-            .chunks(6)
-            .map(|chunk| {
-                SocketAddrV4::new(
-                    Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
-                    u16::from_be_bytes([chunk[4], chunk[5]]),
-                )
-            })
-            .collect(),
-    );
-    Ok(peers)
+    // A trailing partial entry is dropped rather than failing the announce
+    let whole = peers_bytes.len() - peers_bytes.len() % (ip_len + 2);
+    parse_compact(&peers_bytes[..whole], ip_len).ok_or_else(|| "Invalid peer list".to_string())
 }
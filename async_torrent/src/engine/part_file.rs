@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    fs::{File, create_dir_all, remove_file},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bencode::Info,
+    engine::{
+        files::{FilePriority, file_pieces},
+        storage::Storage,
+    },
+};
+
+/// Hidden file holding pieces that overlap skipped files.
+///
+/// Those pieces still have to be downloaded for the wanted files they
+/// overlap, but the bytes of the skipped files can't go to their real paths
+/// without creating the files. Instead the whole piece is kept here, one piece
+/// sized slot per piece, and copied into place if the file is enabled later.
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    piece_length: u64,
+    file: Option<File>,
+    /// Piece index to slot number
+    slots: HashMap<usize, u64>,
+    free_slots: Vec<u64>,
+    next_slot: u64,
+}
+
+impl PartFile {
+    pub fn new(info: &Info, root: &Path) -> PartFile {
+        // Without separators the name can't point outside the root
+        let name = info.name.to_string().replace(['/', '\\'], "_");
+        let path = root.join(format!(".{name}.parts"));
+        // Nothing is resumed between runs, so leftovers are useless
+        let _ = remove_file(&path);
+        PartFile {
+            path,
+            piece_length: info.piece_length,
+            file: None,
+            slots: HashMap::new(),
+            free_slots: Vec::new(),
+            next_slot: 0,
+        }
+    }
+
+    pub fn contains(&self, piece_index: usize) -> bool {
+        self.slots.contains_key(&piece_index)
+    }
+
+    pub fn write_piece(&mut self, piece_index: usize, data: &[u8]) -> std::io::Result<()> {
+        let slot = match self.slots.get(&piece_index) {
+            Some(slot) => *slot,
+            None => {
+                let slot = self.free_slots.pop().unwrap_or_else(|| {
+                    self.next_slot += 1;
+                    self.next_slot - 1
+                });
+                self.slots.insert(piece_index, slot);
+                slot
+            }
+        };
+        if self.file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                create_dir_all(parent)?;
+            }
+            self.file = Some(
+                File::options()
+                    .create(true)
+                    .read(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(&self.path)?,
+            );
+        }
+        let file = self.file.as_ref().unwrap();
+        file.write_all_at(data, slot * self.piece_length)
+    }
+
+    /// Read `buf.len()` bytes of a stored piece starting at `begin`.
+    /// Returns false if the piece isn't in the part file.
+    pub fn read(&self, piece_index: usize, begin: u64, buf: &mut [u8]) -> std::io::Result<bool> {
+        let (Some(slot), Some(file)) = (self.slots.get(&piece_index), &self.file) else {
+            return Ok(false);
+        };
+        file.read_exact_at(buf, slot * self.piece_length + begin)?;
+        Ok(true)
+    }
+
+    /// Copy the parts of stored pieces that belong to a file into the file.
+    /// The file must already be allocated in `storage`.
+    pub fn move_into_place(
+        &self,
+        info: &Info,
+        file_index: usize,
+        storage: &dyn Storage,
+    ) -> std::io::Result<()> {
+        for piece_index in file_pieces(info, file_index) {
+            let layout = storage.layout().piece(piece_index);
+            for s in layout.iter().filter(|s| s.file_index == file_index) {
+                let mut buf = vec![0u8; s.len as usize];
+                if self.read(piece_index, s.piece_offset, &mut buf)? {
+                    storage.write_at(file_index, s.file_offset, &buf)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Free the slots of pieces that no longer overlap a skipped file
+    pub fn release(&mut self, info: &Info, priorities: &[FilePriority]) {
+        let mut needed = vec![false; info.pieces.len().div_ceil(20)];
+        for (file_index, priority) in priorities.iter().enumerate() {
+            if *priority == FilePriority::Skip {
+                for piece_index in file_pieces(info, file_index) {
+                    needed[piece_index] = true;
+                }
+            }
+        }
+        let released: Vec<usize> = self.slots.keys().copied().filter(|i| !needed[*i]).collect();
+        for piece_index in released {
+            if let Some(slot) = self.slots.remove(&piece_index) {
+                self.free_slots.push(slot);
+            }
+        }
+
+        if self.slots.is_empty() && self.file.take().is_some() {
+            let _ = remove_file(&self.path);
+            self.free_slots.clear();
+            self.next_slot = 0;
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::PathBuf,
+};
+
+use crate::{bencode::Info, engine::files};
+
+// Device names Windows won't create files under, whatever the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+// Longest file name most filesystems accept, in bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// Build a relative path that can't leave the download root from the raw
+/// path elements of a torrent.
+///
+/// Separators inside an element split it up, so leading `/`s only leave
+/// empty names behind, which are dropped. Any `..` is refused. Invalid
+/// UTF-8, characters some filesystems reject and reserved device names are
+/// replaced so a torrent lands the same way everywhere.
+pub fn sanitize_path<'a>(elements: impl IntoIterator<Item = &'a [u8]>) -> std::io::Result<PathBuf> {
+    let elements: Vec<_> = elements.into_iter().map(String::from_utf8_lossy).collect();
+    let mut path = PathBuf::new();
+    for name in elements.iter().flat_map(|e| e.split(['/', '\\'])) {
+        match name {
+            "" | "." => continue,
+            ".." => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Path {:?} in the torrent leaves the download directory",
+                        elements.join("/")
+                    ),
+                ));
+            }
+            name => path.push(sanitize_name(name)),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        path.push("_");
+    }
+    Ok(path)
+}
+
+fn sanitize_name(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            // Bytes that weren't valid UTF-8 come out as the replacement character
+            '\u{FFFD}' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows drops trailing dots and spaces, which can make names collide
+    name.truncate(name.trim_end_matches(['.', ' ']).len());
+    let stem = name.split('.').next().unwrap_or_default();
+    if name.is_empty() || RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        name.insert(0, '_');
+    }
+    if name.len() > MAX_NAME_LEN {
+        let mut end = MAX_NAME_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    name
+}
+
+/// Fail before starting if any file of the torrent has a path we refuse
+pub fn check_paths(info: &Info) -> std::io::Result<()> {
+    for file_index in 0..files::file_count(info) {
+        files::file_path(info, file_index)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn sanitize(elements: &[&[u8]]) -> std::io::Result<PathBuf> {
+        sanitize_path(elements.iter().copied())
+    }
+
+    #[test]
+    fn dots_inside_names_are_kept() {
+        assert_eq!(
+            sanitize(&[b"a..b", b"..c"]).unwrap(),
+            Path::new("a..b").join("..c")
+        );
+    }
+
+    #[test]
+    fn parent_elements_are_refused() {
+        assert!(sanitize(&[b"a", b"..", b"b"]).is_err());
+        assert!(sanitize(&[b"a/../b"]).is_err());
+        assert!(sanitize(&[b"a\\..\\b"]).is_err());
+    }
+
+    #[test]
+    fn leading_separators_are_dropped() {
+        assert_eq!(
+            sanitize(&[b"/etc", b"passwd"]).unwrap(),
+            Path::new("etc").join("passwd")
+        );
+        assert_eq!(sanitize(&[b"/", b"."]).unwrap(), Path::new("_"));
+    }
+
+    #[test]
+    fn reserved_names_are_renamed() {
+        assert_eq!(sanitize(&[b"con.txt"]).unwrap(), Path::new("_con.txt"));
+        assert_eq!(sanitize(&[b"LPT1"]).unwrap(), Path::new("_LPT1"));
+        assert_eq!(sanitize(&[b"console"]).unwrap(), Path::new("console"));
+    }
+
+    #[test]
+    fn invalid_utf8_and_special_characters_are_replaced() {
+        assert_eq!(sanitize(&[b"a\xffb"]).unwrap(), Path::new("a_b"));
+        assert_eq!(sanitize(&[b"what?<x>"]).unwrap(), Path::new("what__x_"));
+        assert_eq!(sanitize(&[b"name. . "]).unwrap(), Path::new("name"));
+    }
+
+    #[test]
+    fn long_names_are_cut_on_a_char_boundary() {
+        // 254 bytes then a two byte character
+        let name = format!("{}é", "a".repeat(254));
+        let path = sanitize(&[name.as_bytes()]).unwrap();
+        assert_eq!(path, Path::new(&"a".repeat(254)));
+
+        let name = "é".repeat(200);
+        let path = sanitize(&[name.as_bytes()]).unwrap();
+        let cut = path.to_str().unwrap();
+        assert_eq!(cut.len(), 254);
+        assert!(cut.chars().all(|c| c == 'é'));
+    }
+}
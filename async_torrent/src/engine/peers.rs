@@ -1,37 +1,71 @@
 use serde::de::{self, Deserialize, Deserializer, Visitor};
 use serde::ser::{Serialize, Serializer};
 use std::fmt;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-#[derive(Debug, Clone)]
-pub struct Peers(pub Vec<SocketAddrV4>);
-struct PeersVisitor;
+/// Peers from a compact `peers` list, 6 bytes each (IPv4)
+#[derive(Debug, Clone, Default)]
+pub struct Peers(pub Vec<SocketAddr>);
+
+/// Peers from a compact `peers6` list, 18 bytes each (IPv6, BEP 7)
+#[derive(Debug, Clone, Default)]
+pub struct Peers6(pub Vec<SocketAddr>);
+
+/// Parse compact peers of 4 or 16 byte addresses each followed by a 2 byte port
+pub fn parse_compact(bytes: &[u8], ip_len: usize) -> Option<Vec<SocketAddr>> {
+    let entry_len = ip_len + 2;
+    if !bytes.len().is_multiple_of(entry_len) {
+        return None;
+    }
+    bytes
+        .chunks_exact(entry_len)
+        .map(|entry| {
+            let ip = match ip_len {
+                4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&entry[..4]).ok()?)),
+                16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&entry[..16]).ok()?)),
+                _ => return None,
+            };
+            let port = u16::from_be_bytes([entry[ip_len], entry[ip_len + 1]]);
+            Some(SocketAddr::new(ip, port))
+        })
+        .collect()
+}
+
+/// Compact form of the peers with `ip_len` byte addresses, skipping the rest
+pub fn compact(peers: &[SocketAddr], ip_len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity((ip_len + 2) * peers.len());
+    for peer in peers {
+        match (peer.ip(), ip_len) {
+            (IpAddr::V4(ip), 4) => bytes.extend(ip.octets()),
+            (IpAddr::V6(ip), 16) => bytes.extend(ip.octets()),
+            _ => continue,
+        }
+        bytes.extend(peer.port().to_be_bytes());
+    }
+    bytes
+}
+
+struct PeersVisitor {
+    ip_len: usize,
+}
 
 impl<'de> Visitor<'de> for PeersVisitor {
-    type Value = Peers;
+    type Value = Vec<SocketAddr>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number")
+        write!(
+            formatter,
+            "{} bytes, the first {} bytes are a peer's IP address and the last 2 are a peer's port number",
+            self.ip_len + 2,
+            self.ip_len
+        )
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        if !v.len().is_multiple_of(6) {
-            return Err(E::custom(format!("length is {}", v.len())));
-        }
-        // TODO: use array_chunks when stable; then we can also pattern-match in closure args
-        Ok(Peers(
-            v.chunks_exact(6)
-                .map(|slice_6| {
-                    SocketAddrV4::new(
-                        Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-                        u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                    )
-                })
-                .collect(),
-        ))
+        parse_compact(v, self.ip_len).ok_or_else(|| E::custom(format!("length is {}", v.len())))
     }
 }
 
@@ -40,7 +74,20 @@ impl<'de> Deserialize<'de> for Peers {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(PeersVisitor)
+        deserializer
+            .deserialize_bytes(PeersVisitor { ip_len: 4 })
+            .map(Peers)
+    }
+}
+
+impl<'de> Deserialize<'de> for Peers6 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_bytes(PeersVisitor { ip_len: 16 })
+            .map(Peers6)
     }
 }
 
@@ -49,11 +96,15 @@ impl Serialize for Peers {
     where
         S: Serializer,
     {
-        let mut single_slice = Vec::with_capacity(6 * self.0.len());
-        for peer in &self.0 {
-            single_slice.extend(peer.ip().octets());
-            single_slice.extend(peer.port().to_be_bytes());
-        }
-        serializer.serialize_bytes(&single_slice)
+        serializer.serialize_bytes(&compact(&self.0, 4))
+    }
+}
+
+impl Serialize for Peers6 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&compact(&self.0, 16))
     }
 }
@@ -1,54 +1,118 @@
-use sha1::{Digest, Sha1};
 use std::{
-    collections::HashMap, error::Error, net::SocketAddrV4, os::unix::fs::FileExt, sync::Arc,
-    time::Duration,
+    collections::HashSet,
+    error::Error,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use serde_bencoded::to_vec;
+use serde_bencoded::{from_bytes, to_vec};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::{mpsc, oneshot},
-    time::{sleep, timeout},
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::interval,
 };
 
 type AsyncError = Box<dyn Error + Send + Sync>;
 
-const TIMEOUT: u64 = 5;
 const CHOKE_TIMEOUT: u64 = 30;
+// Seconds of silence from our side before a keep-alive goes out
+const KEEP_ALIVE_INTERVAL: u64 = 120;
+// Seconds a connection neither side wants may hold a slot others wait for
+const CROWDED_IDLE: u64 = 30;
 const REQUEST_ONCE: u8 = 5;
-const BLOCK_LEN: u32 = 16 * 1024;
+const RETRY_INTERVAL: u64 = 10;
+// Largest block a remote peer may request from us
+const MAX_REQUEST_LEN: u32 = 128 * 1024;
+const HANDSHAKE_LEN: usize = 68;
+// Length prefix, message id, piece index and begin offset of a piece message
+const PIECE_HEADER_LEN: usize = 13;
 
 use crate::{
-    bencode::{FileMode, Info, MetaInfo},
+    bencode::MetaInfo,
     engine::{
-        central_manager::{PieceCommands, PieceState},
+        central_manager::PieceCommands,
+        client_id,
+        connection_manager::{ConnEvent, PeerSource},
+        disk_io::{DiskIo, is_disk_full},
         events::UiEvent,
+        extension::{self, EXTENDED_ID, ExtendedHandshake},
+        piece_store::{BlockOutcome, BlockRequest},
+        rate_limiter::Bandwidth,
+        stats::{RateMeter, STATS_INTERVAL},
+        transport::{PeerReader, PeerStream, PeerWriter},
     },
-    utils::{gen_peer_id, sha1_hash},
+    utils::{peer_id, sha1_hash},
 };
 
+/// Error a peer loop ends with when it drops a connection neither side had
+/// any use for, so the peer isn't dialed right back
+#[derive(Debug)]
+pub struct Idle(&'static str);
+
+impl fmt::Display for Idle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Error for Idle {}
+
+/// Commands the central manager sends to a peer task
+#[derive(Debug)]
+pub enum PeerCommand {
+    Choke,
+    Unchoke,
+}
+
 #[allow(unused)]
 pub struct Peer {
-    address: SocketAddrV4,
+    address: SocketAddr,
     info: Arc<MetaInfo>,
     num_pieces: usize,
     info_hash: [u8; 20],
-    socket: TcpStream,
+    writer: PeerWriter,
+    messages: mpsc::Receiver<(MsgType, Vec<u8>)>,
+    reader_task: JoinHandle<()>,
+    control: mpsc::Receiver<PeerCommand>,
+    /// Pieces we finished, kept apart from `control` so none get lost
+    haves: mpsc::UnboundedReceiver<usize>,
     total_size: u64,
     bitfield: Vec<bool>,
-    outstanding: HashMap<usize, PieceStatus>,
-    pub peer_id: [u8; 20],
+    outstanding: HashSet<usize>,
+    am_choking: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    am_interested: bool,
+    choked_since: Instant,
+    /// Set while neither side is interested. The connection is only kept
+    /// open then, without asking for pieces, until the idle timeout.
+    uninterested_since: Option<Instant>,
+    idle_timeout: Duration,
+    /// Whether other peers are waiting for a connection slot
+    crowded: watch::Receiver<bool>,
+    last_received: Instant,
+    last_sent: Instant,
+    /// Our extension handshake, until it is sent. `None` when the peer
+    /// doesn't support extensions.
+    our_extensions: Option<ExtendedHandshake>,
+    /// The peer's extension handshake once received
+    extensions: Option<ExtendedHandshake>,
+    bandwidth: Bandwidth,
+    disk: DiskIo,
+    /// Addresses the peer tells us about go to the connection manager
+    events: mpsc::Sender<ConnEvent>,
+    /// Payload bytes exchanged with this peer
+    downloaded: u64,
+    uploaded: u64,
+    download_meter: RateMeter,
+    upload_meter: RateMeter,
     pub sender: mpsc::Sender<PieceCommands>,
     pub ui_tx: mpsc::Sender<UiEvent>,
 }
 
-struct PieceStatus {
-    data: Vec<u8>,
-    blocks: Vec<bool>,
-    received: usize,
-}
-
 #[derive(PartialEq, Eq)]
 enum MsgType {
     Choke = 0,
@@ -60,6 +124,7 @@ enum MsgType {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
     KeepAlive,
 }
 
@@ -77,282 +142,505 @@ impl TryFrom<u8> for MsgType {
             6 => Ok(MsgType::Request),
             7 => Ok(MsgType::Piece),
             8 => Ok(MsgType::Cancel),
+            20 => Ok(MsgType::Extended),
             // 9 => Ok(MsgType::KeepAlive),
             _ => Err("Invalid message type".into()),
         }
     }
 }
 
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
 impl Peer {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        address: SocketAddrV4,
+        mut stream: PeerStream,
+        address: SocketAddr,
         info: Arc<MetaInfo>,
         tx: mpsc::Sender<PieceCommands>,
         ui_tx: mpsc::Sender<UiEvent>,
+        bandwidth: Bandwidth,
+        disk: DiskIo,
+        our_extensions: ExtendedHandshake,
+        idle_timeout: Duration,
+        crowded: watch::Receiver<bool>,
+        events: mpsc::Sender<ConnEvent>,
     ) -> Result<Peer, AsyncError> {
         let info_portion = &info.info;
         let raw_hash = to_vec(info_portion)?;
         let info_hash = sha1_hash(&raw_hash);
 
         let num_pieces = info.info.pieces.len().div_ceil(20);
-        let socket = TcpStream::connect(address).await?;
-        let mut left: u64 = 0;
-        match &(info.info.mode) {
-            FileMode::SingleFile { length } => left = *length,
-            FileMode::MultipleFiles { files } => {
-                for file in files {
-                    left += file.length;
-                }
-            }
-        }
-        let peer_id = gen_peer_id();
-
-        tx.send(PieceCommands::PeerRegister(peer_id, num_pieces))
-            .await?;
+        let left = info.info.total_length();
+        let (reserved, remote_id) = handshake(&mut stream, &info_hash, &peer_id()).await?;
+        let supports_extensions = reserved[extension::RESERVED_BYTE] & extension::RESERVED_BIT != 0;
+        bandwidth.upload(0, HANDSHAKE_LEN).await;
+        bandwidth.download(0, HANDSHAKE_LEN).await;
+
+        // Messages are read on their own task so the peer loop can wait on
+        // the socket and on central manager commands at the same time
+        let PeerStream { reader, writer } = stream;
+        let (msg_tx, messages) = mpsc::channel(64);
+        let reader_task = tokio::spawn(read_messages(reader, msg_tx, bandwidth.clone()));
+        let (control_tx, control) = mpsc::channel(16);
+        let (haves_tx, haves) = mpsc::unbounded_channel();
+
+        tx.send(PieceCommands::PeerRegister(
+            address, num_pieces, control_tx, haves_tx,
+        ))
+        .await?;
         let _ = ui_tx
             .send(UiEvent::PeerUpdate {
-                peer_id: String::from_utf8_lossy(&peer_id).to_string(),
+                peer: address.to_string(),
                 task: "Trying to connect".to_string(),
                 choked: true,
             })
             .await;
+        let _ = ui_tx
+            .send(UiEvent::PeerClient {
+                peer: address.to_string(),
+                client: client_id::client_name(&remote_id),
+            })
+            .await;
         Ok(Peer {
             address,
             info: info.clone(),
             num_pieces,
             info_hash,
-            socket,
-            peer_id,
+            writer,
+            messages,
+            reader_task,
+            control,
+            haves,
             sender: tx,
             total_size: left,
             bitfield: vec![false; num_pieces],
-            outstanding: HashMap::new(),
+            outstanding: HashSet::new(),
+            am_choking: true,
+            peer_choking: true,
+            peer_interested: false,
+            am_interested: false,
+            choked_since: Instant::now(),
+            uninterested_since: Some(Instant::now()),
+            idle_timeout,
+            crowded,
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            our_extensions: supports_extensions.then_some(our_extensions),
+            extensions: None,
+            bandwidth,
+            disk,
+            events,
+            downloaded: 0,
+            uploaded: 0,
+            download_meter: RateMeter::default(),
+            upload_meter: RateMeter::default(),
             ui_tx,
         })
     }
 
     pub async fn start(&mut self) -> Result<(), AsyncError> {
-        self.handshake().await?;
-
-        let bitfield_future = self.read_until_type(MsgType::Bitfield);
-        if let Ok(Ok((_, bitfield_payload))) =
-            timeout(Duration::from_secs(TIMEOUT), bitfield_future).await
-        {
-            self.parse_bitfield(bitfield_payload);
-            self.sender
-                .send(PieceCommands::SetBitfield(
-                    self.peer_id,
-                    self.bitfield.clone(),
-                ))
+        // The bitfield has to come first, right after the handshake
+        self.send_bitfield().await?;
+        if let Some(handshake) = self.our_extensions.take() {
+            self.send_extended(extension::HANDSHAKE_ID, &to_vec(&handshake)?)
                 .await?;
         }
 
-        self.send_interested().await?;
+        let mut retry = interval(Duration::from_secs(RETRY_INTERVAL));
+        let mut stats = interval(STATS_INTERVAL);
+        let mut want_pieces = false;
+        loop {
+            if want_pieces
+                && self.am_interested
+                && !self.peer_choking
+                && self.outstanding.is_empty()
+            {
+                want_pieces = self.request_pieces().await?;
+            }
 
-        let unchoke_future = self.read_until_type(MsgType::Unchoke);
-        let _ = timeout(Duration::from_secs(CHOKE_TIMEOUT), unchoke_future).await??;
+            tokio::select! {
+                msg = self.messages.recv() => {
+                    let (msg_type, payload) = msg.ok_or("Peer closed the connection")?;
+                    self.last_received = Instant::now();
+                    want_pieces |= self.handle_message(msg_type, payload).await?;
+                }
+                cmd = self.control.recv() => match cmd {
+                    Some(PeerCommand::Choke) => self.send_choke().await?,
+                    Some(PeerCommand::Unchoke) => self.send_unchoke().await?,
+                    None => break,
+                },
+                Some(index) = self.haves.recv() => {
+                    self.send_have(index as u32).await?;
+                    // The piece may have been the last one the peer could give us
+                    if self.am_interested {
+                        self.reevaluate_interest().await?;
+                    }
+                }
+                _ = retry.tick() => {
+                    // A peer that keeps us choked is only worth keeping if it wants our data
+                    if self.am_interested
+                        && self.peer_choking
+                        && !self.peer_interested
+                        && self.choked_since.elapsed() >= Duration::from_secs(CHOKE_TIMEOUT)
+                    {
+                        return Err(Idle("Peer kept us choked").into());
+                    }
+                    self.check_idle().await?;
+                    // File priorities may have changed what we want
+                    self.reevaluate_interest().await?;
+                    want_pieces = true;
+                }
+                _ = stats.tick() => self.report_rates().await,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop a peer that went silent or that neither side has wanted
+    /// anything from for too long, sooner when other peers wait for its
+    /// slot, and keep the connection alive otherwise
+    async fn check_idle(&mut self) -> Result<(), AsyncError> {
+        if self.last_received.elapsed() >= self.idle_timeout {
+            return Err("Peer went silent".into());
+        }
+        if let Some(since) = self.uninterested_since {
+            if since.elapsed() >= self.idle_timeout {
+                return Err(Idle("Neither side interested").into());
+            }
+            if *self.crowded.borrow() && since.elapsed() >= Duration::from_secs(CROWDED_IDLE) {
+                return Err(Idle("Neither side interested, making room").into());
+            }
+        }
+        if self.last_sent.elapsed() >= Duration::from_secs(KEEP_ALIVE_INTERVAL) {
+            self.write_message(&0u32.to_be_bytes(), 0).await?;
+        }
+        Ok(())
+    }
 
+    /// Tell the peer whether we want anything from it, when that changed
+    async fn reevaluate_interest(&mut self) -> Result<(), AsyncError> {
+        let (oneshot_sender, oneshot_receiver) = oneshot::channel();
         self.sender
-            .send(PieceCommands::PeerUnchoke(self.peer_id))
+            .send(PieceCommands::WantsFrom(self.address, oneshot_sender))
             .await?;
+        let interested = oneshot_receiver.await?;
+        if interested != self.am_interested {
+            self.send_interested(interested).await?;
+        }
+        Ok(())
+    }
+
+    /// Note when the connection turns useless to both sides, or useful again
+    fn update_idle(&mut self) {
+        if self.am_interested || self.peer_interested {
+            self.uninterested_since = None;
+        } else if self.uninterested_since.is_none() {
+            self.uninterested_since = Some(Instant::now());
+        }
+    }
+
+    async fn report_rates(&mut self) {
+        let now = Instant::now();
+        self.download_meter.sample(now, self.downloaded);
+        self.upload_meter.sample(now, self.uploaded);
         let _ = self
             .ui_tx
-            .send(UiEvent::PeerUpdate {
-                peer_id: String::from_utf8_lossy(&self.peer_id).to_string(),
-                task: "Peer connection established".to_string(),
-                choked: false,
+            .send(UiEvent::PeerRates {
+                peer: self.address.to_string(),
+                download_rate: self.download_meter.rate(),
+                upload_rate: self.upload_meter.rate(),
             })
             .await;
+    }
 
-        let mut first_try = true;
-        loop {
-            if self.outstanding.len() == 0 {
-                let mut got_one = false;
-                let mut req_str = String::from("Requesting index ");
-                for _request in 0..REQUEST_ONCE {
-                    let (oneshot_sender, oneshot_receiver) = oneshot::channel();
-                    self.sender
-                        .send(PieceCommands::RequestPieceIndex(
-                            self.peer_id,
-                            oneshot_sender,
-                        ))
-                        .await?;
-                    if let Some(index) = oneshot_receiver.await? {
-                        if self
-                            .outstanding
-                            .iter()
-                            .find(|(k, _v)| **k == index)
-                            .is_some()
-                        {
-                            break;
-                        }
-                        let piece_len = if index >= self.num_pieces - 1 {
-                            self.total_size - index as u64 * self.info.info.piece_length
-                        } else {
-                            self.info.info.piece_length
-                        };
-                        self.outstanding.insert(
-                            index,
-                            PieceStatus {
-                                data: vec![0u8; piece_len as usize],
-                                blocks: vec![false; piece_len.div_ceil(BLOCK_LEN as u64) as usize],
-                                received: 0,
-                            },
-                        );
-                        let remaining = std::cmp::min(BLOCK_LEN, piece_len as u32);
-                        self.send_request(index as u32, 0, remaining).await?;
-                        req_str += &index.to_string();
-                        req_str += ",";
-                        got_one = true;
-                    } else {
-                        break;
-                    }
-                }
-                if !got_one {
-                    if !first_try {
-                        break;
-                    } else {
-                        first_try = false;
-                        sleep(Duration::from_secs(10)).await;
-                    }
+    /// Ask the central manager for pieces and request their first missing blocks.
+    /// Returns whether anything was requested.
+    async fn request_pieces(&mut self) -> Result<bool, AsyncError> {
+        let mut got_one = false;
+        let mut req_str = String::from("Requesting index ");
+        for _request in 0..REQUEST_ONCE {
+            let (oneshot_sender, oneshot_receiver) = oneshot::channel();
+            self.sender
+                .send(PieceCommands::RequestPieceIndex(
+                    self.address,
+                    oneshot_sender,
+                ))
+                .await?;
+            if let Some(index) = oneshot_receiver.await? {
+                if self.outstanding.contains(&index) {
+                    break;
                 }
+                // Resume from whatever blocks the store already holds for this piece
+                let (oneshot_sender, oneshot_receiver) = oneshot::channel();
+                self.sender
+                    .send(PieceCommands::NextBlock(index, oneshot_sender))
+                    .await?;
+                let Some(req) = oneshot_receiver.await? else {
+                    continue;
+                };
+                self.outstanding.insert(index);
+                self.send_request(req).await?;
+                req_str += &index.to_string();
+                req_str += ",";
+                got_one = true;
+            } else {
+                break;
+            }
+        }
+        if got_one {
+            let _ = self
+                .ui_tx
+                .send(UiEvent::PeerUpdate {
+                    peer: self.address.to_string(),
+                    task: req_str.trim_end_matches(',').to_string(),
+                    choked: false,
+                })
+                .await;
+        }
+        Ok(got_one)
+    }
+
+    /// Handle one message from the peer.
+    /// Returns true when it is worth asking the central manager for new pieces.
+    async fn handle_message(
+        &mut self,
+        msg_type: MsgType,
+        payload: Vec<u8>,
+    ) -> Result<bool, AsyncError> {
+        match msg_type {
+            MsgType::Choke => {
+                self.peer_choking = true;
+                self.choked_since = Instant::now();
+                self.sender
+                    .send(PieceCommands::PeerChoked(self.address))
+                    .await?;
+                // Blocks received so far stay in the central piece store,
+                // so another peer can pick up where this one stopped
+                self.outstanding.clear();
+                let _ = self
+                    .ui_tx
+                    .send(UiEvent::PeerUpdate {
+                        peer: self.address.to_string(),
+                        task: "Peer choked".to_string(),
+                        choked: true,
+                    })
+                    .await;
+            }
+            MsgType::Unchoke => {
+                self.peer_choking = false;
+                self.sender
+                    .send(PieceCommands::PeerUnchoke(self.address))
+                    .await?;
                 let _ = self
                     .ui_tx
                     .send(UiEvent::PeerUpdate {
-                        peer_id: String::from_utf8_lossy(&self.peer_id).to_string(),
-                        task: req_str.trim_end_matches(',').to_string(),
+                        peer: self.address.to_string(),
+                        task: "Peer unchoked".to_string(),
                         choked: false,
                     })
                     .await;
-            } else {
-                let (msg_type, payload) = self.read_message().await?;
-                match msg_type {
-                    MsgType::Choke => {
-                        self.sender
-                            .send(PieceCommands::PeerChoked(self.peer_id))
-                            .await?;
-                        for index in self.outstanding.keys() {
-                            self.sender
-                                .send(PieceCommands::PieceFailed(self.peer_id, *index))
-                                .await?;
-                        }
-                        self.outstanding.clear();
-                        timeout(
-                            Duration::from_secs(CHOKE_TIMEOUT),
-                            self.read_until_type(MsgType::Unchoke),
-                        )
-                        .await??;
-                        self.sender
-                            .send(PieceCommands::PeerUnchoke(self.peer_id))
-                            .await?;
-                        let _ = self
-                            .ui_tx
-                            .send(UiEvent::PeerUpdate {
-                                peer_id: String::from_utf8_lossy(&self.peer_id).to_string(),
-                                task: "Peer choked".to_string(),
-                                choked: true,
-                            })
-                            .await;
-                    }
-                    MsgType::Unchoke => {
-                        self.sender
-                            .send(PieceCommands::PeerUnchoke(self.peer_id))
-                            .await?;
-                        let _ = self
-                            .ui_tx
-                            .send(UiEvent::PeerUpdate {
-                                peer_id: String::from_utf8_lossy(&self.peer_id).to_string(),
-                                task: "Peer unchoked".to_string(),
-                                choked: false,
-                            })
-                            .await;
+                return Ok(true);
+            }
+            MsgType::Intersted | MsgType::NotInterested => {
+                self.peer_interested = msg_type == MsgType::Intersted;
+                self.update_idle();
+                self.sender
+                    .send(PieceCommands::PeerInterested(
+                        self.address,
+                        self.peer_interested,
+                    ))
+                    .await?;
+            }
+            MsgType::Bitfield => {
+                self.parse_bitfield(payload);
+                self.sender
+                    .send(PieceCommands::SetBitfield(
+                        self.address,
+                        self.bitfield.clone(),
+                    ))
+                    .await?;
+                self.reevaluate_interest().await?;
+                return Ok(true);
+            }
+            MsgType::Have => {
+                let index = u32::from_be_bytes(payload[0..4].try_into()?);
+                self.sender
+                    .send(PieceCommands::UpdateBitfield(self.address, index))
+                    .await?;
+                if !self.am_interested {
+                    self.reevaluate_interest().await?;
+                }
+                return Ok(true);
+            }
+            MsgType::Request => {
+                if payload.len() < 12 {
+                    return Err("Request message too short".into());
+                }
+                let index = u32::from_be_bytes(payload[0..4].try_into()?);
+                let begin = u32::from_be_bytes(payload[4..8].try_into()?);
+                let length = u32::from_be_bytes(payload[8..12].try_into()?);
+                self.serve_request(BlockRequest {
+                    index,
+                    begin,
+                    length,
+                })
+                .await?;
+            }
+            MsgType::Piece => {
+                let index = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+                let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap());
+                let block = payload[8..].to_vec();
+                self.downloaded += block.len() as u64;
+                if !self.outstanding.contains(&(index as usize)) {
+                    return Ok(false);
+                }
+
+                let (oneshot_sender, oneshot_receiver) = oneshot::channel();
+                self.sender
+                    .send(PieceCommands::BlockReceived(
+                        self.address,
+                        index as usize,
+                        begin,
+                        block,
+                        oneshot_sender,
+                    ))
+                    .await?;
+                match oneshot_receiver.await? {
+                    BlockOutcome::Next(req) => {
+                        self.send_request(req).await?;
                     }
-                    MsgType::Have => {
-                        let index = u32::from_be_bytes(payload[0..4].try_into()?);
-                        self.sender
-                            .send(PieceCommands::UpdateBitfield(self.peer_id, index))
-                            .await?;
+                    BlockOutcome::Complete(data) => {
+                        self.outstanding.remove(&(index as usize));
+                        let (valid, data) = self.disk.hash(index as usize, data).await;
+                        let done = valid
+                            && match self.disk.write(index as usize, data).await {
+                                Ok(()) => true,
+                                // The torrent is paused now, the piece is fetched again on resume
+                                Err(e) if is_disk_full(&e) => false,
+                                Err(e) => return Err(e.into()),
+                            };
+                        let cmd = if done {
+                            PieceCommands::PieceDone(self.address, index as usize)
+                        } else {
+                            PieceCommands::PieceFailed(self.address, index as usize)
+                        };
+                        self.sender.send(cmd).await?;
+                        return Ok(true);
                     }
-                    MsgType::Request => {
-                        self.send_choke().await?;
+                    BlockOutcome::Stale => {
+                        self.outstanding.remove(&(index as usize));
+                        return Ok(true);
                     }
-                    MsgType::Piece => {
-                        let index = u32::from_be_bytes(payload[0..4].try_into().unwrap());
-                        let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap());
-                        let block = payload[8..].to_vec();
-                        let mut remove = false;
-                        if let Some(piece) = self.outstanding.get_mut(&(index as usize)) {
-                            let block_index = begin as usize / BLOCK_LEN as usize;
-                            if block_index >= piece.blocks.len() {
-                                continue;
-                            }
-                            if !piece.blocks[block_index] {
-                                piece.blocks[block_index] = true;
-                                piece.received += 1;
-                            }
-
-                            let start = begin as usize;
-                            let end = start + block.len();
-                            if end > piece.data.len() {
-                                continue;
-                            }
-                            piece.data[start..end].copy_from_slice(&block);
-
-                            if piece.received == piece.blocks.len()
-                                && verify_hash(&piece.data, index as usize, &self.info.info.pieces)
-                            {
-                                // println!("Piece done");
-                                remove = true;
-                                write_piece_to_files(&self.info.info, index as usize, &piece.data)?;
-                            } else {
-                                if let Some((i, _)) =
-                                    piece.blocks.iter().enumerate().find(|(_, b)| !**b)
-                                {
-                                    let begin = i as u32 * BLOCK_LEN;
-                                    let remaining = piece.data.len() as u32 - begin;
-                                    let req_len = remaining.min(BLOCK_LEN);
-                                    let (oneshot_sender, oneshot_receiver) = oneshot::channel();
-                                    self.sender
-                                        .send(PieceCommands::RequestPieceStatus(
-                                            index as usize,
-                                            oneshot_sender,
-                                        ))
-                                        .await?;
-                                    if let Some(piece_stat) = oneshot_receiver.await? {
-                                        if piece_stat != PieceState::Done {
-                                            let _ = self
-                                                .ui_tx
-                                                .send(UiEvent::PieceDownloading(index as usize))
-                                                .await;
-                                            self.send_request(index, begin, req_len).await?;
-                                        } else {
-                                            let _ =
-                                                self.outstanding.remove_entry(&(index as usize));
-                                            self.send_cancel(index, begin, req_len).await?;
-                                        }
-                                    } else {
-                                        let _ = self.outstanding.remove_entry(&(index as usize));
-                                        self.send_cancel(index, begin, req_len).await?;
-                                    }
-                                }
-                            }
-                        }
-                        if remove {
-                            let _ = self.outstanding.remove_entry(&(index as usize));
-                            self.sender
-                                .send(PieceCommands::PieceDone(self.peer_id, index as usize))
-                                .await?;
-                        }
+                    BlockOutcome::Invalid => {
+                        return Err("Peer sent a block we never asked for".into());
                     }
-                    _ => {}
-                };
+                }
             }
+            MsgType::Extended => self.handle_extended(&payload).await,
+            _ => {}
+        };
+        Ok(false)
+    }
+
+    async fn handle_extended(&mut self, payload: &[u8]) {
+        let Some((&id, payload)) = payload.split_first() else {
+            return;
+        };
+        // Other extension messages are only sent to peers that asked for them
+        if id != extension::HANDSHAKE_ID {
+            return;
+        }
+        match from_bytes::<ExtendedHandshake>(payload) {
+            Ok(handshake) => {
+                // Another way to reach the same peer (BEP 7), only the port
+                // it listens on is worth dialing
+                if let (Some(ipv6), Some(port)) = (handshake.ipv6(), handshake.p)
+                    && self.address.ip() != IpAddr::V6(ipv6)
+                {
+                    let addr = SocketAddr::new(ipv6.into(), port);
+                    let _ = self
+                        .events
+                        .send(ConnEvent::AddPeers(vec![addr], PeerSource::Extension))
+                        .await;
+                }
+                // More telling than the peer id, which many clients disguise
+                if let Some(client) = handshake
+                    .v
+                    .as_ref()
+                    .and_then(|v| client_id::client_from_version(v.as_bytes()))
+                {
+                    let _ = self
+                        .ui_tx
+                        .send(UiEvent::PeerClient {
+                            peer: self.address.to_string(),
+                            client,
+                        })
+                        .await;
+                }
+                self.extensions = Some(handshake);
+            }
+            Err(e) => eprintln!("Invalid extension handshake from {}: {e}", self.address),
+        }
+    }
+
+    /// Send a block the peer asked for, as long as we are not choking it
+    /// and actually have the piece
+    async fn serve_request(&mut self, req: BlockRequest) -> Result<(), AsyncError> {
+        if self.am_choking || req.length == 0 || req.length > MAX_REQUEST_LEN {
+            return Ok(());
+        }
+        let (oneshot_sender, oneshot_receiver) = oneshot::channel();
+        self.sender
+            .send(PieceCommands::HasPiece(req.index as usize, oneshot_sender))
+            .await?;
+        if !oneshot_receiver.await? {
+            return Ok(());
         }
 
+        let piece_len = if req.index as usize >= self.num_pieces - 1 {
+            self.total_size - req.index as u64 * self.info.info.piece_length
+        } else {
+            self.info.info.piece_length
+        };
+        if req.begin as u64 + req.length as u64 > piece_len {
+            return Ok(());
+        }
+
+        // A failed read only costs this request, not the connection
+        let block = match self.disk.read(req).await {
+            Ok(block) => block,
+            Err(e) => {
+                eprintln!("Failed to read block for piece {}: {e}", req.index);
+                return Ok(());
+            }
+        };
+        self.send_piece(req.index, req.begin, &block).await?;
+        self.uploaded += block.len() as u64;
+        self.sender
+            .send(PieceCommands::BlockUploaded(self.address, block.len()))
+            .await?;
         Ok(())
     }
 
-    async fn send_request(
+    async fn send_request(&mut self, req: BlockRequest) -> Result<(), AsyncError> {
+        let mut msg = Vec::with_capacity(17);
+        msg.extend(&(13u32.to_be_bytes())); // length
+        msg.push(6u8); // message ID = request
+        msg.extend(&req.index.to_be_bytes());
+        msg.extend(&req.begin.to_be_bytes());
+        msg.extend(&req.length.to_be_bytes());
+
+        self.write_message(&msg, 0).await
+    }
+
+    #[allow(unused)]
+    async fn send_cancel(
         &mut self,
         piece_index: u32,
         begin: u32,
@@ -360,50 +648,113 @@ impl Peer {
     ) -> Result<(), AsyncError> {
         let mut msg = Vec::with_capacity(17);
         msg.extend(&(13u32.to_be_bytes())); // length
-        msg.push(6u8); // message ID = request
+        msg.push(8u8); // message ID = cancel
         msg.extend(&piece_index.to_be_bytes());
         msg.extend(&begin.to_be_bytes());
         msg.extend(&block_len.to_be_bytes());
 
-        self.socket.write_all(&msg).await?;
-        Ok(())
+        self.write_message(&msg, 0).await
     }
 
-    async fn send_cancel(
+    async fn send_piece(
         &mut self,
         piece_index: u32,
         begin: u32,
-        block_len: u32,
+        block: &[u8],
     ) -> Result<(), AsyncError> {
-        let mut msg = Vec::with_capacity(17);
-        msg.extend(&(13u32.to_be_bytes())); // length
-        msg.push(8u8); // message ID = cancel
+        let mut msg = Vec::with_capacity(13 + block.len());
+        msg.extend(&(9 + block.len() as u32).to_be_bytes()); // length
+        msg.push(7u8); // message ID = piece
         msg.extend(&piece_index.to_be_bytes());
         msg.extend(&begin.to_be_bytes());
-        msg.extend(&block_len.to_be_bytes());
+        msg.extend(block);
 
-        self.socket.write_all(&msg).await?;
-        Ok(())
+        self.write_message(&msg, block.len()).await
     }
 
     async fn send_choke(&mut self) -> Result<(), AsyncError> {
         let mut buf = [0u8; 5];
         buf[0..4].copy_from_slice(&1u32.to_be_bytes());
 
-        self.socket.write_all(&buf).await?;
+        self.write_message(&buf, 0).await?;
+        self.am_choking = true;
+        Ok(())
+    }
+
+    async fn send_unchoke(&mut self) -> Result<(), AsyncError> {
+        let mut buf = [0u8; 5];
+        buf[0..4].copy_from_slice(&1u32.to_be_bytes());
+        buf[4] = 1;
+
+        self.write_message(&buf, 0).await?;
+        self.am_choking = false;
         Ok(())
     }
 
-    async fn send_interested(&mut self) -> Result<(), AsyncError> {
+    async fn send_extended(&mut self, id: u8, payload: &[u8]) -> Result<(), AsyncError> {
+        let mut msg = Vec::with_capacity(6 + payload.len());
+        msg.extend(&(2 + payload.len() as u32).to_be_bytes()); // length
+        msg.push(EXTENDED_ID);
+        msg.push(id);
+        msg.extend(payload);
+
+        self.write_message(&msg, 0).await
+    }
+
+    /// Send interested or not interested
+    async fn send_interested(&mut self, interested: bool) -> Result<(), AsyncError> {
         let msg_len: [u8; 4] = [0, 0, 0, 1];
-        let msg = 2;
+        let msg = if interested { 2 } else { 3 };
 
         let mut packet = Vec::with_capacity(5);
         packet.extend_from_slice(&msg_len);
         packet.push(msg);
 
-        self.socket.write_all(&packet).await?;
+        self.write_message(&packet, 0).await?;
+        self.am_interested = interested;
+        self.update_idle();
+        Ok(())
+    }
+
+    async fn send_have(&mut self, piece_index: u32) -> Result<(), AsyncError> {
+        let mut buf = [0u8; 9];
+        buf[0..4].copy_from_slice(&5u32.to_be_bytes());
+        buf[4] = 4;
+        buf[5..9].copy_from_slice(&piece_index.to_be_bytes());
+
+        self.write_message(&buf, 0).await
+    }
+
+    /// Send the pieces we have, skipped when we have none
+    async fn send_bitfield(&mut self) -> Result<(), AsyncError> {
+        let (oneshot_sender, oneshot_receiver) = oneshot::channel();
+        self.sender
+            .send(PieceCommands::DonePieces(oneshot_sender))
+            .await?;
+        let pieces = oneshot_receiver.await?;
+        if !pieces.contains(&true) {
+            return Ok(());
+        }
+
+        let mut bits = vec![0u8; pieces.len().div_ceil(8)];
+        for (i, _) in pieces.iter().enumerate().filter(|(_, has)| **has) {
+            bits[i / 8] |= 0x80 >> (i % 8);
+        }
+        let mut msg = Vec::with_capacity(5 + bits.len());
+        msg.extend(&(1 + bits.len() as u32).to_be_bytes()); // length
+        msg.push(5u8); // message ID = bitfield
+        msg.extend(&bits);
+
+        self.write_message(&msg, 0).await
+    }
 
+    /// Write a full message, waiting on the upload limiters for its payload
+    async fn write_message(&mut self, msg: &[u8], payload: usize) -> Result<(), AsyncError> {
+        self.bandwidth.upload(payload, msg.len() - payload).await;
+        self.writer.write_all(msg).await?;
+        // Encrypted connections may hold back what the socket didn't take
+        self.writer.flush().await?;
+        self.last_sent = Instant::now();
         Ok(())
     }
 
@@ -419,128 +770,94 @@ impl Peer {
             }
         }
     }
+}
 
-    async fn read_until_type(
-        &mut self,
-        msg_type: MsgType,
-    ) -> Result<(MsgType, Vec<u8>), AsyncError> {
-        loop {
-            match self.read_message().await {
-                Ok((t, pl)) => {
-                    if t == msg_type {
-                        return Ok((t, pl));
-                    }
-                }
-                Err(e) => return Err(e.into()),
-            }
-        }
-    }
-
-    async fn handshake(&mut self) -> Result<(), AsyncError> {
-        let pstrlen: u8 = 19;
-        let pstr = b"BitTorrent protocol";
-        let reserved = [0u8; 8];
-
-        let mut packet = Vec::with_capacity(68);
-        packet.push(pstrlen);
-        packet.extend_from_slice(pstr);
-        packet.extend_from_slice(&reserved);
-        packet.extend_from_slice(&self.info_hash);
-        packet.extend_from_slice(&self.peer_id);
-
-        self.socket.write_all(&packet).await?;
-
-        let mut resp = [0u8; 68];
-
-        if let Err(err) = self.socket.read_exact(&mut resp).await {
-            return Err(format!("failed reading handshake: {err}").into());
-        }
-
-        if resp[0] != pstrlen {
-            return Err("Handshake 1st byte did not match".into());
-        } else if &resp[1..20] != pstr {
-            return Err("BitTorrent protocol missing from resp".into());
-        } else if &resp[28..48] != self.info_hash {
-            return Err("Wrong info_hash returned from peer".into());
-        }
-
-        Ok(())
+/// Exchange handshakes, returning the reserved bytes and peer id of the peer's
+async fn handshake(
+    stream: &mut PeerStream,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+) -> Result<([u8; 8], [u8; 20]), AsyncError> {
+    let pstrlen: u8 = 19;
+    let pstr = b"BitTorrent protocol";
+    let mut reserved = [0u8; 8];
+    reserved[extension::RESERVED_BYTE] |= extension::RESERVED_BIT;
+
+    let mut packet = Vec::with_capacity(68);
+    packet.push(pstrlen);
+    packet.extend_from_slice(pstr);
+    packet.extend_from_slice(&reserved);
+    packet.extend_from_slice(info_hash);
+    packet.extend_from_slice(peer_id);
+
+    stream.writer.write_all(&packet).await?;
+    stream.writer.flush().await?;
+
+    let mut resp = [0u8; 68];
+
+    if let Err(err) = stream.reader.read_exact(&mut resp).await {
+        return Err(format!("failed reading handshake: {err}").into());
     }
 
-    async fn read_message(&mut self) -> Result<(MsgType, Vec<u8>), AsyncError> {
-        let mut len_buf = [0u8; 4];
-        self.socket.read_exact(&mut len_buf).await?;
-        let len = u32::from_be_bytes(len_buf);
-
-        if len == 0 {
-            return Ok((MsgType::KeepAlive, vec![]));
-        }
-
-        let mut buf = vec![0u8; len as usize];
-        self.socket.read_exact(&mut buf).await?;
-
-        let msg_type = MsgType::try_from(buf[0])?;
-        let payload = buf[1..].to_vec();
-
-        Ok((msg_type, payload))
+    if resp[0] != pstrlen {
+        return Err("Handshake 1st byte did not match".into());
+    } else if &resp[1..20] != pstr {
+        return Err("BitTorrent protocol missing from resp".into());
+    } else if resp[28..48] != *info_hash {
+        return Err("Wrong info_hash returned from peer".into());
     }
-}
 
-fn verify_hash(piece_buf: &[u8], piece_index: usize, pieces_field: &[u8]) -> bool {
-    let start = piece_index * 20;
-    let end = start + 20;
-    let expected_hash = &pieces_field[start..end];
-
-    let mut hasher = Sha1::new();
-    hasher.update(piece_buf);
-    let result = hasher.finalize();
-
-    result[..] == expected_hash[..]
+    Ok((resp[20..28].try_into()?, resp[48..68].try_into()?))
 }
 
-fn write_piece_to_files(info: &Info, piece_index: usize, piece_buf: &[u8]) -> std::io::Result<()> {
-    let piece_len = info.piece_length;
-    let mut piece_offset = piece_index as u64 * piece_len;
-    let mut remaining = piece_buf;
-
-    match &info.mode {
-        FileMode::MultipleFiles { files } => {
-            for file in files {
-                if piece_offset >= file.length {
-                    piece_offset -= file.length;
-                    continue;
-                }
-
-                let write_len =
-                    std::cmp::min(remaining.len() as u64, file.length - piece_offset) as usize;
-
-                let file_name = format!("{}/{}", info.name, file.path.join("/"));
-
-                let f = std::fs::File::options()
-                    .read(true)
-                    .write(true)
-                    .open(file_name)?;
-                f.write_at(&remaining[..write_len], piece_offset)?;
-
-                remaining = &remaining[write_len..];
-                piece_offset = 0;
-
-                if remaining.is_empty() {
+/// Read messages off the socket until it closes or the peer task goes away.
+/// Waiting on the download limiters here stops reading from the socket,
+/// which lets TCP flow control slow the remote peer down.
+async fn read_messages(
+    mut reader: PeerReader,
+    tx: mpsc::Sender<(MsgType, Vec<u8>)>,
+    bandwidth: Bandwidth,
+) {
+    loop {
+        match read_message(&mut reader).await {
+            Ok(msg) => {
+                let wire_len = 4 + if msg.0 == MsgType::KeepAlive {
+                    0
+                } else {
+                    1 + msg.1.len()
+                };
+                let payload = if msg.0 == MsgType::Piece {
+                    wire_len.saturating_sub(PIECE_HEADER_LEN)
+                } else {
+                    0
+                };
+                bandwidth.download(payload, wire_len - payload).await;
+                if tx.send(msg).await.is_err() {
                     break;
                 }
             }
-        }
-        FileMode::SingleFile { length } => {
-            let write_len = std::cmp::min(remaining.len() as u64, length - piece_offset) as usize;
-            let f = std::fs::File::open(&info.name).unwrap();
-            f.write_at(&remaining[..write_len], piece_offset)?;
-            remaining = &remaining[write_len..];
+            Err(e) => {
+                eprintln!("Error reading from peer: {e}");
+                break;
+            }
         }
     }
+}
 
-    if !remaining.is_empty() {
-        eprintln!("Warning: piece data exceeds file boundaries!");
+async fn read_message(reader: &mut PeerReader) -> Result<(MsgType, Vec<u8>), AsyncError> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf);
+
+    if len == 0 {
+        return Ok((MsgType::KeepAlive, vec![]));
     }
 
-    Ok(())
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf).await?;
+
+    let msg_type = MsgType::try_from(buf[0])?;
+    let payload = buf[1..].to_vec();
+
+    Ok((msg_type, payload))
 }
@@ -0,0 +1,394 @@
+use std::{
+    cmp::Reverse,
+    ops::Range,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::{
+    bencode::MetaInfo,
+    engine::files::{FilePriorities, FilePriority, file_pieces, file_spans, piece_priorities},
+};
+
+/// Read position of a player streaming a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    pub file_index: usize,
+    /// Byte offset inside the file
+    pub offset: u64,
+}
+
+/// The deadline set from the TUI and those of readers streaming a file
+#[derive(Debug, Default)]
+struct Deadlines {
+    base: Option<Deadline>,
+    /// Reader ids with their position, the one that read last at the end
+    readers: Vec<(u64, Deadline)>,
+    next_reader: u64,
+}
+
+/// Download order settings shared between the TUI and the engine
+#[derive(Debug, Clone)]
+pub struct StreamControl {
+    sequential: Arc<AtomicBool>,
+    deadlines: Arc<RwLock<Deadlines>>,
+}
+
+impl StreamControl {
+    pub fn new(sequential: bool, deadline: Option<Deadline>) -> StreamControl {
+        StreamControl {
+            sequential: Arc::new(AtomicBool::new(sequential)),
+            deadlines: Arc::new(RwLock::new(Deadlines {
+                base: deadline,
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn sequential(&self) -> bool {
+        self.sequential.load(Ordering::Relaxed)
+    }
+
+    pub fn set_sequential(&self, sequential: bool) {
+        self.sequential.store(sequential, Ordering::Relaxed);
+    }
+
+    /// The reader that read last leads, the TUI's deadline applies when
+    /// nothing is streaming
+    pub fn deadline(&self) -> Option<Deadline> {
+        let deadlines = self.deadlines.read().unwrap();
+        deadlines.readers.last().map(|(_, d)| *d).or(deadlines.base)
+    }
+
+    pub fn set_deadline(&self, deadline: Option<Deadline>) {
+        self.deadlines.write().unwrap().base = deadline;
+    }
+
+    /// Start streaming a file. The reader's deadline is dropped with the
+    /// returned handle, giving the lead back to the other readers.
+    pub fn reader(&self, deadline: Deadline) -> StreamReader {
+        let mut deadlines = self.deadlines.write().unwrap();
+        let id = deadlines.next_reader;
+        deadlines.next_reader += 1;
+        deadlines.readers.push((id, deadline));
+        StreamReader {
+            stream: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Deadline of one streaming reader, see `StreamControl::reader`
+#[derive(Debug)]
+pub struct StreamReader {
+    stream: StreamControl,
+    id: u64,
+}
+
+impl StreamReader {
+    /// Move the reader's deadline and let it lead
+    pub fn seek(&self, deadline: Deadline) {
+        let mut deadlines = self.stream.deadlines.write().unwrap();
+        deadlines.readers.retain(|(id, _)| *id != self.id);
+        deadlines.readers.push((self.id, deadline));
+    }
+}
+
+impl Drop for StreamReader {
+    fn drop(&mut self) {
+        let mut deadlines = self.stream.deadlines.write().unwrap();
+        deadlines.readers.retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Chooses which piece a peer should download next.
+///
+/// Pieces in the window ahead of a streaming deadline come first, nearest
+/// first. Everything else goes by file priority, then pieces already partly
+/// downloaded, then rarest first (or lowest index in sequential mode).
+#[derive(Debug)]
+pub struct PiecePicker {
+    info: Arc<MetaInfo>,
+    file_priorities: FilePriorities,
+    stream: StreamControl,
+    /// Pieces ahead of the deadline that get top priority
+    window: usize,
+    /// Generation of `file_priorities` the piece priorities were computed from
+    generation: u64,
+    files: Vec<FilePriority>,
+    pieces: Vec<FilePriority>,
+    /// Number of connected peers that have each piece
+    availability: Vec<u32>,
+}
+
+impl PiecePicker {
+    pub fn new(
+        info: Arc<MetaInfo>,
+        file_priorities: FilePriorities,
+        stream: StreamControl,
+        window: usize,
+    ) -> PiecePicker {
+        let generation = file_priorities.generation();
+        let files = file_priorities.snapshot();
+        let pieces = piece_priorities(&info.info, &files);
+        let availability = vec![0; pieces.len()];
+        PiecePicker {
+            info,
+            file_priorities,
+            stream,
+            window,
+            generation,
+            files,
+            pieces,
+            availability,
+        }
+    }
+
+    /// A peer announced it has a piece
+    pub fn add_piece(&mut self, piece_index: usize) {
+        if let Some(count) = self.availability.get_mut(piece_index) {
+            *count += 1;
+        }
+    }
+
+    /// Count (`add`) or stop counting the pieces of a peer's bitfield
+    pub fn update_bitfield(&mut self, bitfield: &[bool], add: bool) {
+        for (count, has) in self.availability.iter_mut().zip(bitfield) {
+            if !*has {
+                continue;
+            }
+            if add {
+                *count += 1;
+            } else {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Pieces covering the deadline window, empty when nothing is streaming
+    pub fn deadline_window(&self) -> Range<usize> {
+        let Some(deadline) = self.stream.deadline() else {
+            return 0..0;
+        };
+        let info = &self.info.info;
+        let Some((file_offset, file_length)) = file_spans(info).get(deadline.file_index).copied()
+        else {
+            return 0..0;
+        };
+        let pieces = file_pieces(info, deadline.file_index);
+        let offset = file_offset + deadline.offset.min(file_length.saturating_sub(1));
+        let start = ((offset / info.piece_length) as usize).max(pieces.start);
+        start..(start + self.window).min(pieces.end)
+    }
+
+    /// Pick up priority changes made since the last call.
+    /// Returns the files that were skipped before and are wanted now, and
+    /// the files that were wanted and are skipped now.
+    pub fn refresh(&mut self) -> (Vec<usize>, Vec<usize>) {
+        let generation = self.file_priorities.generation();
+        if generation == self.generation {
+            return (Vec::new(), Vec::new());
+        }
+        self.generation = generation;
+
+        let files = self.file_priorities.snapshot();
+        let (mut enabled, mut skipped) = (Vec::new(), Vec::new());
+        for (i, (new, old)) in files.iter().zip(&self.files).enumerate() {
+            match (*old == FilePriority::Skip, *new == FilePriority::Skip) {
+                (true, false) => enabled.push(i),
+                (false, true) => skipped.push(i),
+                _ => {}
+            }
+        }
+        self.pieces = piece_priorities(&self.info.info, &files);
+        self.files = files;
+        (enabled, skipped)
+    }
+
+    pub fn is_wanted(&self, piece_index: usize) -> bool {
+        self.pieces[piece_index] != FilePriority::Skip
+    }
+
+    /// Best wanted piece accepted by `available`
+    pub fn pick(
+        &self,
+        available: impl Fn(usize) -> bool,
+        is_partial: impl Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let window = self.deadline_window();
+        let sequential = self.stream.sequential();
+        (0..self.pieces.len())
+            .filter(|i| self.is_wanted(*i) && available(*i))
+            .max_by_key(|i| {
+                if window.contains(i) {
+                    // Nearest to the read position first
+                    (true, FilePriority::High, false, Reverse(0), Reverse(*i))
+                } else {
+                    let rarity = if sequential { 0 } else { self.availability[*i] };
+                    (
+                        false,
+                        self.pieces[*i],
+                        is_partial(*i),
+                        Reverse(rarity),
+                        Reverse(*i),
+                    )
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::bencode::{File, FileMode, Info};
+
+    // Pieces 0..4 are file 0, 4..8 file 1 and 7..10 file 2
+    const FILE_LENGTHS: [u64; 3] = [40, 35, 25];
+
+    fn torrent() -> Arc<MetaInfo> {
+        Arc::new(MetaInfo {
+            info: Info {
+                piece_length: 10,
+                pieces: ByteBuf::from(vec![0; 10 * 20]),
+                name: "torrent".into(),
+                private: None,
+                mode: FileMode::MultipleFiles {
+                    files: FILE_LENGTHS
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &length)| File {
+                            length,
+                            path: vec![format!("file{i}").as_str().into()],
+                        })
+                        .collect(),
+                },
+            },
+            announce: String::new(),
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+        })
+    }
+
+    fn picker(priorities: [FilePriority; 3], stream: &StreamControl, window: usize) -> PiecePicker {
+        PiecePicker::new(
+            torrent(),
+            FilePriorities::new(priorities.to_vec()),
+            stream.clone(),
+            window,
+        )
+    }
+
+    fn deadline(file_index: usize, offset: u64) -> Option<Deadline> {
+        Some(Deadline { file_index, offset })
+    }
+
+    #[test]
+    fn window_is_clamped_to_the_file() {
+        let stream = StreamControl::new(false, None);
+        let picker = picker([FilePriority::Normal; 3], &stream, 3);
+        assert_eq!(picker.deadline_window(), 0..0);
+
+        for (deadline, window) in [
+            (deadline(1, 0), 4..7),
+            (deadline(1, 15), 5..8),
+            // Runs into the file's last piece
+            (deadline(1, 30), 7..8),
+            // Past the end of the file reads its last byte
+            (deadline(1, 1000), 7..8),
+            (deadline(2, 0), 7..10),
+            (deadline(0, 5), 0..3),
+            (deadline(3, 0), 0..0),
+        ] {
+            stream.set_deadline(deadline);
+            assert_eq!(picker.deadline_window(), window, "{deadline:?}");
+        }
+    }
+
+    #[test]
+    fn last_reader_leads() {
+        let stream = StreamControl::new(false, deadline(0, 0));
+        let first = stream.reader(deadline(1, 0).unwrap());
+        assert_eq!(stream.deadline(), deadline(1, 0));
+        let second = stream.reader(deadline(2, 0).unwrap());
+        assert_eq!(stream.deadline(), deadline(2, 0));
+
+        first.seek(deadline(1, 20).unwrap());
+        assert_eq!(stream.deadline(), deadline(1, 20));
+        second.seek(deadline(2, 10).unwrap());
+        assert_eq!(stream.deadline(), deadline(2, 10));
+    }
+
+    #[test]
+    fn dropping_a_reader_gives_the_lead_back() {
+        let stream = StreamControl::new(false, deadline(0, 0));
+        let first = stream.reader(deadline(1, 0).unwrap());
+        let second = stream.reader(deadline(2, 0).unwrap());
+
+        drop(second);
+        assert_eq!(stream.deadline(), deadline(1, 0));
+        drop(first);
+        assert_eq!(stream.deadline(), deadline(0, 0));
+    }
+
+    #[test]
+    fn pieces_go_by_priority_then_partial_then_rarity() {
+        let stream = StreamControl::new(false, None);
+        let mut picker = picker(
+            [FilePriority::Normal, FilePriority::High, FilePriority::Low],
+            &stream,
+            2,
+        );
+        let none = |_| false;
+        // Piece 7 is shared by files 1 and 2 and takes the higher priority
+        assert_eq!(picker.pick(|i| i >= 7, none), Some(7));
+        // Among equals, the lowest index when all are as rare
+        assert_eq!(picker.pick(|_| true, none), Some(4));
+
+        picker.add_piece(4);
+        picker.update_bitfield(&[false, false, false, false, false, true], true);
+        assert_eq!(picker.pick(|_| true, none), Some(6));
+        // Finishing a started piece beats rarity
+        assert_eq!(picker.pick(|_| true, |i| i == 5), Some(5));
+        // Rarity doesn't matter in sequential mode
+        stream.set_sequential(true);
+        assert_eq!(picker.pick(|_| true, none), Some(4));
+        // Lower priorities only once nothing better is available
+        assert_eq!(picker.pick(|i| !(4..=7).contains(&i), none), Some(0));
+        assert_eq!(picker.pick(|i| i > 7, none), Some(8));
+    }
+
+    #[test]
+    fn deadline_window_comes_first_nearest_first() {
+        let stream = StreamControl::new(false, deadline(0, 20));
+        let picker = picker(
+            [FilePriority::Low, FilePriority::High, FilePriority::High],
+            &stream,
+            2,
+        );
+        assert_eq!(picker.pick(|_| true, |_| false), Some(2));
+        assert_eq!(picker.pick(|i| i != 2, |_| false), Some(3));
+        // Outside the window the usual order applies
+        assert_eq!(picker.pick(|i| i > 3, |_| false), Some(4));
+    }
+
+    #[test]
+    fn skipped_files_are_never_picked() {
+        let stream = StreamControl::new(true, deadline(2, 0));
+        let priorities = [FilePriority::Normal, FilePriority::Skip, FilePriority::Skip];
+        let mut picker = picker(priorities, &stream, 3);
+        assert_eq!(picker.pick(|i| i >= 4, |_| true), None);
+        assert!((0..4).all(|i| picker.is_wanted(i)));
+        assert!((4..10).all(|i| !picker.is_wanted(i)));
+
+        picker.file_priorities.set(2, FilePriority::Normal);
+        assert_eq!(picker.refresh(), (vec![2], vec![]));
+        assert_eq!(picker.pick(|i| i >= 4, |_| false), Some(7));
+    }
+}
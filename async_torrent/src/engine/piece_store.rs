@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+pub const BLOCK_LEN: u32 = 16 * 1024;
+
+/// A single block request inside a piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// What a peer should do after handing a block to the store
+#[derive(Debug)]
+pub enum BlockOutcome {
+    /// Piece still has missing blocks, request this one next
+    Next(BlockRequest),
+    /// Every block has arrived, the peer should verify and write the piece
+    Complete(Vec<u8>),
+    /// The piece is no longer being assembled (finished elsewhere or reset)
+    Stale,
+    /// The block doesn't fill one of the piece's block slots, so it can't
+    /// be an answer to any request we sent
+    Invalid,
+}
+
+#[derive(Debug)]
+struct PartialPiece {
+    data: Vec<u8>,
+    blocks: Vec<bool>,
+    received: usize,
+}
+
+/// Block buffers for pieces that are partially downloaded.
+///
+/// Lives in the central manager so that a piece abandoned by one peer
+/// (choke, disconnect) can be finished by another without losing blocks.
+#[derive(Debug)]
+pub struct PieceStore {
+    piece_length: u64,
+    total_size: u64,
+    num_pieces: usize,
+    partial: HashMap<usize, PartialPiece>,
+}
+
+impl PieceStore {
+    pub fn new(piece_length: u64, total_size: u64, num_pieces: usize) -> PieceStore {
+        PieceStore {
+            piece_length,
+            total_size,
+            num_pieces,
+            partial: HashMap::new(),
+        }
+    }
+
+    pub fn piece_len(&self, index: usize) -> u64 {
+        if index >= self.num_pieces - 1 {
+            self.total_size - index as u64 * self.piece_length
+        } else {
+            self.piece_length
+        }
+    }
+
+    /// First block of `index` that has not been received yet.
+    /// Starts tracking the piece if no blocks have arrived so far.
+    pub fn next_block(&mut self, index: usize) -> Option<BlockRequest> {
+        if index >= self.num_pieces {
+            return None;
+        }
+        let piece_len = self.piece_len(index);
+        let piece = self.partial.entry(index).or_insert_with(|| PartialPiece {
+            data: vec![0u8; piece_len as usize],
+            blocks: vec![false; piece_len.div_ceil(BLOCK_LEN as u64) as usize],
+            received: 0,
+        });
+        missing_block(index, piece)
+    }
+
+    /// Store a received block and report what the peer should do next
+    pub fn add_block(&mut self, index: usize, begin: u32, block: &[u8]) -> BlockOutcome {
+        let Some(piece) = self.partial.get_mut(&index) else {
+            return BlockOutcome::Stale;
+        };
+
+        let block_index = begin as usize / BLOCK_LEN as usize;
+        let start = begin as usize;
+        let end = start + block.len();
+        // Only the last block of the last piece is shorter
+        if !begin.is_multiple_of(BLOCK_LEN)
+            || block_index >= piece.blocks.len()
+            || block.len() != (piece.data.len() - start).min(BLOCK_LEN as usize)
+        {
+            return BlockOutcome::Invalid;
+        }
+
+        piece.data[start..end].copy_from_slice(block);
+        if !piece.blocks[block_index] {
+            piece.blocks[block_index] = true;
+            piece.received += 1;
+        }
+
+        if piece.received == piece.blocks.len() {
+            let piece = self.partial.remove(&index).unwrap();
+            return BlockOutcome::Complete(piece.data);
+        }
+
+        match missing_block(index, piece) {
+            Some(req) => BlockOutcome::Next(req),
+            None => BlockOutcome::Stale,
+        }
+    }
+
+    /// Drop any blocks held for `index`, e.g. after a failed hash check
+    pub fn discard(&mut self, index: usize) {
+        self.partial.remove(&index);
+    }
+
+    pub fn is_partial(&self, index: usize) -> bool {
+        self.partial.contains_key(&index)
+    }
+}
+
+fn missing_block(index: usize, piece: &PartialPiece) -> Option<BlockRequest> {
+    piece.blocks.iter().position(|b| !*b).map(|i| {
+        let begin = i as u32 * BLOCK_LEN;
+        let remaining = piece.data.len() as u32 - begin;
+        BlockRequest {
+            index: index as u32,
+            begin,
+            length: remaining.min(BLOCK_LEN),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECE_LENGTH: u64 = 2 * BLOCK_LEN as u64;
+    // The last piece has one full block and a short one
+    const TOTAL_SIZE: u64 = 2 * PIECE_LENGTH + BLOCK_LEN as u64 + 100;
+
+    fn store() -> PieceStore {
+        PieceStore::new(PIECE_LENGTH, TOTAL_SIZE, 3)
+    }
+
+    fn block(len: u32, fill: u8) -> Vec<u8> {
+        vec![fill; len as usize]
+    }
+
+    fn request(index: u32, begin: u32, length: u32) -> BlockRequest {
+        BlockRequest {
+            index,
+            begin,
+            length,
+        }
+    }
+
+    #[test]
+    fn blocks_complete_the_piece_in_any_order() {
+        let mut store = store();
+        assert_eq!(store.next_block(0), Some(request(0, 0, BLOCK_LEN)));
+        let BlockOutcome::Next(next) = store.add_block(0, BLOCK_LEN, &block(BLOCK_LEN, 2)) else {
+            panic!("expected the first block to be requested");
+        };
+        assert_eq!(next, request(0, 0, BLOCK_LEN));
+        let BlockOutcome::Complete(data) = store.add_block(0, 0, &block(BLOCK_LEN, 1)) else {
+            panic!("expected the piece to be complete");
+        };
+        assert_eq!(data[..BLOCK_LEN as usize], block(BLOCK_LEN, 1));
+        assert_eq!(data[BLOCK_LEN as usize..], block(BLOCK_LEN, 2));
+        assert!(!store.is_partial(0));
+    }
+
+    #[test]
+    fn last_piece_ends_with_a_short_block() {
+        let mut store = store();
+        assert_eq!(store.piece_len(2), BLOCK_LEN as u64 + 100);
+        store.next_block(2);
+        assert!(matches!(
+            store.add_block(2, 0, &block(BLOCK_LEN, 1)),
+            BlockOutcome::Next(req) if req == request(2, BLOCK_LEN, 100)
+        ));
+        assert!(matches!(
+            store.add_block(2, BLOCK_LEN, &block(100, 1)),
+            BlockOutcome::Complete(data) if data.len() == BLOCK_LEN as usize + 100
+        ));
+    }
+
+    #[test]
+    fn duplicate_block_counts_once() {
+        let mut store = store();
+        store.next_block(0);
+        store.add_block(0, 0, &block(BLOCK_LEN, 1));
+        assert!(matches!(
+            store.add_block(0, 0, &block(BLOCK_LEN, 1)),
+            BlockOutcome::Next(req) if req == request(0, BLOCK_LEN, BLOCK_LEN)
+        ));
+        assert!(store.is_partial(0));
+    }
+
+    #[test]
+    fn blocks_outside_a_slot_are_invalid() {
+        let mut store = store();
+        store.next_block(0);
+        store.next_block(2);
+        for (index, begin, len) in [
+            // Past the end of the piece
+            (0, PIECE_LENGTH as u32, BLOCK_LEN),
+            // Not on a block boundary
+            (0, 100, BLOCK_LEN),
+            // Shorter or longer than the slot
+            (0, 0, BLOCK_LEN - 1),
+            (0, 0, BLOCK_LEN + 1),
+            (2, BLOCK_LEN, 99),
+            (2, BLOCK_LEN, BLOCK_LEN),
+        ] {
+            assert!(matches!(
+                store.add_block(index, begin, &block(len, 1)),
+                BlockOutcome::Invalid
+            ));
+        }
+        // Nothing was marked received
+        assert_eq!(store.next_block(0), Some(request(0, 0, BLOCK_LEN)));
+        assert_eq!(store.next_block(2), Some(request(2, 0, BLOCK_LEN)));
+    }
+
+    #[test]
+    fn block_for_untracked_piece_is_stale() {
+        let mut store = store();
+        assert!(matches!(
+            store.add_block(1, 0, &block(BLOCK_LEN, 1)),
+            BlockOutcome::Stale
+        ));
+        store.next_block(1);
+        store.discard(1);
+        assert!(matches!(
+            store.add_block(1, 0, &block(BLOCK_LEN, 1)),
+            BlockOutcome::Stale
+        ));
+        assert_eq!(store.next_block(5), None);
+    }
+}
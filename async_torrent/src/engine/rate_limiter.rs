@@ -0,0 +1,244 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::time::{Instant, sleep};
+
+/// Token bucket holding up to one second worth of bytes.
+/// A rate of 0 means unlimited.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+
+    /// Take `bytes` out of the bucket and return how long the caller has to
+    /// wait before the bucket is out of debt again
+    fn reserve(&mut self, bytes: u64, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// Shared handle to a token bucket, cheap to clone into peer tasks
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// `rate` is in bytes per second, 0 for unlimited
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate as f64);
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = self.bucket.lock().unwrap().reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Download and upload limiters for one scope (global or a single torrent)
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl Throttle {
+    pub fn new(download: u64, upload: u64) -> Throttle {
+        Throttle {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+}
+
+/// Byte counters, with protocol overhead (message headers, control
+/// messages, handshakes) kept apart from piece payload
+#[derive(Debug, Default)]
+pub struct TransferCounters {
+    pub payload_down: AtomicU64,
+    pub overhead_down: AtomicU64,
+    pub payload_up: AtomicU64,
+    pub overhead_up: AtomicU64,
+}
+
+/// Everything a peer task needs to account for and throttle its traffic.
+///
+/// Only payload is throttled, overhead is counted but always let through so
+/// that keep-alives and choke messages are never stuck behind piece data.
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    pub global: Throttle,
+    pub torrent: Throttle,
+    pub counters: Arc<TransferCounters>,
+}
+
+impl Bandwidth {
+    pub async fn download(&self, payload: usize, overhead: usize) {
+        self.counters
+            .payload_down
+            .fetch_add(payload as u64, Ordering::Relaxed);
+        self.counters
+            .overhead_down
+            .fetch_add(overhead as u64, Ordering::Relaxed);
+        if payload > 0 {
+            self.global.download.acquire(payload as u64).await;
+            self.torrent.download.acquire(payload as u64).await;
+        }
+    }
+
+    pub async fn upload(&self, payload: usize, overhead: usize) {
+        if payload > 0 {
+            self.global.upload.acquire(payload as u64).await;
+            self.torrent.upload.acquire(payload as u64).await;
+        }
+        self.counters
+            .payload_up
+            .fetch_add(payload as u64, Ordering::Relaxed);
+        self.counters
+            .overhead_up
+            .fetch_add(overhead as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn timed(f: impl Future<Output = ()>) -> Duration {
+        let start = Instant::now();
+        f.await;
+        start.elapsed()
+    }
+
+    /// Sleeps are rounded up to whole milliseconds
+    fn assert_about(elapsed: Duration, expected: Duration) {
+        assert!(
+            elapsed >= expected && elapsed <= expected + Duration::from_millis(2),
+            "waited {elapsed:?}, expected {expected:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_up_to_one_second_then_wait() {
+        let limiter = RateLimiter::new(1000);
+        assert_eq!(timed(limiter.acquire(1000)).await, Duration::ZERO);
+        assert_about(
+            timed(limiter.acquire(500)).await,
+            Duration::from_millis(500),
+        );
+
+        // Idle time refills the bucket, but never past one second worth
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(timed(limiter.acquire(1000)).await, Duration::ZERO);
+        assert_about(
+            timed(limiter.acquire(100)).await,
+            Duration::from_millis(100),
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_rate_applies_to_the_refill() {
+        let limiter = RateLimiter::new(1000);
+        limiter.acquire(1000).await;
+        limiter.set_rate(4000);
+        assert_eq!(limiter.rate(), 4000);
+        assert_about(
+            timed(limiter.acquire(2000)).await,
+            Duration::from_millis(500),
+        );
+
+        // Lowering the rate drops tokens above the new capacity
+        tokio::time::advance(Duration::from_secs(1)).await;
+        limiter.set_rate(100);
+        assert_eq!(timed(limiter.acquire(100)).await, Duration::ZERO);
+        assert_about(timed(limiter.acquire(50)).await, Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_rate_is_unlimited() {
+        let limiter = RateLimiter::new(0);
+        assert_eq!(
+            timed(limiter.acquire(u32::MAX as u64)).await,
+            Duration::ZERO
+        );
+
+        let limiter = RateLimiter::new(10);
+        limiter.acquire(10).await;
+        limiter.set_rate(0);
+        assert_eq!(timed(limiter.acquire(1_000_000)).await, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_payload_is_throttled() {
+        let bandwidth = Bandwidth {
+            global: Throttle::new(100, 100),
+            torrent: Throttle::new(0, 50),
+            counters: Arc::default(),
+        };
+        let overhead = async {
+            bandwidth.download(0, 10_000).await;
+            bandwidth.upload(0, 10_000).await;
+        };
+        assert_eq!(timed(overhead).await, Duration::ZERO);
+
+        assert_eq!(timed(bandwidth.download(100, 13)).await, Duration::ZERO);
+        assert_about(
+            timed(bandwidth.download(50, 13)).await,
+            Duration::from_millis(500),
+        );
+        // The stricter of the two scopes wins
+        assert_eq!(timed(bandwidth.upload(50, 0)).await, Duration::ZERO);
+        assert_about(
+            timed(bandwidth.upload(25, 0)).await,
+            Duration::from_millis(500),
+        );
+
+        let counters = &bandwidth.counters;
+        assert_eq!(counters.payload_down.load(Ordering::Relaxed), 150);
+        assert_eq!(counters.overhead_down.load(Ordering::Relaxed), 10_026);
+        assert_eq!(counters.payload_up.load(Ordering::Relaxed), 75);
+        assert_eq!(counters.overhead_up.load(Ordering::Relaxed), 10_000);
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::mpsc, time::interval};
+
+use crate::engine::{
+    disk_cache::CacheStats, disk_io::DiskStats, events::UiEvent, rate_limiter::TransferCounters,
+};
+
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Rolling transfer rate computed from samples of a cumulative byte counter
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateMeter {
+    /// Record the counter's current total
+    pub fn sample(&mut self, now: Instant, total: u64) {
+        self.samples.push_back((now, total));
+        while self.samples.len() > 2 && now.duration_since(self.samples[0].0) > RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second over the last few seconds
+    pub fn rate(&self) -> u64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some((first_at, first)), Some((last_at, last))) if last_at > first_at => {
+                let secs = last_at.duration_since(*first_at).as_secs_f64();
+                (last.saturating_sub(*first) as f64 / secs) as u64
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Report the torrent's overall payload rates to the UI once a second
+pub async fn report_transfer_rates(counters: Arc<TransferCounters>, ui_tx: mpsc::Sender<UiEvent>) {
+    let mut down = RateMeter::default();
+    let mut up = RateMeter::default();
+    let mut tick = interval(STATS_INTERVAL);
+    loop {
+        tick.tick().await;
+        let now = Instant::now();
+        let downloaded = counters.payload_down.load(Ordering::Relaxed);
+        let uploaded = counters.payload_up.load(Ordering::Relaxed);
+        down.sample(now, downloaded);
+        up.sample(now, uploaded);
+
+        let event = UiEvent::TransferRates {
+            download_rate: down.rate(),
+            upload_rate: up.rate(),
+            downloaded,
+            uploaded,
+        };
+        if ui_tx.send(event).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Report disk queue depth, latency and cache use to the UI once a second
+pub async fn report_disk_stats(
+    stats: Arc<DiskStats>,
+    cache: Arc<CacheStats>,
+    ui_tx: mpsc::Sender<UiEvent>,
+) {
+    let mut tick = interval(STATS_INTERVAL);
+    loop {
+        tick.tick().await;
+        let event = UiEvent::DiskStats {
+            queued: stats.queued.load(Ordering::Relaxed),
+            latency: Duration::from_micros(stats.latency_micros.load(Ordering::Relaxed)),
+            cache_used: cache.used.load(Ordering::Relaxed),
+            cache_hits: cache.hits.load(Ordering::Relaxed),
+            cache_misses: cache.misses.load(Ordering::Relaxed),
+        };
+        if ui_tx.send(event).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,803 @@
+use std::{
+    collections::VecDeque,
+    ffi::CString,
+    fmt::Debug,
+    fs::{File, copy, create_dir_all, remove_file, rename},
+    io::{Error, ErrorKind},
+    os::unix::{ffi::OsStrExt, fs::FileExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use memmap2::MmapMut;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencode::Info,
+    engine::{
+        config::EngineConfig,
+        files::{FilePriority, file_count, file_path, file_spans},
+        piece_store::BlockRequest,
+    },
+};
+
+/// One file's share of a piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub file_index: usize,
+    /// Offset of the segment in the file
+    pub file_offset: u64,
+    /// Offset of the segment in the piece
+    pub piece_offset: u64,
+    pub len: u64,
+}
+
+/// Where every piece of the torrent lives in its files, computed once
+#[derive(Debug)]
+pub struct FileLayout {
+    lengths: Vec<u64>,
+    pieces: Vec<Vec<Segment>>,
+}
+
+impl FileLayout {
+    pub fn new(info: &Info) -> FileLayout {
+        let num_pieces = info.pieces.len().div_ceil(20);
+        let mut pieces = vec![Vec::new(); num_pieces];
+        let spans = file_spans(info);
+        for (file_index, (offset, length)) in spans.iter().copied().enumerate() {
+            let mut pos = offset;
+            while pos < offset + length {
+                let piece_index = (pos / info.piece_length) as usize;
+                let piece_offset = pos % info.piece_length;
+                let len = (info.piece_length - piece_offset).min(offset + length - pos);
+                if let Some(piece) = pieces.get_mut(piece_index) {
+                    piece.push(Segment {
+                        file_index,
+                        file_offset: pos - offset,
+                        piece_offset,
+                        len,
+                    });
+                }
+                pos += len;
+            }
+        }
+        FileLayout {
+            lengths: spans.into_iter().map(|(_, len)| len).collect(),
+            pieces,
+        }
+    }
+
+    pub fn file_length(&self, file_index: usize) -> u64 {
+        self.lengths[file_index]
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.lengths.len()
+    }
+
+    /// Segments of a whole piece, in order
+    pub fn piece(&self, piece_index: usize) -> &[Segment] {
+        self.pieces.get(piece_index).map_or(&[], |p| p.as_slice())
+    }
+
+    pub fn piece_len(&self, piece_index: usize) -> u64 {
+        self.piece(piece_index).iter().map(|s| s.len).sum()
+    }
+
+    /// Segments covering `len` bytes starting at `begin` inside a piece
+    pub fn range(&self, piece_index: usize, begin: u64, len: u64) -> Vec<Segment> {
+        let end = begin + len;
+        self.piece(piece_index)
+            .iter()
+            .filter(|s| s.piece_offset < end && s.piece_offset + s.len > begin)
+            .map(|s| {
+                let start = s.piece_offset.max(begin);
+                let stop = (s.piece_offset + s.len).min(end);
+                Segment {
+                    file_index: s.file_index,
+                    file_offset: s.file_offset + (start - s.piece_offset),
+                    piece_offset: start,
+                    len: stop - start,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Backend that keeps the torrent's data.
+///
+/// Backends only deal with byte ranges of single files. Splitting pieces and
+/// blocks across file boundaries is done by the provided methods, using the
+/// backend's precomputed layout.
+pub trait Storage: Send + Sync + Debug {
+    fn layout(&self) -> &FileLayout;
+
+    /// Make room for a file, keeping existing data unless `truncate` is set
+    fn allocate(&self, file_index: usize, truncate: bool) -> std::io::Result<()>;
+
+    fn read_at(&self, file_index: usize, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
+
+    fn write_at(&self, file_index: usize, offset: u64, data: &[u8]) -> std::io::Result<()>;
+
+    /// Move every file under a new directory
+    fn move_to(&self, root: &Path) -> std::io::Result<()>;
+
+    /// Remove a file and its data
+    fn delete(&self, file_index: usize) -> std::io::Result<()>;
+
+    fn read_block(&self, req: BlockRequest) -> std::io::Result<Vec<u8>> {
+        let mut block = vec![0u8; req.length as usize];
+        let mut filled = 0;
+        let begin = req.begin as u64;
+        for s in self
+            .layout()
+            .range(req.index as usize, begin, block.len() as u64)
+        {
+            let start = (s.piece_offset - begin) as usize;
+            self.read_at(
+                s.file_index,
+                s.file_offset,
+                &mut block[start..start + s.len as usize],
+            )?;
+            filled += s.len as usize;
+        }
+        if filled != block.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "block exceeds file boundaries",
+            ));
+        }
+        Ok(block)
+    }
+
+    /// Write a run of consecutive verified pieces starting at `first_piece`.
+    /// Parts belonging to skipped files are left out, and segments that
+    /// continue each other in the same file go out as one write.
+    fn write_pieces(
+        &self,
+        first_piece: usize,
+        data: &[u8],
+        priorities: &[FilePriority],
+    ) -> std::io::Result<()> {
+        // Segments with their offset in `data`
+        let mut writes: Vec<(Segment, usize)> = Vec::new();
+        let mut piece_start = 0;
+        let mut piece_index = first_piece;
+        while piece_start < data.len() {
+            let segments = self.layout().piece(piece_index);
+            if segments.is_empty() {
+                eprintln!("Warning: piece data exceeds file boundaries!");
+                break;
+            }
+            for s in segments {
+                let start = piece_start + s.piece_offset as usize;
+                if start >= data.len() {
+                    break;
+                }
+                let len = s.len.min((data.len() - start) as u64);
+                match writes.last_mut() {
+                    Some((last, last_start))
+                        if last.file_index == s.file_index
+                            && last.file_offset + last.len == s.file_offset
+                            && *last_start + last.len as usize == start =>
+                    {
+                        last.len += len;
+                    }
+                    _ => writes.push((Segment { len, ..*s }, start)),
+                }
+            }
+            piece_start += self.layout().piece_len(piece_index) as usize;
+            piece_index += 1;
+        }
+        for (s, start) in writes {
+            if priorities.get(s.file_index) != Some(&FilePriority::Skip) {
+                self.write_at(
+                    s.file_index,
+                    s.file_offset,
+                    &data[start..start + s.len as usize],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash a piece as it is stored and compare it with the torrent's hash
+    fn verify_piece(&self, info: &Info, piece_index: usize) -> std::io::Result<bool> {
+        let mut hasher = Sha1::new();
+        let mut buf = Vec::new();
+        for s in self.layout().piece(piece_index) {
+            buf.resize(s.len as usize, 0);
+            self.read_at(s.file_index, s.file_offset, &mut buf)?;
+            hasher.update(&buf);
+        }
+        let expected = &info.pieces[piece_index * 20..piece_index * 20 + 20];
+        Ok(hasher.finalize()[..] == expected[..])
+    }
+}
+
+/// Available storage backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Filesystem,
+    Mmap,
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fs" => Ok(StorageKind::Filesystem),
+            "mmap" => Ok(StorageKind::Mmap),
+            "memory" => Ok(StorageKind::Memory),
+            _ => Err(format!("Unknown storage {s}")),
+        }
+    }
+}
+
+/// Open a backend keeping files under `root`
+pub fn open_storage(
+    kind: StorageKind,
+    allocation: Allocation,
+    root: &Path,
+    info: &Info,
+) -> std::io::Result<Arc<dyn Storage>> {
+    Ok(match kind {
+        StorageKind::Filesystem => Arc::new(FsStorage::new(info, root, allocation)?),
+        StorageKind::Mmap => Arc::new(MmapStorage::new(info, root, allocation)?),
+        StorageKind::Memory => Arc::new(MemoryStorage::new(info)),
+    })
+}
+
+/// How files on disk get their space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// Set the file length and let the filesystem fill in blocks as pieces arrive
+    Sparse,
+    /// Reserve every block up front so the disk can't fill up mid download
+    Full,
+    /// Create files on their first write and let them grow from there
+    OnWrite,
+}
+
+impl FromStr for Allocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sparse" => Ok(Allocation::Sparse),
+            "full" => Ok(Allocation::Full),
+            "on-write" => Ok(Allocation::OnWrite),
+            _ => Err(format!("Unknown allocation mode {s}")),
+        }
+    }
+}
+
+/// Reserve the blocks of a whole file
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, length: u64) -> std::io::Result<()> {
+    if length == 0 {
+        return Ok(());
+    }
+    // Safety: plain syscall on a descriptor we own for the whole call
+    let ret = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) };
+    if ret != 0 {
+        return Err(Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+/// Reserve the blocks of a whole file. Without fallocate the file is only
+/// sized, which is the best we can do cheaply.
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, length: u64) -> std::io::Result<()> {
+    file.set_len(length)
+}
+
+/// Bytes we may still write to the filesystem holding `path`
+pub fn free_space(path: &Path) -> std::io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // Safety: statvfs is plain old data, and zeroed is a valid value for it
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // Safety: `path` is NUL terminated and `stat` outlives the call
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Fail before starting if the wanted files can't fit where they go
+pub fn check_free_space(info: &Info, config: &EngineConfig) -> std::io::Result<()> {
+    if config.storage == StorageKind::Memory {
+        return Ok(());
+    }
+    let needed: u64 = file_spans(info)
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            // Later overrides win, like when the engine applies them
+            let priority = config.file_priorities.iter().rev().find(|(j, _)| j == i);
+            !matches!(priority, Some((_, FilePriority::Skip)))
+        })
+        .map(|(_, (_, length))| length)
+        .sum();
+    // The download directory may not exist yet
+    let root = config
+        .output_dir
+        .ancestors()
+        .find(|p| p.is_dir())
+        .unwrap_or(Path::new("."));
+    let free = free_space(root)?;
+    if needed > free {
+        return Err(Error::new(
+            ErrorKind::StorageFull,
+            format!(
+                "Not enough disk space: the torrent needs {} but only {} is free",
+                bytesize::ByteSize(needed),
+                bytesize::ByteSize(free)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Open file handles kept around between reads and writes
+const MAX_OPEN_FILES: usize = 32;
+
+/// Paths of the torrent's files under a movable root directory, with a
+/// least recently used cache of open handles
+#[derive(Debug)]
+struct FileTable {
+    root: PathBuf,
+    allocation: Allocation,
+    paths: Vec<PathBuf>,
+    /// Most recently used last
+    handles: VecDeque<(usize, Arc<File>)>,
+}
+
+impl FileTable {
+    fn new(info: &Info, root: &Path, allocation: Allocation) -> std::io::Result<FileTable> {
+        Ok(FileTable {
+            root: root.to_path_buf(),
+            allocation,
+            paths: (0..file_count(info))
+                .map(|i| file_path(info, i))
+                .collect::<std::io::Result<_>>()?,
+            handles: VecDeque::new(),
+        })
+    }
+
+    fn path(&self, file_index: usize) -> PathBuf {
+        self.root.join(&self.paths[file_index])
+    }
+
+    fn create(&mut self, file_index: usize, length: u64, truncate: bool) -> std::io::Result<()> {
+        if self.allocation == Allocation::OnWrite {
+            // The first write creates the file, old data just has to go
+            return if truncate {
+                self.delete(file_index)
+            } else {
+                Ok(())
+            };
+        }
+        self.forget(file_index);
+        let path = self.path(file_index);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let f = File::options()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(truncate)
+            .open(path)?;
+        match self.allocation {
+            Allocation::Full => preallocate(&f, length),
+            // Size files up front so that pieces can be written anywhere
+            _ if f.metadata()?.len() != length => f.set_len(length),
+            _ => Ok(()),
+        }
+    }
+
+    /// Open handle to write to a file, creating it first when allocating on write
+    fn open_for_write(&mut self, file_index: usize) -> std::io::Result<Arc<File>> {
+        let path = self.path(file_index);
+        if self.allocation == Allocation::OnWrite && !path.exists() {
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            File::options()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(path)?;
+        }
+        self.open(file_index)
+    }
+
+    /// Open handle to a file, reusing a cached one when possible
+    fn open(&mut self, file_index: usize) -> std::io::Result<Arc<File>> {
+        if let Some(pos) = self.handles.iter().position(|(i, _)| *i == file_index) {
+            let entry = self.handles.remove(pos).unwrap();
+            let file = entry.1.clone();
+            self.handles.push_back(entry);
+            return Ok(file);
+        }
+        let file = Arc::new(
+            File::options()
+                .read(true)
+                .write(true)
+                .open(self.path(file_index))?,
+        );
+        if self.handles.len() >= MAX_OPEN_FILES {
+            self.handles.pop_front();
+        }
+        self.handles.push_back((file_index, file.clone()));
+        Ok(file)
+    }
+
+    fn forget(&mut self, file_index: usize) {
+        self.handles.retain(|(i, _)| *i != file_index);
+    }
+
+    /// Move every existing file under `root`. When one can't be moved the
+    /// ones before it go back, so all files stay under the same root.
+    fn move_to(&mut self, root: &Path) -> std::io::Result<()> {
+        self.handles.clear();
+        let mut moved: Vec<(PathBuf, PathBuf)> = Vec::new();
+        for file_index in 0..self.paths.len() {
+            let from = self.path(file_index);
+            if !from.exists() {
+                continue;
+            }
+            let to = root.join(&self.paths[file_index]);
+            if let Err(e) = move_file(&from, &to) {
+                for (from, to) in moved.iter().rev() {
+                    if let Err(e) = move_file(to, from) {
+                        eprintln!("Failed to move {} back: {e}", to.display());
+                    }
+                }
+                return Err(e);
+            }
+            moved.push((from, to));
+        }
+        self.root = root.to_path_buf();
+        Ok(())
+    }
+
+    fn delete(&mut self, file_index: usize) -> std::io::Result<()> {
+        self.forget(file_index);
+        match remove_file(self.path(file_index)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rename a file, copying it instead when `to` is on another filesystem
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent)?;
+    }
+    match rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            // The original only goes once the copy is safely on disk
+            let copied = copy(from, to).and_then(|_| File::open(to)?.sync_all());
+            if let Err(e) = copied {
+                let _ = remove_file(to);
+                return Err(e);
+            }
+            remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Plain files on disk
+#[derive(Debug)]
+pub struct FsStorage {
+    layout: FileLayout,
+    files: Mutex<FileTable>,
+}
+
+impl FsStorage {
+    pub fn new(info: &Info, root: &Path, allocation: Allocation) -> std::io::Result<FsStorage> {
+        Ok(FsStorage {
+            layout: FileLayout::new(info),
+            files: Mutex::new(FileTable::new(info, root, allocation)?),
+        })
+    }
+}
+
+impl Storage for FsStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn allocate(&self, file_index: usize, truncate: bool) -> std::io::Result<()> {
+        let length = self.layout.file_length(file_index);
+        self.files
+            .lock()
+            .unwrap()
+            .create(file_index, length, truncate)?;
+        Ok(())
+    }
+
+    fn read_at(&self, file_index: usize, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        // The lock only covers the cache, I/O happens on the shared handle
+        let f = self.files.lock().unwrap().open(file_index)?;
+        f.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, file_index: usize, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let f = self.files.lock().unwrap().open_for_write(file_index)?;
+        f.write_all_at(data, offset)
+    }
+
+    fn move_to(&self, root: &Path) -> std::io::Result<()> {
+        self.files.lock().unwrap().move_to(root)
+    }
+
+    fn delete(&self, file_index: usize) -> std::io::Result<()> {
+        self.files.lock().unwrap().delete(file_index)
+    }
+}
+
+/// Files on disk accessed through memory maps, mapped on first use
+#[derive(Debug)]
+pub struct MmapStorage {
+    layout: FileLayout,
+    files: Mutex<FileTable>,
+    maps: Mutex<Vec<Option<MmapMut>>>,
+}
+
+impl MmapStorage {
+    /// Files are always allocated in full. A mapping can't grow, and a write
+    /// into a hole of a sparse file on a full disk raises SIGBUS instead of
+    /// returning an error the engine could pause on.
+    pub fn new(info: &Info, root: &Path, _allocation: Allocation) -> std::io::Result<MmapStorage> {
+        let layout = FileLayout::new(info);
+        let maps = (0..layout.file_count()).map(|_| None).collect();
+        Ok(MmapStorage {
+            layout,
+            files: Mutex::new(FileTable::new(info, root, Allocation::Full)?),
+            maps: Mutex::new(maps),
+        })
+    }
+
+    /// Run `f` on the file's mapping, mapping it first if needed
+    fn with_map<T>(
+        &self,
+        file_index: usize,
+        f: impl FnOnce(&mut MmapMut) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let mut maps = self.maps.lock().unwrap();
+        if maps[file_index].is_none() {
+            let mut files = self.files.lock().unwrap();
+            let file = files.open(file_index)?;
+            // The mapping stays valid without the handle
+            files.forget(file_index);
+            // Safety: the engine is the only writer of the torrent's files
+            maps[file_index] = Some(unsafe { MmapMut::map_mut(file.as_ref())? });
+        }
+        f(maps[file_index].as_mut().unwrap())
+    }
+}
+
+fn map_range(map_len: usize, offset: u64, len: usize) -> std::io::Result<std::ops::Range<usize>> {
+    let start = offset as usize;
+    if start + len > map_len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "range exceeds file boundaries",
+        ));
+    }
+    Ok(start..start + len)
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn allocate(&self, file_index: usize, truncate: bool) -> std::io::Result<()> {
+        let mut maps = self.maps.lock().unwrap();
+        maps[file_index] = None;
+        let length = self.layout.file_length(file_index);
+        self.files
+            .lock()
+            .unwrap()
+            .create(file_index, length, truncate)?;
+        Ok(())
+    }
+
+    fn read_at(&self, file_index: usize, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.with_map(file_index, |map| {
+            let range = map_range(map.len(), offset, buf.len())?;
+            buf.copy_from_slice(&map[range]);
+            Ok(())
+        })
+    }
+
+    fn write_at(&self, file_index: usize, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.with_map(file_index, |map| {
+            let range = map_range(map.len(), offset, data.len())?;
+            map[range].copy_from_slice(data);
+            Ok(())
+        })
+    }
+
+    fn move_to(&self, root: &Path) -> std::io::Result<()> {
+        let mut maps = self.maps.lock().unwrap();
+        for map in maps.iter_mut() {
+            if let Some(map) = map.take() {
+                map.flush()?;
+            }
+        }
+        self.files.lock().unwrap().move_to(root)
+    }
+
+    fn delete(&self, file_index: usize) -> std::io::Result<()> {
+        self.maps.lock().unwrap()[file_index] = None;
+        self.files.lock().unwrap().delete(file_index)
+    }
+}
+
+/// Keeps everything in memory, for tests and embedding
+#[derive(Debug)]
+pub struct MemoryStorage {
+    layout: FileLayout,
+    files: Mutex<Vec<Option<Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &Info) -> MemoryStorage {
+        let layout = FileLayout::new(info);
+        MemoryStorage {
+            files: Mutex::new(vec![None; layout.file_count()]),
+            layout,
+        }
+    }
+}
+
+fn not_allocated() -> Error {
+    Error::new(ErrorKind::NotFound, "file is not allocated")
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn allocate(&self, file_index: usize, truncate: bool) -> std::io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        if truncate || files[file_index].is_none() {
+            files[file_index] = Some(vec![0; self.layout.file_length(file_index) as usize]);
+        }
+        Ok(())
+    }
+
+    fn read_at(&self, file_index: usize, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let files = self.files.lock().unwrap();
+        let data = files[file_index].as_ref().ok_or_else(not_allocated)?;
+        let range = map_range(data.len(), offset, buf.len())?;
+        buf.copy_from_slice(&data[range]);
+        Ok(())
+    }
+
+    fn write_at(&self, file_index: usize, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files[file_index].as_mut().ok_or_else(not_allocated)?;
+        let range = map_range(file.len(), offset, data.len())?;
+        file[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn move_to(&self, _root: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, file_index: usize) -> std::io::Result<()> {
+        self.files.lock().unwrap()[file_index] = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::bencode::{File as InfoFile, FileMode};
+
+    fn torrent() -> Info {
+        Info {
+            piece_length: 16,
+            pieces: ByteBuf::from(vec![0; 40]),
+            name: "torrent".into(),
+            private: None,
+            mode: FileMode::MultipleFiles {
+                files: ["a", "b"]
+                    .iter()
+                    .map(|name| InfoFile {
+                        length: 10,
+                        path: vec![(*name).into()],
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    /// Directory removed when the test is done
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("async_torrent-{name}-{}", std::process::id()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn filled(root: &Path) -> FsStorage {
+        let storage = FsStorage::new(&torrent(), root, Allocation::Sparse).unwrap();
+        for file_index in 0..2 {
+            storage.allocate(file_index, true).unwrap();
+            storage
+                .write_at(file_index, 0, &[file_index as u8 + 1; 10])
+                .unwrap();
+        }
+        storage
+    }
+
+    fn read(storage: &FsStorage, file_index: usize) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0; 10];
+        storage.read_at(file_index, 0, &mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn move_keeps_files_readable() {
+        let dir = TempDir::new("move");
+        let storage = filled(&dir.0.join("old"));
+        storage.move_to(&dir.0.join("new")).unwrap();
+
+        assert!(!dir.0.join("old/torrent/a").exists());
+        assert!(dir.0.join("new/torrent/a").exists());
+        assert_eq!(read(&storage, 0).unwrap(), [1; 10]);
+        assert_eq!(read(&storage, 1).unwrap(), [2; 10]);
+    }
+
+    #[test]
+    fn failed_move_puts_moved_files_back() {
+        let dir = TempDir::new("move-back");
+        let storage = filled(&dir.0.join("old"));
+        // A directory in the way of the second file
+        fs::create_dir_all(dir.0.join("new/torrent/b/taken")).unwrap();
+        assert!(storage.move_to(&dir.0.join("new")).is_err());
+
+        assert!(dir.0.join("old/torrent/a").exists());
+        assert!(!dir.0.join("new/torrent/a").exists());
+        assert_eq!(read(&storage, 0).unwrap(), [1; 10]);
+        assert_eq!(read(&storage, 1).unwrap(), [2; 10]);
+    }
+}
@@ -1,24 +1,107 @@
-use std::{error::Error, time::Duration};
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
 
 use serde::Deserialize;
 use serde_bencoded::{from_bytes, to_vec};
-use tokio::time::timeout;
+use tokio::{
+    sync::{mpsc, watch},
+    time::{Instant, sleep_until, timeout},
+};
 
 use crate::{
-    bencode::{FileMode, MetaInfo},
-    engine::network,
-    engine::peers::Peers,
-    utils::{encode_binary, gen_peer_id, sha1_hash},
+    bencode::MetaInfo,
+    engine::connection_manager::{ConnEvent, PeerSource},
+    engine::network::{self, proxy::ProxyConfig, socket::OutgoingInterface},
+    engine::peers::{Peers, Peers6},
+    engine::rate_limiter::TransferCounters,
+    utils::{encode_binary, peer_id, sha1_hash},
 };
 
+// Trackers asking for less (or UDP ones that we don't parse an interval from)
+// are re-announced to no more often than this
+const MIN_ANNOUNCE_INTERVAL: u64 = 5 * 60;
+
 #[allow(unused)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
+    /// Missing from the answer to a stopped announce
+    #[serde(default)]
     pub interval: usize,
+    #[serde(default)]
     pub peers: Peers,
+    #[serde(default)]
+    pub peers6: Peers6,
+}
+
+impl TrackerResponse {
+    /// IPv4 and IPv6 peers together
+    pub fn all_peers(self) -> Vec<SocketAddr> {
+        let mut peers = self.peers.0;
+        peers.extend(self.peers6.0);
+        peers
+    }
+}
+
+/// What happened since the last announce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// A regular re-announce
+    None,
+    Started,
+    /// Every wanted piece is done, sent once per session
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// Value of the `event` query parameter, left out for regular announces
+    fn http_name(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    /// Event field of a UDP announce (BEP 15)
+    fn udp_code(self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
 }
 
-pub async fn fetch_peers(info: &MetaInfo) -> Result<TrackerResponse, Box<dyn Error + Send + Sync>> {
+/// How we announce ourselves, the same on every announce of a session
+#[derive(Debug, Clone)]
+pub struct AnnounceParams {
+    pub port: u16,
+    /// Lets the tracker tell it's still us when our address changes.
+    /// Private trackers expect it to stay the same.
+    pub key: u32,
+    pub proxy: Option<ProxyConfig>,
+    pub outgoing: Option<OutgoingInterface>,
+    /// Payload transferred this session
+    pub counters: Arc<TransferCounters>,
+    /// Bytes of wanted pieces still missing
+    pub left: watch::Receiver<u64>,
+}
+
+pub async fn fetch_peers(
+    info: &MetaInfo,
+    params: &AnnounceParams,
+    event: AnnounceEvent,
+) -> Result<TrackerResponse, Box<dyn Error + Send + Sync>> {
+    let port = params.port;
+    let proxy = params.proxy.as_ref();
+    let outgoing = params.outgoing.as_ref();
     let info_portion = &info.info;
     let raw_hash = to_vec(info_portion)?;
     let info_hash = sha1_hash(&raw_hash);
@@ -32,29 +115,19 @@ pub async fn fetch_peers(info: &MetaInfo) -> Result<TrackerResponse, Box<dyn Err
         trackers.push(info.announce.clone());
     }
 
-    let peer_id = gen_peer_id();
-    let port = 6881;
-    let downloaded: u64 = 0;
-    let uploaded: u64 = 0;
-    let mut left: u64 = 0;
-    match &(info.info.mode) {
-        FileMode::SingleFile { length } => left = *length,
-        FileMode::MultipleFiles { files } => {
-            for file in files {
-                left += file.length;
-            }
-        }
-    }
+    let peer_id = peer_id();
+    let downloaded = params.counters.payload_down.load(Ordering::Relaxed);
+    let uploaded = params.counters.payload_up.load(Ordering::Relaxed);
+    let left = *params.left.borrow();
     let compact = 1;
-    let event = "started";
     let numwant: u32 = 50;
 
     let timeout_dur = 5;
 
     for tracker in trackers {
         if tracker.starts_with("http") {
-            let query = format!(
-                "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}&event={}&numwant={}",
+            let mut query = format!(
+                "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}&numwant={}&key={:08x}",
                 encode_binary(&info_hash),
                 encode_binary(&peer_id),
                 port,
@@ -62,14 +135,18 @@ pub async fn fetch_peers(info: &MetaInfo) -> Result<TrackerResponse, Box<dyn Err
                 downloaded,
                 left,
                 compact,
-                event,
-                numwant
+                numwant,
+                params.key
             );
+            if let Some(event) = event.http_name() {
+                query.push_str(&format!("&event={event}"));
+            }
+            // The passkey may be in the path or the query, both are kept
             let sep = if tracker.contains("?") { "&" } else { "?" };
             let full_url = format!("{}{}{}", tracker, sep, query,);
             let peers_bytes = match timeout(
                 Duration::from_secs(timeout_dur),
-                network::http::get_peers(full_url),
+                network::http::get_peers(full_url, proxy, outgoing),
             )
             .await
             {
@@ -97,7 +174,18 @@ pub async fn fetch_peers(info: &MetaInfo) -> Result<TrackerResponse, Box<dyn Err
             let p = match timeout(
                 Duration::from_secs(timeout_dur),
                 network::udp::get_peers(
-                    tracker, &info_hash, &peer_id, left, port, downloaded, uploaded, numwant,
+                    tracker,
+                    proxy,
+                    outgoing,
+                    &info_hash,
+                    &peer_id,
+                    left,
+                    port,
+                    downloaded,
+                    uploaded,
+                    event.udp_code(),
+                    numwant,
+                    params.key,
                 ),
             )
             .await
@@ -117,6 +205,7 @@ pub async fn fetch_peers(info: &MetaInfo) -> Result<TrackerResponse, Box<dyn Err
             let peers = TrackerResponse {
                 interval: 0,
                 peers: p,
+                peers6: Peers6::default(),
             };
             return Ok(peers);
         }
@@ -124,3 +213,46 @@ pub async fn fetch_peers(info: &MetaInfo) -> Result<TrackerResponse, Box<dyn Err
 
     Err("Failed to fetch peers".into())
 }
+
+/// Re-announce on the tracker's interval and feed new peers to the connection
+/// manager. Finishing the download is announced right away.
+pub async fn announce_loop(
+    info: Arc<MetaInfo>,
+    params: AnnounceParams,
+    mut interval: usize,
+    events: mpsc::Sender<ConnEvent>,
+) {
+    let mut left = params.left.clone();
+    // Nothing to report when we started out complete
+    let mut watch_left = *left.borrow_and_update() != 0;
+    loop {
+        let next =
+            Instant::now() + Duration::from_secs((interval as u64).max(MIN_ANNOUNCE_INTERVAL));
+        let event = loop {
+            tokio::select! {
+                _ = sleep_until(next) => break AnnounceEvent::None,
+                changed = left.changed(), if watch_left => {
+                    if changed.is_err() {
+                        watch_left = false;
+                    } else if *left.borrow_and_update() == 0 {
+                        watch_left = false;
+                        break AnnounceEvent::Completed;
+                    }
+                }
+            }
+        };
+        match fetch_peers(&info, &params, event).await {
+            Ok(resp) => {
+                interval = resp.interval;
+                if events
+                    .send(ConnEvent::AddPeers(resp.all_peers(), PeerSource::Tracker))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Err(e) => eprintln!("Re-announce failed: {e}"),
+        }
+    }
+}
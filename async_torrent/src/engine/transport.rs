@@ -0,0 +1,121 @@
+use std::{
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Byte stream to a peer, whether it runs over TCP or uTP
+pub trait Connection: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Connection for T {}
+
+pub type BoxedConnection = Box<dyn Connection>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Utp,
+}
+
+/// Which transports outgoing connections try, and in what order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportPreference {
+    Tcp,
+    Utp,
+    PreferTcp,
+    PreferUtp,
+}
+
+impl TransportPreference {
+    pub fn order(self) -> &'static [Transport] {
+        match self {
+            TransportPreference::Tcp => &[Transport::Tcp],
+            TransportPreference::Utp => &[Transport::Utp],
+            TransportPreference::PreferTcp => &[Transport::Tcp, Transport::Utp],
+            TransportPreference::PreferUtp => &[Transport::Utp, Transport::Tcp],
+        }
+    }
+
+    pub fn uses_utp(self) -> bool {
+        self != TransportPreference::Tcp
+    }
+}
+
+impl FromStr for TransportPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(TransportPreference::Tcp),
+            "utp" => Ok(TransportPreference::Utp),
+            "prefer-tcp" => Ok(TransportPreference::PreferTcp),
+            "prefer-utp" => Ok(TransportPreference::PreferUtp),
+            _ => Err(format!("Unknown transport {s}")),
+        }
+    }
+}
+
+pub type PeerReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type PeerWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Connection to a peer after any obfuscation layer has been set up.
+/// Peer tasks only see the two halves and don't care what carries them.
+pub struct PeerStream {
+    pub reader: PeerReader,
+    pub writer: PeerWriter,
+}
+
+impl PeerStream {
+    pub fn new(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> PeerStream {
+        PeerStream {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        }
+    }
+
+    /// The bare connection with nothing in between
+    pub fn plain(socket: BoxedConnection) -> PeerStream {
+        let (reader, writer) = tokio::io::split(socket);
+        PeerStream::new(reader, writer)
+    }
+}
+
+/// Reader that hands out bytes already taken off the connection before
+/// reading from it again
+pub struct Prefixed<R> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: R,
+}
+
+impl<R> Prefixed<R> {
+    pub fn new(prefix: Vec<u8>, inner: R) -> Prefixed<R> {
+        Prefixed {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Prefixed<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.prefix.len() {
+            let len = (this.prefix.len() - this.pos).min(buf.remaining());
+            buf.put_slice(&this.prefix[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
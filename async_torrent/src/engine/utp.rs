@@ -0,0 +1,958 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf, duplex},
+    net::UdpSocket,
+    sync::{Mutex as AsyncMutex, mpsc, oneshot},
+    time::{Instant, sleep_until},
+};
+
+use crate::engine::network::socket::{canonical, for_socket};
+
+const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+// Payload per packet, small enough to avoid IP fragmentation on most paths
+const MAX_PAYLOAD: usize = 1200;
+// Bytes buffered between a stream and its connection task in each direction
+const STREAM_BUFFER: usize = 256 * 1024;
+// Receive window we advertise, matching what the stream buffer can hold
+const RECV_WINDOW: u32 = STREAM_BUFFER as u32;
+// Packets waiting for a connection task before new ones are dropped
+const PACKET_QUEUE: usize = 256;
+// Queuing delay LEDBAT aims for, in microseconds
+const TARGET_DELAY: f64 = 100_000.0;
+// Most the congestion window may grow in one round trip
+const MAX_CWND_INCREASE: f64 = 3000.0;
+const INITIAL_WINDOW: f64 = (MAX_PAYLOAD * 4) as f64;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+// Retransmissions of a SYN before giving up on connecting
+const SYN_RETRIES: u32 = 3;
+// Retransmissions of our FIN once the remote closed too. It may have left
+// before its ack got through, and then nobody is left to answer.
+const FIN_RETRIES: u32 = 3;
+// Duplicate acks that mean the packet after the acked one was lost
+const DUP_ACK_LIMIT: u32 = 3;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// How long the other side may stay quiet without closing once our FIN was acked
+const LINGER: Duration = Duration::from_secs(10);
+// Base delay is the lowest delay seen over this many one minute buckets
+const DELAY_HISTORY: usize = 2;
+const EXTENSION_SACK: u8 = 1;
+// Longest selective ack bitmask we send, covering this many bytes times 8 packets
+const MAX_SACK_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<PacketType, ()> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    /// Selective ack bitmask, bit `i` set for packet `ack_nr + 2 + i`
+    sack: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn parse(buf: &[u8]) -> Option<Packet> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0f != VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        let mut extension = buf[1];
+        let mut pos = HEADER_LEN;
+        let mut sack = Vec::new();
+        while extension != 0 {
+            let len = *buf.get(pos + 1)? as usize;
+            let data = buf.get(pos + 2..pos + 2 + len)?;
+            if extension == EXTENSION_SACK {
+                sack = data.to_vec();
+            }
+            extension = buf[pos];
+            pos += 2 + len;
+        }
+        Some(Packet {
+            kind: PacketType::try_from(buf[0] >> 4).ok()?,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: buf.get(pos..)?.to_vec(),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + 2 + self.sack.len() + self.payload.len());
+        buf.push(((self.kind as u8) << 4) | VERSION);
+        buf.push(if self.sack.is_empty() {
+            0
+        } else {
+            EXTENSION_SACK
+        });
+        buf.extend_from_slice(&self.connection_id.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        buf.extend_from_slice(&self.wnd_size.to_be_bytes());
+        buf.extend_from_slice(&self.seq_nr.to_be_bytes());
+        buf.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if !self.sack.is_empty() {
+            buf.extend_from_slice(&[0, self.sack.len() as u8]);
+            buf.extend_from_slice(&self.sack);
+        }
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+/// Microsecond clock for packet timestamps. Only differences matter, so it
+/// starts at zero and wraps.
+fn now_micros() -> u32 {
+    static EPOCH: OnceLock<std::time::Instant> = OnceLock::new();
+    EPOCH
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_micros() as u32
+}
+
+/// Whether sequence number `a` comes before or is `b`, allowing for wrapping
+fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+struct SocketInner {
+    udp: UdpSocket,
+    local: SocketAddr,
+    /// Connection tasks by remote address and the id they receive on
+    connections: Mutex<HashMap<(SocketAddr, u16), mpsc::Sender<Packet>>>,
+    incoming: AsyncMutex<mpsc::Receiver<(DuplexStream, SocketAddr)>>,
+    /// Share of outgoing packets dropped on purpose, to test on loopback
+    #[cfg(test)]
+    loss: Mutex<f64>,
+}
+
+impl SocketInner {
+    async fn send(&self, packet: &Packet, to: SocketAddr) {
+        #[cfg(test)]
+        if rand::random::<f64>() < *self.loss.lock().unwrap() {
+            return;
+        }
+        // A lost datagram is dealt with like any other lost packet
+        let to = for_socket(to, self.local);
+        let _ = self.udp.send_to(&packet.encode(), to).await;
+    }
+}
+
+/// UDP socket carrying any number of uTP connections (BEP 29).
+///
+/// Each connection runs on its own task and is handed out as one end of an
+/// in-memory duplex pipe, so it reads and writes like a TCP stream.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<SocketInner>,
+}
+
+impl UtpSocket {
+    pub fn new(udp: UdpSocket) -> std::io::Result<UtpSocket> {
+        let (incoming_tx, incoming) = mpsc::channel(16);
+        let inner = Arc::new(SocketInner {
+            local: udp.local_addr()?,
+            udp,
+            connections: Mutex::new(HashMap::new()),
+            incoming: AsyncMutex::new(incoming),
+            #[cfg(test)]
+            loss: Mutex::new(0.0),
+        });
+        tokio::spawn(receive_loop(inner.clone(), incoming_tx));
+        Ok(UtpSocket { inner })
+    }
+
+    /// Drop this share of outgoing packets, between 0 and 1
+    #[cfg(test)]
+    pub fn simulate_loss(&self, rate: f64) {
+        *self.inner.loss.lock().unwrap() = rate.clamp(0.0, 1.0);
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<DuplexStream> {
+        let (packets_tx, packets) = mpsc::channel(PACKET_QUEUE);
+        let recv_id = {
+            let mut connections = self.inner.connections.lock().unwrap();
+            let id = loop {
+                let id: u16 = rand::random();
+                let taken = |id| connections.contains_key(&(addr, id));
+                if !taken(id) && !taken(id.wrapping_add(1)) {
+                    break id;
+                }
+            };
+            connections.insert((addr, id), packets_tx);
+            id
+        };
+        let (stream, ours) = duplex(STREAM_BUFFER);
+        let (connected_tx, connected) = oneshot::channel();
+        let conn = Connection::new(
+            self.inner.clone(),
+            addr,
+            recv_id,
+            recv_id.wrapping_add(1),
+            1,
+        );
+        tokio::spawn(conn.run(packets, ours, Some(connected_tx)));
+        connected
+            .await
+            .unwrap_or_else(|_| Err(ErrorKind::ConnectionAborted.into()))?;
+        Ok(stream)
+    }
+
+    pub async fn accept(&self) -> std::io::Result<(DuplexStream, SocketAddr)> {
+        self.inner
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| ErrorKind::NotConnected.into())
+    }
+}
+
+async fn receive_loop(inner: Arc<SocketInner>, incoming: mpsc::Sender<(DuplexStream, SocketAddr)>) {
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let (len, from) = match inner.udp.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("uTP receive failed: {e}");
+                continue;
+            }
+        };
+        let from = canonical(from);
+        let Some(packet) = Packet::parse(&buf[..len]) else {
+            continue;
+        };
+        // A SYN is addressed to the id the initiator receives on, the
+        // connection we made for it receives on the next one
+        let recv_id = match packet.kind {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let known = inner
+            .connections
+            .lock()
+            .unwrap()
+            .get(&(from, recv_id))
+            .cloned();
+        match known {
+            // Dropped when the task falls behind, like on a congested link
+            Some(tx) => {
+                let _ = tx.try_send(packet);
+            }
+            None if packet.kind == PacketType::Syn => {
+                let (packets_tx, packets) = mpsc::channel(PACKET_QUEUE);
+                let (stream, ours) = duplex(STREAM_BUFFER);
+                inner
+                    .connections
+                    .lock()
+                    .unwrap()
+                    .insert((from, recv_id), packets_tx.clone());
+                let mut conn = Connection::new(
+                    inner.clone(),
+                    from,
+                    recv_id,
+                    packet.connection_id,
+                    rand::random(),
+                );
+                conn.state = State::Connected;
+                conn.ack_nr = packet.seq_nr;
+                let _ = packets_tx.try_send(packet);
+                tokio::spawn(conn.run(packets, ours, None));
+                if incoming.try_send((stream, from)).is_err() {
+                    eprintln!("Dropping uTP connection from {from}, nobody is accepting");
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+}
+
+/// Packet sent and not acked yet
+struct Sent {
+    kind: PacketType,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Acked out of order through a selective ack
+    acked: bool,
+    /// Resent because later packets got through, not because of a timeout
+    fast_resent: bool,
+}
+
+/// Lowest one way delay seen recently, which is taken as the delay of the
+/// path without any queues
+#[derive(Default)]
+struct DelayHistory {
+    /// Lowest sample of each minute, newest last
+    buckets: VecDeque<(Instant, u32)>,
+}
+
+impl DelayHistory {
+    fn update(&mut self, sample: u32) -> u32 {
+        let now = Instant::now();
+        match self.buckets.back_mut() {
+            Some((start, min)) if now - *start < Duration::from_secs(60) => {
+                *min = (*min).min(sample);
+            }
+            _ => {
+                self.buckets.push_back((now, sample));
+                if self.buckets.len() > DELAY_HISTORY {
+                    self.buckets.pop_front();
+                }
+            }
+        }
+        self.buckets
+            .iter()
+            .map(|(_, min)| *min)
+            .min()
+            .unwrap_or(sample)
+    }
+}
+
+struct Connection {
+    socket: Arc<SocketInner>,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    /// Sequence number of the next packet we send
+    seq_nr: u16,
+    /// Last sequence number received in order
+    ack_nr: u16,
+    unacked: VecDeque<Sent>,
+    /// Payload bytes sent and not acked yet
+    in_flight: usize,
+    /// Congestion window in bytes, steered by LEDBAT
+    max_window: f64,
+    peer_window: u32,
+    rtt: f64,
+    rtt_var: f64,
+    timeout: Duration,
+    dup_acks: u32,
+    /// When the window was last cut for a lost packet, at most once a round trip
+    last_loss: Instant,
+    /// One way delay of the last packet from the remote, echoed back to it
+    reply_micro: u32,
+    base_delay: DelayHistory,
+    /// Packets that arrived ahead of a missing one
+    out_of_order: HashMap<u16, Packet>,
+    fin_sent: bool,
+    fin_acked_at: Option<Instant>,
+    fin_received: bool,
+    last_received: Instant,
+    last_sent: Instant,
+}
+
+impl Connection {
+    fn new(
+        socket: Arc<SocketInner>,
+        remote: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+        seq_nr: u16,
+    ) -> Connection {
+        let now = Instant::now();
+        Connection {
+            socket,
+            remote,
+            recv_id,
+            send_id,
+            state: State::SynSent,
+            seq_nr,
+            ack_nr: 0,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            max_window: INITIAL_WINDOW,
+            peer_window: RECV_WINDOW,
+            rtt: 0.0,
+            rtt_var: 0.0,
+            timeout: Duration::from_secs(1),
+            dup_acks: 0,
+            last_loss: now,
+            reply_micro: 0,
+            base_delay: DelayHistory::default(),
+            out_of_order: HashMap::new(),
+            fin_sent: false,
+            fin_acked_at: None,
+            fin_received: false,
+            last_received: now,
+            last_sent: now,
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut packets: mpsc::Receiver<Packet>,
+        stream: DuplexStream,
+        mut connected: Option<oneshot::Sender<std::io::Result<()>>>,
+    ) {
+        let (mut app_rx, mut app_tx) = tokio::io::split(stream);
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        if self.state == State::SynSent {
+            self.send_new(PacketType::Syn, Vec::new()).await;
+        }
+
+        let result = loop {
+            if self.fin_received && self.fin_acked_at.is_some() {
+                break Ok(());
+            }
+            if self.state == State::Connected
+                && let Some(tx) = connected.take()
+            {
+                let _ = tx.send(Ok(()));
+            }
+            let can_send = self.state == State::Connected && !self.fin_sent && self.window_open();
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => {
+                        if let Err(e) = self.on_packet(packet, &mut app_tx).await {
+                            break Err(e);
+                        }
+                    }
+                    None => break Ok(()),
+                },
+                read = app_rx.read(&mut buf), if can_send => match read {
+                    Ok(n) if n > 0 => self.send_new(PacketType::Data, buf[..n].to_vec()).await,
+                    // The stream was closed or dropped, nothing more to send
+                    _ => {
+                        self.fin_sent = true;
+                        self.send_new(PacketType::Fin, Vec::new()).await;
+                    }
+                },
+                _ = sleep_until(self.next_deadline()) => {
+                    if let Err(e) = self.on_timeout().await {
+                        break Err(e);
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = &result
+            && e.kind() != ErrorKind::ConnectionReset
+        {
+            self.send_control(PacketType::Reset).await;
+        }
+        if let Some(tx) = connected.take() {
+            let _ = tx.send(result);
+        }
+        self.socket
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&(self.remote, self.recv_id));
+    }
+
+    fn window_open(&self) -> bool {
+        if self.unacked.is_empty() {
+            return true;
+        }
+        // Packets acked out of order still sit in the remote's buffer
+        let buffered = (self.unacked.len() + 1) * MAX_PAYLOAD;
+        self.in_flight + MAX_PAYLOAD <= self.max_window as usize
+            && buffered <= self.peer_window as usize
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let idle = self.last_received + IDLE_TIMEOUT;
+        let next = match self.unacked.front() {
+            Some(oldest) => oldest.sent_at + self.timeout,
+            None => self.last_sent + KEEPALIVE_INTERVAL,
+        };
+        let next = match self.fin_acked_at {
+            Some(at) => next.min(at.max(self.last_received) + LINGER),
+            None => next,
+        };
+        next.min(idle)
+    }
+
+    fn packet(&self, kind: PacketType, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: match kind {
+                // A SYN carries the id we receive on so the remote can derive both
+                PacketType::Syn => self.recv_id,
+                _ => self.send_id,
+            },
+            timestamp: now_micros(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: RECV_WINDOW,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            sack: self.selective_ack(),
+            payload,
+        }
+    }
+
+    /// Tell the remote which packets past a missing one we already have
+    fn selective_ack(&self) -> Vec<u8> {
+        let mut sack = Vec::new();
+        let first = self.ack_nr.wrapping_add(2);
+        for seq_nr in self.out_of_order.keys() {
+            let bit = seq_nr.wrapping_sub(first) as usize;
+            if bit >= MAX_SACK_LEN * 8 {
+                continue;
+            }
+            // The bitmask is sent in multiples of 4 bytes
+            let len = (bit / 32 + 1) * 4;
+            if sack.len() < len {
+                sack.resize(len, 0);
+            }
+            sack[bit / 8] |= 1 << (bit % 8);
+        }
+        sack
+    }
+
+    /// Send a packet that takes a sequence number and has to be acked
+    async fn send_new(&mut self, kind: PacketType, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight += payload.len();
+        let packet = self.packet(kind, seq_nr, payload);
+        self.socket.send(&packet, self.remote).await;
+        self.last_sent = Instant::now();
+        self.unacked.push_back(Sent {
+            kind,
+            seq_nr,
+            payload: packet.payload,
+            sent_at: self.last_sent,
+            transmissions: 1,
+            acked: false,
+            fast_resent: false,
+        });
+    }
+
+    /// Send a state or reset packet, which don't take a sequence number
+    async fn send_control(&mut self, kind: PacketType) {
+        let packet = self.packet(kind, self.seq_nr, Vec::new());
+        self.socket.send(&packet, self.remote).await;
+        self.last_sent = Instant::now();
+    }
+
+    async fn retransmit(&mut self, index: usize) {
+        let Some(sent) = self.unacked.get(index) else {
+            return;
+        };
+        let packet = self.packet(sent.kind, sent.seq_nr, sent.payload.clone());
+        self.socket.send(&packet, self.remote).await;
+        let now = Instant::now();
+        self.last_sent = now;
+        if let Some(sent) = self.unacked.get_mut(index) {
+            sent.sent_at = now;
+            sent.transmissions += 1;
+        }
+    }
+
+    async fn on_packet(
+        &mut self,
+        packet: Packet,
+        app_tx: &mut WriteHalf<DuplexStream>,
+    ) -> std::io::Result<()> {
+        self.last_received = Instant::now();
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size;
+        match packet.kind {
+            PacketType::Reset => return Err(ErrorKind::ConnectionReset.into()),
+            PacketType::Syn => {
+                // Our ack of the SYN may have been lost, so send it again
+                self.send_control(PacketType::State).await;
+                return Ok(());
+            }
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            if packet.kind != PacketType::State {
+                return Ok(());
+            }
+            self.state = State::Connected;
+            // The remote's first data packet reuses the number of its ack
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+        }
+        self.on_ack(&packet).await;
+
+        if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            let next = self.ack_nr.wrapping_add(1);
+            let window = (RECV_WINDOW as usize / MAX_PAYLOAD) as u16;
+            if packet.seq_nr == next {
+                self.deliver(packet, app_tx).await;
+                while let Some(packet) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                    self.deliver(packet, app_tx).await;
+                }
+            } else if !seq_le(packet.seq_nr, self.ack_nr)
+                && packet.seq_nr.wrapping_sub(next) < window
+            {
+                self.out_of_order.insert(packet.seq_nr, packet);
+            }
+            self.send_control(PacketType::State).await;
+        }
+        Ok(())
+    }
+
+    /// Hand an in order packet to the stream
+    async fn deliver(&mut self, packet: Packet, app_tx: &mut WriteHalf<DuplexStream>) {
+        self.ack_nr = packet.seq_nr;
+        // Errors mean the stream is gone and nobody wants the data anymore
+        if packet.kind == PacketType::Fin {
+            self.fin_received = true;
+            let _ = app_tx.shutdown().await;
+        } else if !self.fin_received {
+            let _ = app_tx.write_all(&packet.payload).await;
+        }
+    }
+
+    async fn on_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        let mut ack = |sent: &mut Sent| {
+            if !sent.acked {
+                sent.acked = true;
+                acked += 1;
+                acked_bytes += sent.payload.len();
+                // Round trips of retransmitted packets are ambiguous
+                if sent.transmissions == 1 {
+                    rtt_sample = Some(now - sent.sent_at);
+                }
+            }
+        };
+        let mut progress = false;
+        while let Some(oldest) = self.unacked.front_mut()
+            && seq_le(oldest.seq_nr, packet.ack_nr)
+        {
+            ack(oldest);
+            if oldest.kind == PacketType::Fin {
+                self.fin_acked_at = Some(now);
+            }
+            self.unacked.pop_front();
+            progress = true;
+        }
+        let first = packet.ack_nr.wrapping_add(2);
+        for (i, byte) in packet.sack.iter().enumerate() {
+            for bit in (0..8).filter(|bit| byte & (1 << bit) != 0) {
+                let seq_nr = first.wrapping_add((i * 8 + bit) as u16);
+                if let Some(sent) = self.unacked.iter_mut().find(|s| s.seq_nr == seq_nr) {
+                    ack(sent);
+                }
+            }
+        }
+        self.in_flight -= acked_bytes;
+        self.resend_lost(packet, acked == 0).await;
+        if acked == 0 {
+            return;
+        }
+
+        if let Some(sample) = rtt_sample {
+            let sample = sample.as_micros() as f64;
+            if self.rtt == 0.0 {
+                self.rtt = sample;
+                self.rtt_var = sample / 2.0;
+            } else {
+                self.rtt_var += ((self.rtt - sample).abs() - self.rtt_var) / 4.0;
+                self.rtt += (sample - self.rtt) / 8.0;
+            }
+        }
+        // The remote is answering again, so drop any timeout backoff
+        if progress && self.rtt > 0.0 {
+            self.timeout = Duration::from_micros((self.rtt + self.rtt_var * 4.0) as u64)
+                .clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+        }
+
+        // LEDBAT: grow the window while our packets see less queuing delay
+        // than the target and shrink it once they see more
+        if acked_bytes > 0 && packet.timestamp_diff != 0 {
+            let base = self.base_delay.update(packet.timestamp_diff);
+            let our_delay = packet.timestamp_diff.saturating_sub(base) as f64;
+            let off_target = (TARGET_DELAY - our_delay) / TARGET_DELAY;
+            let window_factor = acked_bytes as f64 / self.max_window;
+            let gain = MAX_CWND_INCREASE * off_target * window_factor;
+            self.max_window = (self.max_window + gain).max(MIN_WINDOW);
+        }
+    }
+
+    /// Resend packets that later ones overtook, either seen through selective
+    /// acks or, for remotes without them, through repeated acks
+    async fn resend_lost(&mut self, packet: &Packet, duplicate: bool) {
+        let mut lost = Vec::new();
+        let mut acked_after = 0;
+        for (index, sent) in self.unacked.iter().enumerate().rev() {
+            if sent.acked {
+                acked_after += 1;
+            } else if acked_after >= DUP_ACK_LIMIT && !sent.fast_resent {
+                lost.push(index);
+            }
+        }
+        if duplicate && packet.kind == PacketType::State && !self.unacked.is_empty() {
+            self.dup_acks += 1;
+            if self.dup_acks == DUP_ACK_LIMIT && !self.unacked[0].fast_resent {
+                lost.push(0);
+            }
+        } else if !duplicate {
+            self.dup_acks = 0;
+        }
+        if lost.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        if now - self.last_loss > Duration::from_micros(self.rtt as u64) {
+            self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+            self.last_loss = now;
+        }
+        for index in lost {
+            self.unacked[index].fast_resent = true;
+            self.retransmit(index).await;
+        }
+    }
+
+    async fn on_timeout(&mut self) -> std::io::Result<()> {
+        let now = Instant::now();
+        if now >= self.last_received + IDLE_TIMEOUT {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        if let Some(at) = self.fin_acked_at
+            && now >= at.max(self.last_received) + LINGER
+        {
+            // The other side never closed, but we're done with it
+            return Err(ErrorKind::TimedOut.into());
+        }
+        match self.unacked.front() {
+            Some(oldest) if now >= oldest.sent_at + self.timeout => {
+                if self.state == State::SynSent && oldest.transmissions > SYN_RETRIES {
+                    return Err(Error::new(ErrorKind::TimedOut, "uTP connect timed out"));
+                }
+                // Everything before the FIN is acked, so the remote has it all
+                if oldest.kind == PacketType::Fin
+                    && self.fin_received
+                    && oldest.transmissions > FIN_RETRIES
+                {
+                    return Err(Error::new(ErrorKind::TimedOut, "uTP close timed out"));
+                }
+                // Treated as heavy congestion, start over from the smallest window
+                self.max_window = MIN_WINDOW;
+                self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+                self.retransmit(0).await;
+            }
+            Some(_) => {}
+            None if now >= self.last_sent + KEEPALIVE_INTERVAL => {
+                self.send_control(PacketType::State).await;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(kind: PacketType, sack: Vec<u8>, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: 0xbeef,
+            timestamp: 123_456,
+            timestamp_diff: 789,
+            wnd_size: RECV_WINDOW,
+            seq_nr: 65_535,
+            ack_nr: 42,
+            sack,
+            payload,
+        }
+    }
+
+    async fn socket() -> UtpSocket {
+        UtpSocket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        for (sack, payload) in [
+            (vec![], vec![]),
+            (vec![], b"piece data".to_vec()),
+            (vec![0b101, 0, 0, 0x80], b"more".to_vec()),
+        ] {
+            let sent = packet(PacketType::Data, sack, payload);
+            let encoded = sent.encode();
+            assert_eq!(encoded[0], 0x01);
+            let parsed = Packet::parse(&encoded).unwrap();
+            assert_eq!(parsed.kind, sent.kind);
+            assert_eq!(parsed.connection_id, sent.connection_id);
+            assert_eq!(parsed.timestamp, sent.timestamp);
+            assert_eq!(parsed.timestamp_diff, sent.timestamp_diff);
+            assert_eq!(parsed.wnd_size, sent.wnd_size);
+            assert_eq!(parsed.seq_nr, sent.seq_nr);
+            assert_eq!(parsed.ack_nr, sent.ack_nr);
+            assert_eq!(parsed.sack, sent.sack);
+            assert_eq!(parsed.payload, sent.payload);
+        }
+    }
+
+    #[test]
+    fn packet_parse_rejects_bad_input() {
+        let encoded = packet(PacketType::Fin, vec![1, 0, 0, 0], vec![]).encode();
+        // Short header, wrong version, unknown type, cut off extension
+        assert!(Packet::parse(&encoded[..HEADER_LEN - 1]).is_none());
+        let mut bad = encoded.clone();
+        bad[0] = (PacketType::Fin as u8) << 4 | 2;
+        assert!(Packet::parse(&bad).is_none());
+        bad[0] = 7 << 4 | VERSION;
+        assert!(Packet::parse(&bad).is_none());
+        assert!(Packet::parse(&encoded[..HEADER_LEN + 3]).is_none());
+    }
+
+    #[test]
+    fn packet_parse_skips_unknown_extensions() {
+        let mut encoded = packet(PacketType::State, vec![], b"x".to_vec()).encode();
+        encoded[1] = 9;
+        encoded.splice(HEADER_LEN..HEADER_LEN, [EXTENSION_SACK, 2, 0xaa, 0xbb]);
+        encoded.splice(HEADER_LEN + 4..HEADER_LEN + 4, [0, 4, 1, 0, 0, 0]);
+        let parsed = Packet::parse(&encoded).unwrap();
+        assert_eq!(parsed.sack, [1, 0, 0, 0]);
+        assert_eq!(parsed.payload, b"x");
+    }
+
+    #[tokio::test]
+    async fn selective_ack_bitmask() {
+        let socket = socket().await;
+        let remote = "127.0.0.1:9".parse().unwrap();
+        let mut conn = Connection::new(socket.inner.clone(), remote, 1, 2, 1);
+        conn.ack_nr = 10;
+        assert!(conn.selective_ack().is_empty());
+
+        // Bit 0 is ack_nr + 2, the packet right after the missing one
+        for seq_nr in [12, 13, 20, 50, 10 + 2 + 256] {
+            conn.out_of_order
+                .insert(seq_nr, packet(PacketType::Data, vec![], vec![]));
+        }
+        let sack = conn.selective_ack();
+        assert_eq!(sack, [0b11, 0b1, 0, 0, 0b100_0000, 0, 0, 0]);
+
+        // Sequence numbers wrap
+        conn.ack_nr = 65_534;
+        conn.out_of_order.clear();
+        conn.out_of_order
+            .insert(1, packet(PacketType::Data, vec![], vec![]));
+        assert_eq!(conn.selective_ack(), [0b10, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn selective_ack_marks_sent_packets() {
+        let socket = socket().await;
+        let remote = "127.0.0.1:9".parse().unwrap();
+        let mut conn = Connection::new(socket.inner.clone(), remote, 1, 2, 100);
+        conn.state = State::Connected;
+        for _ in 0..6 {
+            conn.send_new(PacketType::Data, vec![0; 10]).await;
+        }
+        // 100 and 101 arrived, 102 is missing, 103 and 105 got through after it
+        let mut ack = packet(PacketType::State, vec![0b101, 0, 0, 0], vec![]);
+        ack.ack_nr = 101;
+        conn.on_ack(&ack).await;
+
+        let acked: Vec<(u16, bool)> = conn.unacked.iter().map(|s| (s.seq_nr, s.acked)).collect();
+        assert_eq!(
+            acked,
+            [(102, false), (103, true), (104, false), (105, true)]
+        );
+        assert_eq!(conn.in_flight, 20);
+    }
+
+    /// Send `data` and close, while reading everything the other side sends
+    async fn exchange(stream: DuplexStream, data: Vec<u8>) -> Vec<u8> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let send = async {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let receive = async {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        tokio::join!(send, receive).1
+    }
+
+    #[tokio::test]
+    async fn transfer_over_lossy_loopback() {
+        const LEN: usize = 3 * 1024 * 1024;
+        let ours = socket().await;
+        let theirs = socket().await;
+        ours.simulate_loss(0.02);
+        theirs.simulate_loss(0.02);
+
+        let up: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+        let down: Vec<u8> = (0..LEN).map(|i| (i % 241) as u8).rev().collect();
+        let addr = theirs.inner.local;
+        let (connected, accepted) = tokio::join!(ours.connect(addr), theirs.accept());
+        let (client, server) = tokio::join!(
+            exchange(connected.unwrap(), up.clone()),
+            exchange(accepted.unwrap().0, down.clone())
+        );
+        assert!(client == down, "download corrupted");
+        assert!(server == up, "upload corrupted");
+
+        // Both connection tasks end once the FINs are through
+        let closed = async {
+            while [&ours, &theirs]
+                .iter()
+                .any(|s| !s.inner.connections.lock().unwrap().is_empty())
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(15), closed)
+            .await
+            .expect("connections never closed");
+    }
+}
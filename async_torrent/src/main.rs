@@ -1,14 +1,13 @@
 mod app;
 mod bencode;
+mod cli;
 mod engine;
 mod tui;
 mod utils;
 
-use anyhow;
 use std::env;
 use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
 
 fn redirect_stderr() {
     let file = OpenOptions::new()
@@ -25,12 +24,27 @@ fn redirect_stderr() {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <path_to_torrent>", args[0]);
+    let args = match cli::parse_args(&args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{e}\n{}", cli::usage(&args[0]));
+            std::process::exit(1);
+        }
+    };
+    let info = bencode::decode_bencode(args.torrent_path).unwrap();
+    let checks = engine::paths::check_paths(&info.info)
+        .and_then(|_| engine::storage::check_free_space(&info.info, &args.engine));
+    if let Err(e) = checks {
+        eprintln!("{e}");
         std::process::exit(1);
     }
-    let torrent_path: PathBuf = PathBuf::from(&args[1]);
+    let ip_filter = match engine::ip_filter::IpFilterControl::open(args.engine.ip_filter.clone()) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
     redirect_stderr();
-    let info = bencode::decode_bencode(torrent_path).unwrap();
-    app::run_tui(info).await
+    app::run_tui(info, args.engine, ip_filter).await
 }
@@ -3,10 +3,15 @@ use std::{
     sync::{Arc, RwLock},
 };
 
+use std::time::Duration;
+
 use tokio::sync::mpsc;
 
 use crate::engine::events::UiEvent;
 
+// One rate sample per second, so this is the last three minutes
+const RATE_HISTORY_LEN: usize = 180;
+
 #[derive(Clone, Copy)]
 pub enum PieceState {
     Missing,
@@ -16,21 +21,112 @@ pub enum PieceState {
 }
 
 pub struct PeerStatus {
-    pub peer_id: String,
+    pub address: String,
+    /// Client name and version, empty until the handshake is done
+    pub client: String,
     pub task: Vec<String>,
     pub choked: bool,
+    pub download_rate: u64,
+    pub upload_rate: u64,
 }
 
 pub struct AppState {
     pub pieces: Vec<PieceState>,
     pub peers: VecDeque<PeerStatus>,
+    pub total_size: u64,
+    pub piece_length: u64,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Recent download rates, oldest first
+    pub rate_history: VecDeque<u64>,
+    /// Disk jobs waiting or running and their average duration
+    pub disk_queued: usize,
+    pub disk_latency: Duration,
+    /// Bytes held by the piece cache and its hits and misses so far
+    pub cache_used: usize,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
 }
 
 impl AppState {
-    pub fn new(num_pieces: usize) -> Self {
+    pub fn new(num_pieces: usize, total_size: u64, piece_length: u64) -> Self {
         AppState {
             pieces: vec![PieceState::Missing; num_pieces],
             peers: VecDeque::new(),
+            total_size,
+            piece_length,
+            download_rate: 0,
+            upload_rate: 0,
+            downloaded: 0,
+            uploaded: 0,
+            rate_history: VecDeque::with_capacity(RATE_HISTORY_LEN),
+            disk_queued: 0,
+            disk_latency: Duration::ZERO,
+            cache_used: 0,
+            cache_hits: 0,
+            cache_misses: 0,
+        }
+    }
+
+    fn piece_len(&self, index: usize) -> u64 {
+        if index == self.pieces.len().saturating_sub(1) {
+            self.total_size - index as u64 * self.piece_length
+        } else {
+            self.piece_length
+        }
+    }
+
+    /// Bytes of the pieces `wanted` says are wanted, one flag per piece
+    pub fn wanted_bytes(&self, wanted: &[bool]) -> u64 {
+        (0..self.pieces.len())
+            .filter(|&i| wanted[i])
+            .map(|i| self.piece_len(i))
+            .sum()
+    }
+
+    /// Bytes of verified wanted pieces
+    pub fn completed_bytes(&self, wanted: &[bool]) -> u64 {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| wanted[*i] && matches!(p, PieceState::Complete))
+            .map(|(i, _)| self.piece_len(i))
+            .sum()
+    }
+
+    /// Time left at the current download rate to get every wanted piece,
+    /// `None` while stalled
+    pub fn eta(&self, wanted: &[bool]) -> Option<Duration> {
+        let remaining = self
+            .wanted_bytes(wanted)
+            .saturating_sub(self.completed_bytes(wanted));
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        if self.download_rate == 0 {
+            return None;
+        }
+        Some(Duration::from_secs(remaining.div_ceil(self.download_rate)))
+    }
+
+    /// Share of block reads served from the piece cache
+    pub fn cache_hit_rate(&self) -> f64 {
+        let reads = self.cache_hits + self.cache_misses;
+        if reads == 0 {
+            0.0
+        } else {
+            self.cache_hits as f64 / reads as f64
+        }
+    }
+
+    /// Uploaded over downloaded payload
+    pub fn ratio(&self) -> f64 {
+        if self.downloaded == 0 {
+            0.0
+        } else {
+            self.uploaded as f64 / self.downloaded as f64
         }
     }
 }
@@ -48,26 +144,105 @@ pub async fn process_event(app_state: Arc<RwLock<AppState>>, ui_rx: &mut mpsc::R
                 UiEvent::PieceCompleted(index) => {
                     state.pieces[index] = PieceState::Complete;
                 }
-                UiEvent::PeerUpdate {
-                    peer_id,
-                    task,
-                    choked,
-                } => {
-                    if let Some(peer) = state.peers.iter_mut().find(|p| p.peer_id == peer_id) {
+                UiEvent::PeerUpdate { peer, task, choked } => {
+                    if let Some(peer) = state.peers.iter_mut().find(|p| p.address == peer) {
                         peer.task.push(task);
                         peer.choked = choked;
                     } else {
                         state.peers.push_back(PeerStatus {
-                            peer_id,
+                            address: peer,
+                            client: String::new(),
                             task: vec![task],
                             choked,
+                            download_rate: 0,
+                            upload_rate: 0,
                         });
                     }
                 }
-                UiEvent::PeerDisconnected(peer_id) => {
-                    state.peers.retain(|p| p.peer_id != peer_id);
+                UiEvent::PeerDisconnected(peer) => {
+                    state.peers.retain(|p| p.address != peer);
+                }
+                UiEvent::PeerClient { peer, client } => {
+                    if let Some(peer) = state.peers.iter_mut().find(|p| p.address == peer) {
+                        peer.client = client;
+                    }
+                }
+                UiEvent::PeerRates {
+                    peer,
+                    download_rate,
+                    upload_rate,
+                } => {
+                    if let Some(peer) = state.peers.iter_mut().find(|p| p.address == peer) {
+                        peer.download_rate = download_rate;
+                        peer.upload_rate = upload_rate;
+                    }
+                }
+                UiEvent::TransferRates {
+                    download_rate,
+                    upload_rate,
+                    downloaded,
+                    uploaded,
+                } => {
+                    state.download_rate = download_rate;
+                    state.upload_rate = upload_rate;
+                    state.downloaded = downloaded;
+                    state.uploaded = uploaded;
+                    if state.rate_history.len() == RATE_HISTORY_LEN {
+                        state.rate_history.pop_front();
+                    }
+                    state.rate_history.push_back(download_rate);
+                }
+                UiEvent::DiskStats {
+                    queued,
+                    latency,
+                    cache_used,
+                    cache_hits,
+                    cache_misses,
+                } => {
+                    state.disk_queued = queued;
+                    state.disk_latency = latency;
+                    state.cache_used = cache_used;
+                    state.cache_hits = cache_hits;
+                    state.cache_misses = cache_misses;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Four pieces of 10 bytes and a last one of 5
+    fn state() -> AppState {
+        AppState::new(5, 45, 10)
+    }
+
+    #[test]
+    fn progress_counts_only_wanted_pieces() {
+        let mut state = state();
+        let wanted = [true, true, false, false, true];
+        assert_eq!(state.wanted_bytes(&wanted), 25);
+        assert_eq!(state.wanted_bytes(&[true; 5]), 45);
+
+        state.pieces[2] = PieceState::Complete;
+        state.pieces[4] = PieceState::Complete;
+        assert_eq!(state.completed_bytes(&wanted), 5);
+        assert_eq!(state.completed_bytes(&[true; 5]), 15);
+    }
+
+    #[test]
+    fn eta_reaches_zero_with_skipped_pieces_missing() {
+        let mut state = state();
+        let wanted = [true, true, false, false, false];
+        assert_eq!(state.eta(&wanted), None);
+        state.download_rate = 4;
+        assert_eq!(state.eta(&wanted), Some(Duration::from_secs(5)));
+
+        state.pieces[0] = PieceState::Complete;
+        state.pieces[1] = PieceState::Complete;
+        state.download_rate = 0;
+        assert_eq!(state.eta(&wanted), Some(Duration::ZERO));
+    }
+}
@@ -1,2 +1,2 @@
-pub mod ui;
 pub mod app_state;
+pub mod ui;
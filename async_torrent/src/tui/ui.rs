@@ -1,4 +1,5 @@
 use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
@@ -14,14 +15,37 @@ use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style},
     text::Span,
-    widgets::{Block, Borders, Paragraph},
+    widgets::{Block, Borders, Paragraph, Sparkline},
 };
 
 use crate::bencode::MetaInfo;
+use crate::engine::EngineHandles;
+use crate::engine::files::{FilePriorities, FilePriority, file_count, piece_priorities};
+use crate::engine::ip_filter::IpFilterControl;
+use crate::engine::piece_picker::{Deadline, StreamControl};
+use crate::engine::rate_limiter::{Bandwidth, RateLimiter};
 use crate::tui::app_state::{AppState, PeerStatus, PieceState};
 
+// Step used when raising or lowering a rate limit from the keyboard
+const LIMIT_STEP: u64 = 64 * 1024;
+const LIMIT_NAMES: [&str; 4] = ["global down", "global up", "torrent down", "torrent up"];
+
+fn selected_limiter(bandwidth: &Bandwidth, selected: usize) -> &RateLimiter {
+    match selected {
+        0 => &bandwidth.global.download,
+        1 => &bandwidth.global.upload,
+        2 => &bandwidth.torrent.download,
+        _ => &bandwidth.torrent.upload,
+    }
+}
+
 /// Main TUI run loop
-pub fn run(state: Arc<RwLock<AppState>>, info: MetaInfo) -> anyhow::Result<()> {
+pub fn run(
+    state: Arc<RwLock<AppState>>,
+    info: MetaInfo,
+    handles: EngineHandles,
+) -> anyhow::Result<()> {
+    let bandwidth = &handles.bandwidth;
     enable_raw_mode()?;
     let mut stdout = std::io::stdout();
     execute!(stdout, EnterAlternateScreen)?;
@@ -32,25 +56,77 @@ pub fn run(state: Arc<RwLock<AppState>>, info: MetaInfo) -> anyhow::Result<()> {
     let mut last_tick = Instant::now();
     let mut peer_scroll: usize = 0;
     let mut files_scroll: usize = 0;
+    let mut selected_limit: usize = 0;
 
     loop {
-        terminal.draw(|f| draw_ui(f, &state, peer_scroll, files_scroll, &info))?;
+        terminal.draw(|f| {
+            draw_ui(
+                f,
+                &state,
+                peer_scroll,
+                files_scroll,
+                &info,
+                &handles,
+                selected_limit,
+            )
+        })?;
 
         // Event handling
         let timeout = tick_rate
             .checked_sub(last_tick.elapsed())
             .unwrap_or_else(|| Duration::from_secs(0));
 
-        if event::poll(timeout)? {
-            if let CEvent::Key(KeyEvent { code, .. }) = event::read()? {
-                match code {
-                    KeyCode::Char('q') => break, // quit
-                    KeyCode::Up => peer_scroll = peer_scroll.saturating_sub(3),
-                    KeyCode::Down => peer_scroll = peer_scroll.saturating_add(3),
-                    KeyCode::Char('p') => files_scroll = files_scroll.saturating_sub(1),
-                    KeyCode::Char('n') => files_scroll = files_scroll.saturating_add(1),
-                    _ => {}
+        if event::poll(timeout)?
+            && let CEvent::Key(KeyEvent { code, .. }) = event::read()?
+        {
+            match code {
+                KeyCode::Char('q') => break, // quit
+                KeyCode::Up => peer_scroll = peer_scroll.saturating_sub(3),
+                KeyCode::Down => peer_scroll = peer_scroll.saturating_add(3),
+                KeyCode::Char('p') => files_scroll = files_scroll.saturating_sub(1),
+                KeyCode::Char('n') => {
+                    files_scroll = (files_scroll + 1).min(file_count(&info.info).saturating_sub(1))
+                }
+                KeyCode::Char('f') => {
+                    // The file at the top of the Files panel is the selected one
+                    let priorities = &handles.file_priorities;
+                    if let Some(p) = priorities.get(files_scroll) {
+                        priorities.set(files_scroll, p.next());
+                    }
+                }
+                KeyCode::Char('s') => {
+                    let stream = &handles.stream;
+                    stream.set_sequential(!stream.sequential());
+                }
+                KeyCode::Char('d') => {
+                    // Stream the selected file from its start, or stop streaming it
+                    let stream = &handles.stream;
+                    let deadline = match stream.deadline() {
+                        Some(d) if d.file_index == files_scroll => None,
+                        _ => Some(Deadline {
+                            file_index: files_scroll,
+                            offset: 0,
+                        }),
+                    };
+                    stream.set_deadline(deadline);
+                }
+                KeyCode::Char('r') => handles.pause.resume(),
+                KeyCode::Char('b') => {
+                    // Big lists take a moment to parse, keep the UI drawing
+                    let ip_filter = handles.ip_filter.clone();
+                    std::thread::spawn(move || ip_filter.reload());
+                }
+                KeyCode::Char('l') => selected_limit = (selected_limit + 1) % LIMIT_NAMES.len(),
+                KeyCode::Char('+') | KeyCode::Char('=') => {
+                    let limiter = selected_limiter(bandwidth, selected_limit);
+                    limiter.set_rate(limiter.rate().saturating_add(LIMIT_STEP));
                 }
+                KeyCode::Char('-') => {
+                    let limiter = selected_limiter(bandwidth, selected_limit);
+                    limiter.set_rate(limiter.rate().saturating_sub(LIMIT_STEP));
+                }
+                KeyCode::Char('0') => selected_limiter(bandwidth, selected_limit).set_rate(0),
+                _ => {}
             }
         }
 
@@ -72,12 +148,14 @@ fn draw_ui(
     peer_scroll: usize,
     files_scroll: usize,
     info: &MetaInfo,
+    handles: &EngineHandles,
+    selected_limit: usize,
 ) {
     let app = state.read().unwrap();
     let area = f.area();
 
     let cols = area.width.max(1) as usize;
-    let rows = (app.pieces.len() + cols - 1) / cols;
+    let rows = app.pieces.len().div_ceil(cols);
     let piece_height = rows as u16 + 2;
 
     let chunks = Layout::default()
@@ -85,14 +163,172 @@ fn draw_ui(
         .margin(1)
         .constraints([
             Constraint::Max(5),
+            Constraint::Length(8),
             Constraint::Length(piece_height),
             Constraint::Min(5),
         ])
         .split(area);
 
-    draw_torrent_info(f, chunks[0], info, files_scroll);
-    draw_piece_map(f, chunks[1], &app.pieces);
-    draw_peer_panel(f, chunks[2], &app.peers, peer_scroll);
+    draw_torrent_info(f, chunks[0], info, &handles.file_priorities, files_scroll);
+    let wanted: Vec<bool> = piece_priorities(&info.info, &handles.file_priorities.snapshot())
+        .iter()
+        .map(|p| *p != FilePriority::Skip)
+        .collect();
+    draw_transfer_panel(f, chunks[1], &app, &wanted, handles, selected_limit);
+    draw_piece_map(f, chunks[2], &app.pieces);
+    draw_peer_panel(f, chunks[3], &app.peers, peer_scroll);
+}
+
+fn format_limit(limiter: &RateLimiter) -> String {
+    match limiter.rate() {
+        0 => "unlimited".to_string(),
+        rate => format!("{}/s", bytesize::ByteSize(rate)),
+    }
+}
+
+fn format_rate(rate: u64) -> String {
+    format!("{}/s", bytesize::ByteSize(rate))
+}
+
+fn format_eta(eta: Option<Duration>) -> String {
+    let Some(eta) = eta else {
+        return "∞".to_string();
+    };
+    let secs = eta.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+fn download_order_line(stream: &StreamControl) -> Line<'static> {
+    let order = if stream.sequential() {
+        "sequential"
+    } else {
+        "rarest first"
+    };
+    let streaming = match stream.deadline() {
+        Some(d) => format!("file {} at {}", d.file_index, bytesize::ByteSize(d.offset)),
+        None => "off".to_string(),
+    };
+    Line::from(vec![
+        Span::raw(format!("Order: {order}   Streaming: {streaming}  ")),
+        Span::styled(
+            "[s] order [d] stream selected file",
+            Style::default().fg(Color::DarkGray),
+        ),
+    ])
+}
+
+fn ip_filter_line(ip_filter: &IpFilterControl) -> Line<'static> {
+    if !ip_filter.is_enabled() {
+        return Line::from("IP filter: off");
+    }
+    let mut spans = vec![Span::raw(format!(
+        "IP filter: {} ranges   Blocked: {} outgoing, {} incoming  ",
+        ip_filter.ranges(),
+        ip_filter.blocked_outgoing.load(Ordering::Relaxed),
+        ip_filter.blocked_incoming.load(Ordering::Relaxed),
+    ))];
+    if let Some(error) = ip_filter.error() {
+        spans.push(Span::styled(
+            format!("(reload failed: {error}) "),
+            Style::default().fg(Color::Red),
+        ));
+    }
+    spans.push(Span::styled(
+        "[b] reload",
+        Style::default().fg(Color::DarkGray),
+    ));
+    Line::from(spans)
+}
+
+fn draw_transfer_panel(
+    f: &mut ratatui::Frame,
+    area: Rect,
+    app: &AppState,
+    wanted: &[bool],
+    handles: &EngineHandles,
+    selected: usize,
+) {
+    let bandwidth = &handles.bandwidth;
+    let title = match handles.pause.reason() {
+        Some(reason) => Line::styled(
+            format!("Transfer (paused: {reason}, [r] resume)"),
+            Style::default().fg(Color::Red),
+        ),
+        None => Line::from("Transfer"),
+    };
+    let outer = Block::new().title(title).borders(Borders::ALL);
+    let inner = outer.inner(area);
+    f.render_widget(outer, area);
+    let layout = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+        .split(inner);
+
+    let counters = &bandwidth.counters;
+    let size = |v: &std::sync::atomic::AtomicU64| bytesize::ByteSize(v.load(Ordering::Relaxed));
+
+    let mut limits = vec![Span::raw("Limits: ")];
+    for (i, name) in LIMIT_NAMES.iter().enumerate() {
+        let text = format!("{name} {}  ", format_limit(selected_limiter(bandwidth, i)));
+        let style = if i == selected {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default()
+        };
+        limits.push(Span::styled(text, style));
+    }
+    limits.push(Span::styled(
+        "[l] select [+/-] change [0] unlimited",
+        Style::default().fg(Color::DarkGray),
+    ));
+
+    let para = Paragraph::new(vec![
+        Line::from(format!(
+            "Down: {} ({})   Up: {} ({})",
+            format_rate(app.download_rate),
+            bytesize::ByteSize(app.downloaded),
+            format_rate(app.upload_rate),
+            bytesize::ByteSize(app.uploaded),
+        )),
+        Line::from(format!(
+            "Done: {} / {}   Ratio: {:.2}   ETA: {}",
+            bytesize::ByteSize(app.completed_bytes(wanted)),
+            bytesize::ByteSize(app.wanted_bytes(wanted)),
+            app.ratio(),
+            format_eta(app.eta(wanted)),
+        )),
+        Line::from(format!(
+            "Overhead: {} down, {} up   Disk: {} queued, {:.1} ms   Cache: {}, {:.0}% hits",
+            size(&counters.overhead_down),
+            size(&counters.overhead_up),
+            app.disk_queued,
+            app.disk_latency.as_secs_f64() * 1000.0,
+            bytesize::ByteSize(app.cache_used as u64),
+            app.cache_hit_rate() * 100.0,
+        )),
+        Line::from(limits),
+        download_order_line(&handles.stream),
+        ip_filter_line(&handles.ip_filter),
+    ]);
+    f.render_widget(para, layout[0]);
+
+    // Only the most recent samples that fit in the panel
+    let width = layout[1].width.saturating_sub(2) as usize;
+    let history: Vec<u64> = app
+        .rate_history
+        .iter()
+        .skip(app.rate_history.len().saturating_sub(width))
+        .copied()
+        .collect();
+    let sparkline = Sparkline::default()
+        .block(Block::new().title("Download rate").borders(Borders::ALL))
+        .data(&history)
+        .style(Style::default().fg(Color::Cyan));
+    f.render_widget(sparkline, layout[1]);
 }
 
 fn format_file_line(name: &str, length: u64, max_width: usize) -> String {
@@ -107,7 +343,32 @@ fn format_file_line(name: &str, length: u64, max_width: usize) -> String {
     }
 }
 
-fn draw_torrent_info(f: &mut ratatui::Frame, area: Rect, info: &MetaInfo, files_scroll: usize) {
+fn file_line(
+    name: &str,
+    length: u64,
+    priority: Option<FilePriority>,
+    selected: bool,
+    max_width: usize,
+) -> Line<'static> {
+    let priority = priority.unwrap_or(FilePriority::Normal);
+    let name = format!("[{}] {}", priority.label(), name);
+    let mut style = Style::default();
+    if priority == FilePriority::Skip {
+        style = style.fg(Color::DarkGray);
+    }
+    if selected {
+        style = style.fg(Color::Yellow);
+    }
+    Line::styled(format_file_line(&name, length, max_width), style)
+}
+
+fn draw_torrent_info(
+    f: &mut ratatui::Frame,
+    area: Rect,
+    info: &MetaInfo,
+    priorities: &FilePriorities,
+    files_scroll: usize,
+) {
     let layout = Layout::default()
         .direction(Direction::Horizontal)
         .constraints(vec![Constraint::Percentage(50); 2])
@@ -127,20 +388,34 @@ fn draw_torrent_info(f: &mut ratatui::Frame, area: Rect, info: &MetaInfo, files_
     ])
     .block(b);
     f.render_widget(para, layout[0]);
-    let b = Block::new().title("Files").borders(Borders::ALL);
+    let b = Block::new()
+        .title("Files [p/n] select [f] priority")
+        .borders(Borders::ALL);
     let w = layout[1].width - 2;
     let para = match &info.info.mode {
         crate::bencode::FileMode::SingleFile { length } => {
-            let line = format_file_line(&info.info.name, *length, w as usize);
-            Paragraph::new(vec![Line::from(line)]).block(b)
+            let line = file_line(
+                &info.info.name.to_string(),
+                *length,
+                priorities.get(0),
+                true,
+                w as usize,
+            );
+            Paragraph::new(vec![line]).block(b)
         }
         crate::bencode::FileMode::MultipleFiles { files } => {
             let mut lines = Vec::new();
             let start = files_scroll.min(files.len().saturating_sub(1));
-            for file in files.iter().skip(start) {
-                let name = file.path.join("/");
-                let line = format_file_line(&name, file.length, w as usize);
-                lines.push(Line::from(line));
+            for (i, file) in files.iter().enumerate().skip(start) {
+                let name = file.display_path();
+                let line = file_line(
+                    &name,
+                    file.length,
+                    priorities.get(i),
+                    i == start,
+                    w as usize,
+                );
+                lines.push(line);
             }
             Paragraph::new(lines).block(b)
         }
@@ -237,9 +512,17 @@ fn draw_peer_box(f: &mut ratatui::Frame, area: Rect, peer: &PeerStatus) {
     }
     // let text = vec![Line::from(peer.task.clone())];
 
-    let block = Block::default()
-        .borders(Borders::ALL)
-        .title(peer.peer_id.clone());
+    let name = if peer.client.is_empty() {
+        peer.address.clone()
+    } else {
+        format!("{} {}", peer.address, peer.client)
+    };
+    let block = Block::default().borders(Borders::ALL).title(format!(
+        "{} ↓{} ↑{}",
+        name,
+        format_rate(peer.download_rate),
+        format_rate(peer.upload_rate)
+    ));
 
     let paragraph = Paragraph::new(text).block(block);
     f.render_widget(paragraph, area);
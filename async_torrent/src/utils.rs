@@ -1,24 +1,72 @@
+use std::sync::OnceLock;
+
 use rand::Rng;
 use sha1::{Digest, Sha1};
 
-pub fn gen_peer_id() -> [u8; 20] {
-    let mut id = *b"-RS0001-000000000000";
-    let mut rng = rand::rng();
-    for i in id.iter_mut().skip(8) {
-        *i = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz"
-            [rng.random_range(0..62)];
-    }
-    id
+/// Our peer id, generated once so trackers and peers all see the same one.
+/// Azureus style, `-RS0100-` followed by random characters for 0.1.0.
+pub fn peer_id() -> [u8; 20] {
+    static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+    *PEER_ID.get_or_init(|| {
+        let mut id = *b"-RS0000-000000000000";
+        let version = [
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ];
+        for (i, part) in version.iter().enumerate() {
+            id[3 + i] = version_char(part.parse().unwrap_or(0));
+        }
+        let mut rng = rand::rng();
+        for i in id.iter_mut().skip(8) {
+            *i = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz"
+                [rng.random_range(0..62)];
+        }
+        id
+    })
+}
+
+/// Version numbers up to 35 fit in one character, 0-9 then A-Z
+fn version_char(n: u32) -> u8 {
+    char::from_digit(n.min(35), 36).map_or(b'0', |c| c.to_ascii_uppercase() as u8)
 }
 
 pub fn sha1_hash(bytes: &[u8]) -> [u8; 20] {
     let mut hasher = Sha1::new();
     hasher.update(bytes);
-    let hash = hasher.finalize().into();
-    hash
+    hasher.finalize().into()
 }
 
 pub fn encode_binary(data: &[u8]) -> String {
     use url::form_urlencoded::byte_serialize;
     byte_serialize(data).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::client_id::client_name;
+
+    #[test]
+    fn peer_id_is_stable() {
+        assert_eq!(peer_id(), peer_id());
+    }
+
+    #[test]
+    fn peer_id_encodes_version() {
+        let id = peer_id();
+        let version: Vec<u8> = env!("CARGO_PKG_VERSION")
+            .split(['.', '-', '+'])
+            .take(3)
+            .map(|part| version_char(part.parse().unwrap()))
+            .collect();
+        assert_eq!(&id[..3], b"-RS");
+        assert_eq!(&id[3..6], &version[..]);
+        assert_eq!(&id[6..8], b"0-");
+        assert!(id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_eq!(
+            client_name(&id),
+            format!("async_torrent {}", env!("CARGO_PKG_VERSION"))
+        );
+    }
+}